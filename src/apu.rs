@@ -0,0 +1,1098 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::error;
+
+use super::wav::WavWriter;
+use super::savestate::{StateReader, StateWriter};
+
+pub const SAMPLE_RATE: u32 = 44100;
+
+const CPU_CLOCK: u32 = 4_194_304;
+const FRAME_SEQUENCER_PERIOD: u32 = 8192;
+
+// Keep at most a second of audio around if nobody is draining the buffer.
+const MAX_BUFFERED_SAMPLES: usize = SAMPLE_RATE as usize * 2;
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Bits that always read back as 1 for each register in 0xFF10-0xFF2F.
+const READ_MASKS: [u8; 32] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF,
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF,
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF,
+    0xFF, 0xFF, 0x00, 0x00, 0xBF,
+    0x00, 0x00, 0x70,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    period: u8,
+
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.initial_volume);
+        writer.bool(self.increase);
+        writer.u8(self.period);
+        writer.u8(self.volume);
+        writer.u8(self.timer);
+    }
+
+    // Masked like the register writes, so a broken state can't produce impossible values.
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.initial_volume = reader.u8()? & 0xF;
+        self.increase = reader.bool()?;
+        self.period = reader.u8()? & 7;
+        self.volume = reader.u8()? & 0xF;
+        self.timer = reader.u8()? & 7;
+        Ok(())
+    }
+
+    pub fn new() -> Envelope {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        (self.initial_volume << 4) | ((self.increase as u8) << 3) | self.period
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = ((value >> 3) & 1) == 1;
+        self.period = value & 7;
+    }
+
+    // The DAC is powered as long as any of the upper 5 bits of NRx2 are set.
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.period;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            }
+            else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+struct LengthCounter {
+    max: u16,
+    counter: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.counter);
+        writer.bool(self.enabled);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.counter = reader.u16()?.min(self.max);
+        self.enabled = reader.bool()?;
+        Ok(())
+    }
+
+    pub fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            max,
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    pub fn load(&mut self, value: u16) {
+        self.counter = self.max - value;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    // Returns true if the channel has to be disabled.
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+
+        false
+    }
+}
+
+struct SquareChannel {
+    enabled: bool,
+
+    duty: u8,
+    duty_position: usize,
+
+    frequency: u16,
+    timer: i32,
+
+    length: LengthCounter,
+    envelope: Envelope,
+
+    has_sweep: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    sweep_shadow: u16,
+}
+
+impl SquareChannel {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.u8(self.duty);
+        writer.u32(self.duty_position as u32);
+        writer.u16(self.frequency);
+        writer.i32(self.timer);
+
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+
+        writer.u8(self.sweep_period);
+        writer.bool(self.sweep_negate);
+        writer.u8(self.sweep_shift);
+        writer.u8(self.sweep_timer);
+        writer.bool(self.sweep_enabled);
+        writer.u16(self.sweep_shadow);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.enabled = reader.bool()?;
+        self.duty = reader.u8()? & 3;
+        self.duty_position = reader.u32()? as usize % 8;
+        // Frequencies above 0x7FF would keep the timer from ever running out.
+        self.frequency = reader.u16()? & 0x7FF;
+        self.timer = reader.i32()?.clamp(0, (2048 - self.frequency as i32) * 4);
+
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+
+        self.sweep_period = reader.u8()? & 7;
+        self.sweep_negate = reader.bool()?;
+        self.sweep_shift = reader.u8()? & 7;
+        self.sweep_timer = reader.u8()?.min(8);
+        self.sweep_enabled = reader.bool()?;
+        self.sweep_shadow = reader.u16()? & 0x7FF;
+        Ok(())
+    }
+
+    pub fn new(has_sweep: bool) -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+
+            duty: 0,
+            duty_position: 0,
+
+            frequency: 0,
+            timer: 0,
+
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+
+            has_sweep,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            sweep_shadow: 0,
+        }
+    }
+
+    // Register index goes from 0 (NRx0) to 4 (NRx4).
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => (self.sweep_period << 4) | ((self.sweep_negate as u8) << 3) | self.sweep_shift,
+            1 => self.duty << 6,
+            2 => self.envelope.read(),
+            3 => 0,
+            4 => (self.length.enabled as u8) << 6,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 if self.has_sweep => {
+                self.sweep_period = (value >> 4) & 7;
+                self.sweep_negate = ((value >> 3) & 1) == 1;
+                self.sweep_shift = value & 7;
+            },
+            1 => {
+                self.duty = value >> 6;
+                self.length.load((value & 0x3F) as u16);
+            },
+            2 => {
+                self.envelope.write(value);
+
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => {
+                self.frequency = (self.frequency & 0x700) | value as u16;
+            },
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (((value & 7) as u16) << 8);
+                self.length.enabled = ((value >> 6) & 1) == 1;
+
+                if (value >> 7) == 1 {
+                    self.trigger();
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.length.trigger();
+        self.envelope.trigger();
+        self.timer = (2048 - self.frequency as i32) * 4;
+
+        if self.has_sweep {
+            self.sweep_shadow = self.frequency;
+            self.sweep_timer = if self.sweep_period == 0 {8} else {self.sweep_period};
+            self.sweep_enabled = self.sweep_period != 0 || self.sweep_shift != 0;
+
+            if self.sweep_shift != 0 {
+                self.sweep_calculate();
+            }
+        }
+    }
+
+    fn sweep_calculate(&mut self) -> u16 {
+        let offset = self.sweep_shadow >> self.sweep_shift;
+        let result = if self.sweep_negate {self.sweep_shadow.wrapping_sub(offset)} else {self.sweep_shadow + offset};
+
+        if result > 2047 {
+            self.enabled = false;
+        }
+
+        result
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+
+        if self.sweep_timer == 0 {
+            self.sweep_timer = if self.sweep_period == 0 {8} else {self.sweep_period};
+
+            if self.sweep_enabled && self.sweep_period != 0 {
+                let result = self.sweep_calculate();
+
+                if result <= 2047 && self.sweep_shift != 0 {
+                    self.frequency = result;
+                    self.sweep_shadow = result;
+                    self.sweep_calculate();
+                }
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+
+        while self.timer <= 0 {
+            self.timer += (2048 - self.frequency as i32) * 4;
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.enabled {
+            DUTY_PATTERNS[self.duty as usize][self.duty_position] * self.envelope.volume
+        }
+        else {
+            0
+        }
+    }
+}
+
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+
+    volume_code: u8,
+    frequency: u16,
+    timer: i32,
+    position: usize,
+
+    length: LengthCounter,
+    wave_ram: [u8; 16],
+}
+
+impl WaveChannel {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.bool(self.dac_enabled);
+        writer.u8(self.volume_code);
+        writer.u16(self.frequency);
+        writer.i32(self.timer);
+        writer.u32(self.position as u32);
+
+        self.length.save_state(writer);
+        writer.bytes(&self.wave_ram);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.enabled = reader.bool()?;
+        self.dac_enabled = reader.bool()?;
+        self.volume_code = reader.u8()? & 3;
+        self.frequency = reader.u16()? & 0x7FF;
+        self.timer = reader.i32()?.clamp(0, (2048 - self.frequency as i32) * 2);
+        self.position = reader.u32()? as usize % 32;
+
+        self.length.load_state(reader)?;
+
+        let wave_ram = reader.bytes()?;
+        for (byte, value) in self.wave_ram.iter_mut().zip(wave_ram.iter()) {
+            *byte = *value;
+        }
+        Ok(())
+    }
+
+    pub fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+
+            length: LengthCounter::new(256),
+            wave_ram: [0; 16],
+        }
+    }
+
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => (self.dac_enabled as u8) << 7,
+            1 => 0,
+            2 => self.volume_code << 5,
+            3 => 0,
+            4 => (self.length.enabled as u8) << 6,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.dac_enabled = (value >> 7) == 1;
+
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            },
+            1 => self.length.load(value as u16),
+            2 => self.volume_code = (value >> 5) & 3,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | (((value & 7) as u16) << 8);
+                self.length.enabled = ((value >> 6) & 1) == 1;
+
+                if (value >> 7) == 1 {
+                    self.enabled = self.dac_enabled;
+                    self.length.trigger();
+                    self.timer = (2048 - self.frequency as i32) * 2;
+                    self.position = 0;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+
+        while self.timer <= 0 {
+            self.timer += (2048 - self.frequency as i32) * 2;
+            self.position = (self.position + 1) % 32;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let sample_byte = self.wave_ram[self.position / 2];
+        let sample = if self.position & 1 == 0 {sample_byte >> 4} else {sample_byte & 0x0F};
+
+        match self.volume_code {
+            0 => 0,
+            1 => sample,
+            2 => sample >> 1,
+            3 => sample >> 2,
+            _ => 0,
+        }
+    }
+}
+
+struct NoiseChannel {
+    enabled: bool,
+
+    clock_shift: u8,
+    width_mode: bool,
+    divisor_code: u8,
+
+    timer: i32,
+    lfsr: u16,
+
+    length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+        writer.u8(self.clock_shift);
+        writer.bool(self.width_mode);
+        writer.u8(self.divisor_code);
+        writer.i32(self.timer);
+        writer.u16(self.lfsr);
+
+        self.length.save_state(writer);
+        self.envelope.save_state(writer);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.enabled = reader.bool()?;
+        // Larger shifts would overflow the period.
+        self.clock_shift = reader.u8()? & 0xF;
+        self.width_mode = reader.bool()?;
+        self.divisor_code = reader.u8()? & 7;
+        self.timer = reader.i32()?.clamp(0, self.period());
+        self.lfsr = reader.u16()?;
+
+        self.length.load_state(reader)?;
+        self.envelope.load_state(reader)?;
+        Ok(())
+    }
+
+    pub fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+
+            clock_shift: 0,
+            width_mode: false,
+            divisor_code: 0,
+
+            timer: 0,
+            lfsr: 0x7FFF,
+
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn period(&self) -> i32 {
+        (NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift) as i32
+    }
+
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            1 => 0,
+            2 => self.envelope.read(),
+            3 => (self.clock_shift << 4) | ((self.width_mode as u8) << 3) | self.divisor_code,
+            4 => (self.length.enabled as u8) << 6,
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            1 => self.length.load((value & 0x3F) as u16),
+            2 => {
+                self.envelope.write(value);
+
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            },
+            3 => {
+                self.clock_shift = value >> 4;
+                self.width_mode = ((value >> 3) & 1) == 1;
+                self.divisor_code = value & 7;
+            },
+            4 => {
+                self.length.enabled = ((value >> 6) & 1) == 1;
+
+                if (value >> 7) == 1 {
+                    self.enabled = self.envelope.dac_enabled();
+                    self.length.trigger();
+                    self.envelope.trigger();
+                    self.timer = self.period();
+                    self.lfsr = 0x7FFF;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+
+        while self.timer <= 0 {
+            self.timer += self.period();
+
+            let xor = (self.lfsr & 1) ^ ((self.lfsr >> 1) & 1);
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+
+            if self.width_mode {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.enabled && (self.lfsr & 1) == 0 {
+            self.envelope.volume
+        }
+        else {
+            0
+        }
+    }
+}
+
+pub const CHANNEL_NAMES: [&str; 4] = ["Square 1", "Square 2", "Wave", "Noise"];
+
+// Snapshot of a channel, for debugging views.
+pub struct ChannelState {
+    pub enabled: bool,
+    pub muted: bool,
+    pub frequency: f32,
+    pub volume: u8,
+    pub duty: Option<u8>,
+}
+
+// "music.wav" becomes "music_ch1.wav", "music_ch2.wav" and so on.
+pub fn channel_dump_paths(base: &Path) -> Vec<PathBuf> {
+    let stem = base.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+
+    (1..=4).map(|channel| base.with_file_name(format!("{}_ch{}.wav", stem, channel))).collect()
+}
+
+// Writes samples to one or more WAV files, at its own sample rate.
+struct AudioRecorder {
+    writers: Vec<WavWriter>,
+    sample_rate: u32,
+    sample_counter: u32,
+}
+
+impl AudioRecorder {
+    pub fn new(paths: &[PathBuf], sample_rate: u32) -> io::Result<AudioRecorder> {
+        let mut writers = Vec::with_capacity(paths.len());
+
+        for path in paths.iter() {
+            writers.push(WavWriter::create(path, sample_rate, 2)?);
+        }
+
+        Ok(AudioRecorder {
+            writers,
+            sample_rate,
+            sample_counter: 0,
+        })
+    }
+
+    // Each writer gets the stereo sample with the same index.
+    pub fn record(&mut self, cycles: u32, samples: &[(f32, f32)]) -> io::Result<()> {
+        self.sample_counter += cycles * self.sample_rate;
+
+        while self.sample_counter >= CPU_CLOCK {
+            self.sample_counter -= CPU_CLOCK;
+
+            for (writer, sample) in self.writers.iter_mut().zip(samples.iter()) {
+                writer.write_sample(sample.0)?;
+                writer.write_sample(sample.1)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        for writer in self.writers.into_iter() {
+            writer.finish()?;
+        }
+
+        Ok(())
+    }
+}
+
+pub struct Apu {
+    enabled: bool,
+
+    square1: SquareChannel,
+    square2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+
+    // NR50 and NR51.
+    master_volume: u8,
+    panning: u8,
+
+    frame_sequencer_cycles: u32,
+    frame_sequencer_step: u8,
+
+    // Only affect what gets played and recorded, the channels keep running.
+    muted_channels: [bool; 4],
+    solo_channel: Option<usize>,
+
+    sample_counter: u32,
+    samples: Vec<f32>,
+
+    recorder: Option<AudioRecorder>,
+    channel_dumps: Option<AudioRecorder>,
+    // Samples played since the last video frame, while a video is being recorded.
+    video_samples: Option<Vec<f32>>,
+}
+
+impl Apu {
+    // Mute, solo and recording settings aren't part of the machine, so they're left alone.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.enabled);
+
+        self.square1.save_state(writer);
+        self.square2.save_state(writer);
+        self.wave.save_state(writer);
+        self.noise.save_state(writer);
+
+        writer.u8(self.master_volume);
+        writer.u8(self.panning);
+        writer.u32(self.frame_sequencer_cycles);
+        writer.u8(self.frame_sequencer_step);
+        writer.u32(self.sample_counter);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.enabled = reader.bool()?;
+
+        self.square1.load_state(reader)?;
+        self.square2.load_state(reader)?;
+        self.wave.load_state(reader)?;
+        self.noise.load_state(reader)?;
+
+        self.master_volume = reader.u8()?;
+        self.panning = reader.u8()?;
+        self.frame_sequencer_cycles = reader.u32()?;
+        self.frame_sequencer_step = reader.u8()? & 7;
+        self.sample_counter = reader.u32()?;
+        Ok(())
+    }
+
+    // Back to power on, with mute, solo and recordings carried over like in save states.
+    pub fn reset(&mut self) {
+        let mut apu = Apu::new();
+        apu.take_outputs(self);
+        *self = apu;
+    }
+
+    // Moves mute, solo and recordings over from another APU, used when the machine gets replaced.
+    pub fn take_outputs(&mut self, other: &mut Apu) {
+        self.muted_channels = other.muted_channels;
+        self.solo_channel = other.solo_channel;
+        self.recorder = other.recorder.take();
+        self.channel_dumps = other.channel_dumps.take();
+        self.video_samples = other.video_samples.take();
+    }
+
+    pub fn new() -> Apu {
+        Apu {
+            enabled: false,
+
+            square1: SquareChannel::new(true),
+            square2: SquareChannel::new(false),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+
+            master_volume: 0,
+            panning: 0,
+
+            frame_sequencer_cycles: 0,
+            frame_sequencer_step: 0,
+
+            muted_channels: [false; 4],
+            solo_channel: None,
+
+            sample_counter: 0,
+            samples: Vec::with_capacity(MAX_BUFFERED_SAMPLES),
+
+            recorder: None,
+            channel_dumps: None,
+            video_samples: None,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        let value = match address {
+            0xFF10 ..= 0xFF14 => self.square1.read(address - 0xFF10),
+            0xFF15 ..= 0xFF19 => self.square2.read(address - 0xFF15),
+            0xFF1A ..= 0xFF1E => self.wave.read(address - 0xFF1A),
+            0xFF1F ..= 0xFF23 => self.noise.read(address - 0xFF1F),
+            0xFF24 => self.master_volume,
+            0xFF25 => self.panning,
+            0xFF26 => {
+                ((self.enabled as u8) << 7) | ((self.noise.enabled as u8) << 3) | ((self.wave.enabled as u8) << 2)
+                | ((self.square2.enabled as u8) << 1) | (self.square1.enabled as u8)
+            },
+            0xFF30 ..= 0xFF3F => return self.wave.wave_ram[address as usize - 0xFF30],
+            _ => 0,
+        };
+
+        value | READ_MASKS[address as usize - 0xFF10]
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+
+        // Wave RAM and NR52 are the only things that can be written while the APU is off.
+        if (0xFF30..=0xFF3F).contains(&address) {
+            self.wave.wave_ram[address as usize - 0xFF30] = value;
+            return;
+        }
+
+        if address == 0xFF26 {
+            let enabled = (value >> 7) == 1;
+
+            if self.enabled && !enabled {
+                self.power_off();
+            }
+            else if !self.enabled && enabled {
+                self.frame_sequencer_step = 0;
+            }
+
+            self.enabled = enabled;
+            return;
+        }
+
+        if !self.enabled {
+            return;
+        }
+
+        match address {
+            0xFF10 ..= 0xFF14 => self.square1.write(address - 0xFF10, value),
+            0xFF15 ..= 0xFF19 => self.square2.write(address - 0xFF15, value),
+            0xFF1A ..= 0xFF1E => self.wave.write(address - 0xFF1A, value),
+            0xFF1F ..= 0xFF23 => self.noise.write(address - 0xFF1F, value),
+            0xFF24 => self.master_volume = value,
+            0xFF25 => self.panning = value,
+            _ => {}
+        }
+    }
+
+    // Turning the APU off clears every register, except for Wave RAM.
+    fn power_off(&mut self) {
+        let wave_ram = self.wave.wave_ram;
+
+        self.square1 = SquareChannel::new(true);
+        self.square2 = SquareChannel::new(false);
+        self.wave = WaveChannel::new();
+        self.noise = NoiseChannel::new();
+        self.wave.wave_ram = wave_ram;
+
+        self.master_volume = 0;
+        self.panning = 0;
+    }
+
+    pub fn apu_cycle(&mut self, cycles: u16) {
+        let cycles = cycles as u32;
+
+        if self.enabled {
+            self.frame_sequencer_cycles += cycles;
+
+            while self.frame_sequencer_cycles >= FRAME_SEQUENCER_PERIOD {
+                self.frame_sequencer_cycles -= FRAME_SEQUENCER_PERIOD;
+                self.clock_frame_sequencer();
+            }
+
+            self.square1.step(cycles);
+            self.square2.step(cycles);
+            self.wave.step(cycles);
+            self.noise.step(cycles);
+        }
+
+        // Channel outputs only change once per call, so mixing once is enough for every consumer.
+        let channels = self.channel_outputs();
+        let (left, right) = self.mix(&channels);
+
+        self.sample_counter += cycles * SAMPLE_RATE;
+
+        while self.sample_counter >= CPU_CLOCK {
+            self.sample_counter -= CPU_CLOCK;
+
+            // If nobody drains the buffer, drop new samples instead of growing forever.
+            if self.samples.len() < MAX_BUFFERED_SAMPLES {
+                self.samples.push(left);
+                self.samples.push(right);
+            }
+
+            if let Some(samples) = &mut self.video_samples {
+                samples.push(left);
+                samples.push(right);
+            }
+        }
+
+        if let Some(recorder) = &mut self.recorder {
+            if let Err(error) = recorder.record(cycles, &[(left, right)]) {
+                error!("APU: Failed to write audio recording, error {}. Recording stopped", error);
+                self.recorder = None;
+            }
+        }
+
+        if let Some(dumps) = &mut self.channel_dumps {
+            if let Err(error) = dumps.record(cycles, &channels) {
+                error!("APU: Failed to write channel dumps, error {}. Dumps stopped", error);
+                self.channel_dumps = None;
+            }
+        }
+    }
+
+    pub fn start_recording(&mut self, path: &Path, sample_rate: u32) -> io::Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(AudioRecorder::new(&[path.to_path_buf()], sample_rate)?);
+
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // Dumps every channel to its own file, before muting and soloing are applied.
+    pub fn start_channel_dumps(&mut self, paths: &[PathBuf], sample_rate: u32) -> io::Result<()> {
+        self.stop_channel_dumps()?;
+        self.channel_dumps = Some(AudioRecorder::new(paths, sample_rate)?);
+
+        Ok(())
+    }
+
+    pub fn stop_channel_dumps(&mut self) -> io::Result<()> {
+        match self.channel_dumps.take() {
+            Some(dumps) => dumps.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn is_dumping_channels(&self) -> bool {
+        self.channel_dumps.is_some()
+    }
+
+    pub fn toggle_mute(&mut self, channel: usize) -> bool {
+        self.muted_channels[channel] = !self.muted_channels[channel];
+        self.muted_channels[channel]
+    }
+
+    // Soloing the channel that's already soloed goes back to the normal mix.
+    pub fn toggle_solo(&mut self, channel: usize) -> Option<usize> {
+        self.solo_channel = if self.solo_channel == Some(channel) {None} else {Some(channel)};
+        self.solo_channel
+    }
+
+    fn is_audible(&self, channel: usize) -> bool {
+        match self.solo_channel {
+            Some(solo) => solo == channel,
+            None => !self.muted_channels[channel],
+        }
+    }
+
+    pub fn channel_states(&self) -> Vec<ChannelState> {
+        let noise_divisor = if self.noise.divisor_code == 0 {0.5} else {self.noise.divisor_code as f32};
+
+        vec![
+            ChannelState {
+                enabled: self.square1.enabled,
+                muted: !self.is_audible(0),
+                frequency: 131072.0 / (2048 - self.square1.frequency) as f32,
+                volume: self.square1.envelope.volume,
+                duty: Some(self.square1.duty),
+            },
+            ChannelState {
+                enabled: self.square2.enabled,
+                muted: !self.is_audible(1),
+                frequency: 131072.0 / (2048 - self.square2.frequency) as f32,
+                volume: self.square2.envelope.volume,
+                duty: Some(self.square2.duty),
+            },
+            ChannelState {
+                enabled: self.wave.enabled,
+                muted: !self.is_audible(2),
+                frequency: 65536.0 / (2048 - self.wave.frequency) as f32,
+                volume: self.wave.volume_code,
+                duty: None,
+            },
+            ChannelState {
+                enabled: self.noise.enabled,
+                muted: !self.is_audible(3),
+                frequency: 524288.0 / noise_divisor / (1 << (self.noise.clock_shift + 1)) as f32,
+                volume: self.noise.envelope.volume,
+                duty: None,
+            },
+        ]
+    }
+
+    // Steps 0, 2, 4 and 6 clock length counters, 2 and 6 also clock the sweep unit, 7 clocks envelopes.
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+
+        if step & 1 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+
+        if step == 2 || step == 6 {
+            self.square1.clock_sweep();
+        }
+
+        if step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    fn dac_output(value: u8, dac_enabled: bool) -> f32 {
+        if dac_enabled {
+            (value as f32 / 7.5) - 1.0
+        }
+        else {
+            0.0
+        }
+    }
+
+    // Every channel on its own, with panning and master volume applied.
+    fn channel_outputs(&self) -> [(f32, f32); 4] {
+        if !self.enabled {
+            return [(0.0, 0.0); 4];
+        }
+
+        let outputs = [
+            Apu::dac_output(self.square1.output(), self.square1.envelope.dac_enabled()),
+            Apu::dac_output(self.square2.output(), self.square2.envelope.dac_enabled()),
+            Apu::dac_output(self.wave.output(), self.wave.dac_enabled),
+            Apu::dac_output(self.noise.output(), self.noise.envelope.dac_enabled()),
+        ];
+
+        let left_volume = (((self.master_volume >> 4) & 7) + 1) as f32 / 8.0;
+        let right_volume = ((self.master_volume & 7) + 1) as f32 / 8.0;
+        let mut result = [(0.0, 0.0); 4];
+
+        for (channel, output) in outputs.iter().enumerate() {
+            if (self.panning >> (channel + 4)) & 1 == 1 {
+                result[channel].0 = output * left_volume;
+            }
+            if (self.panning >> channel) & 1 == 1 {
+                result[channel].1 = output * right_volume;
+            }
+        }
+
+        result
+    }
+
+    fn mix(&self, channels: &[(f32, f32); 4]) -> (f32, f32) {
+        let mut left = 0.0;
+        let mut right = 0.0;
+
+        for (channel, output) in channels.iter().enumerate() {
+            if self.is_audible(channel) {
+                left += output.0;
+                right += output.1;
+            }
+        }
+
+        (left / 4.0, right / 4.0)
+    }
+
+    // Interleaved stereo samples generated since the last call.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::replace(&mut self.samples, Vec::with_capacity(MAX_BUFFERED_SAMPLES))
+    }
+
+    // Starts or stops keeping samples for a video, at SAMPLE_RATE.
+    pub fn set_video_recording(&mut self, recording: bool) {
+        self.video_samples = if recording {Some(Vec::new())} else {None};
+    }
+
+    pub fn take_video_samples(&mut self) -> Vec<f32> {
+        self.video_samples.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+}
@@ -0,0 +1,223 @@
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::fs::File;
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use super::gpu::Frame;
+
+
+// The Game Boy's frame rate, 4194304 cycles per second over 70224 cycles per frame.
+const FRAME_RATE: u32 = 4_194_304;
+const FRAME_SCALE: u32 = 70224;
+
+// The headers are always the same size, so they can be written again once the counts are known.
+// RIFF header, hdrl list with the main header and 2 stream lists, then the movi list header.
+const HEADERS_SIZE: u32 = 12 + 300 + 12;
+
+// Plain AVI files use 32-bit sizes, and a lot of players give up past 2 GiB.
+const MAX_DATA_SIZE: u32 = 0x7F00_0000;
+
+// Index flag for chunks that can be decoded on their own, which is all of them.
+const KEYFRAME: u32 = 0x10;
+
+// Uncompressed AVI writer: 24-bit RGB frames and 16-bit stereo PCM audio, interleaved one frame
+// at a time. Sizes, counts and the index are written when the file is finished.
+pub struct AviWriter {
+    file: BufWriter<File>,
+    width: u32,
+    height: u32,
+    sample_rate: u32,
+
+    frames: u32,
+    audio_bytes: u32,
+    // Bytes in the movi list after its type, and the index entries (chunk id, offset, size).
+    data_size: u32,
+    index: Vec<([u8; 4], u32, u32)>,
+}
+
+impl AviWriter {
+    pub fn create(path: &Path, width: u32, height: u32, sample_rate: u32) -> io::Result<AviWriter> {
+        let mut writer = AviWriter {
+            file: BufWriter::new(File::create(path)?),
+            width,
+            height,
+            sample_rate,
+
+            frames: 0,
+            audio_bytes: 0,
+            data_size: 0,
+            index: Vec::new(),
+        };
+
+        writer.write_headers()?;
+        Ok(writer)
+    }
+
+    fn frame_size(&self) -> u32 {
+        self.width * self.height * 3
+    }
+
+    // Every frame is followed by the audio played during it.
+    pub fn write_frame(&mut self, frame: &Frame, samples: &[f32]) -> io::Result<()> {
+        if frame.width != self.width || frame.height != self.height {
+            return Err(io::Error::other(format!("the frame size changed from {}x{} to {}x{}", self.width, self.height, frame.width, frame.height)));
+        }
+
+        let audio_size = samples.len() as u32 * 2;
+
+        if self.data_size + self.frame_size() + audio_size + 16 > MAX_DATA_SIZE {
+            return Err(io::Error::other("the AVI size limit has been reached"));
+        }
+
+        // Bitmaps are stored bottom-up, in BGR order.
+        let mut pixels = Vec::with_capacity(self.frame_size() as usize);
+
+        for row in frame.pixels.chunks(self.width as usize * 4).rev() {
+            for pixel in row.chunks(4) {
+                pixels.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+            }
+        }
+
+        self.write_chunk(*b"00db", &pixels)?;
+        self.frames += 1;
+
+        let mut audio = Vec::with_capacity(audio_size as usize);
+
+        for sample in samples.iter() {
+            audio.write_i16::<LittleEndian>((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)?;
+        }
+
+        self.write_chunk(*b"01wb", &audio)?;
+        self.audio_bytes += audio_size;
+
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, id: [u8; 4], data: &[u8]) -> io::Result<()> {
+        self.file.write_all(&id)?;
+        self.file.write_u32::<LittleEndian>(data.len() as u32)?;
+        self.file.write_all(data)?;
+
+        // Offsets count from the movi list's type.
+        self.index.push((id, 4 + self.data_size, data.len() as u32));
+        self.data_size += 8 + data.len() as u32;
+        Ok(())
+    }
+
+    fn write_headers(&mut self) -> io::Result<()> {
+        let index_size = self.index.len() as u32 * 16;
+        let microseconds_per_frame = (1_000_000u64 * FRAME_SCALE as u64 / FRAME_RATE as u64) as u32;
+        let bytes_per_second = self.frame_size() * 60 + self.sample_rate * 4;
+
+        let file = &mut self.file;
+
+        file.write_all(b"RIFF")?;
+        file.write_u32::<LittleEndian>(HEADERS_SIZE - 8 + self.data_size + 8 + index_size)?;
+        file.write_all(b"AVI ")?;
+
+        file.write_all(b"LIST")?;
+        file.write_u32::<LittleEndian>(292)?;
+        file.write_all(b"hdrl")?;
+
+        file.write_all(b"avih")?;
+        file.write_u32::<LittleEndian>(56)?;
+        file.write_u32::<LittleEndian>(microseconds_per_frame)?;
+        file.write_u32::<LittleEndian>(bytes_per_second)?;
+        file.write_u32::<LittleEndian>(0)?;
+        // Has an index, and the streams are interleaved.
+        file.write_u32::<LittleEndian>(0x110)?;
+        file.write_u32::<LittleEndian>(self.frames)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(2)?;
+        file.write_u32::<LittleEndian>(self.width * self.height * 3)?;
+        file.write_u32::<LittleEndian>(self.width)?;
+        file.write_u32::<LittleEndian>(self.height)?;
+        file.write_all(&[0; 16])?;
+
+        file.write_all(b"LIST")?;
+        file.write_u32::<LittleEndian>(116)?;
+        file.write_all(b"strl")?;
+
+        file.write_all(b"strh")?;
+        file.write_u32::<LittleEndian>(56)?;
+        file.write_all(b"vids")?;
+        file.write_all(b"DIB ")?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(FRAME_SCALE)?;
+        file.write_u32::<LittleEndian>(FRAME_RATE)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(self.frames)?;
+        file.write_u32::<LittleEndian>(self.width * self.height * 3)?;
+        file.write_u32::<LittleEndian>(u32::MAX)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u16::<LittleEndian>(0)?;
+        file.write_u16::<LittleEndian>(0)?;
+        file.write_u16::<LittleEndian>(self.width as u16)?;
+        file.write_u16::<LittleEndian>(self.height as u16)?;
+
+        file.write_all(b"strf")?;
+        file.write_u32::<LittleEndian>(40)?;
+        file.write_u32::<LittleEndian>(40)?;
+        file.write_i32::<LittleEndian>(self.width as i32)?;
+        file.write_i32::<LittleEndian>(self.height as i32)?;
+        file.write_u16::<LittleEndian>(1)?;
+        file.write_u16::<LittleEndian>(24)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(self.width * self.height * 3)?;
+        file.write_all(&[0; 16])?;
+
+        file.write_all(b"LIST")?;
+        file.write_u32::<LittleEndian>(92)?;
+        file.write_all(b"strl")?;
+
+        // Audio is counted in stereo samples, 4 bytes each.
+        file.write_all(b"strh")?;
+        file.write_u32::<LittleEndian>(56)?;
+        file.write_all(b"auds")?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(4)?;
+        file.write_u32::<LittleEndian>(self.sample_rate * 4)?;
+        file.write_u32::<LittleEndian>(0)?;
+        file.write_u32::<LittleEndian>(self.audio_bytes / 4)?;
+        file.write_u32::<LittleEndian>(self.sample_rate * 4 / 30)?;
+        file.write_u32::<LittleEndian>(u32::MAX)?;
+        file.write_u32::<LittleEndian>(4)?;
+        file.write_all(&[0; 8])?;
+
+        file.write_all(b"strf")?;
+        file.write_u32::<LittleEndian>(16)?;
+        file.write_u16::<LittleEndian>(1)?;
+        file.write_u16::<LittleEndian>(2)?;
+        file.write_u32::<LittleEndian>(self.sample_rate)?;
+        file.write_u32::<LittleEndian>(self.sample_rate * 4)?;
+        file.write_u16::<LittleEndian>(4)?;
+        file.write_u16::<LittleEndian>(16)?;
+
+        file.write_all(b"LIST")?;
+        file.write_u32::<LittleEndian>(4 + self.data_size)?;
+        file.write_all(b"movi")
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.write_all(b"idx1")?;
+        self.file.write_u32::<LittleEndian>(self.index.len() as u32 * 16)?;
+
+        for (id, offset, size) in self.index.iter() {
+            self.file.write_all(id)?;
+            self.file.write_u32::<LittleEndian>(KEYFRAME)?;
+            self.file.write_u32::<LittleEndian>(*offset)?;
+            self.file.write_u32::<LittleEndian>(*size)?;
+        }
+
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_headers()?;
+        self.file.flush()
+    }
+}
@@ -8,6 +8,8 @@ use std::io::Read;
 use std::io::Write;
 use std::sync::atomic::{AtomicU8, AtomicBool, Ordering};
 
+use super::savestate::{StateReader, StateWriter};
+
 #[derive(Debug)]
 pub enum CartType {
 
@@ -29,6 +31,13 @@ pub struct CartData {
     ram_data: Vec<AtomicU8>,
 
     rom_title: String,
+    rom_hash: u64,
+    // 0x80 for games that also run on a DMG, 0xC0 for CGB only ones.
+    cgb_flag: u8,
+    // Set for games that use SGB features, only honored with the new licensee code.
+    sgb_flag: bool,
+    // Sum of the title bytes and the fourth letter, for games by Nintendo.
+    title_checksum: Option<(u8, u8)>,
     
     has_ram: bool,
     has_battery: bool,
@@ -123,6 +132,10 @@ impl CartData {
             rom_data: rom_banks,
             ram_data: ram_banks,
             rom_title: title.to_lowercase(),
+            rom_hash: rom_hash(&data),
+            cgb_flag: data[0x0143],
+            sgb_flag: data[0x0146] == 0x03 && data[0x014B] == 0x33,
+            title_checksum: title_checksum(&data),
             has_ram: ram_size > 0,
             has_battery: battery,
             ram_enabled: AtomicBool::from(false),
@@ -133,6 +146,68 @@ impl CartData {
         }
     }
 
+    pub fn rom_hash(&self) -> u64 {
+        self.rom_hash
+    }
+
+    pub fn rom_title(&self) -> &str {
+        &self.rom_title
+    }
+
+    pub fn supports_cgb(&self) -> bool {
+        self.cgb_flag & 0x80 != 0
+    }
+
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag
+    }
+
+    pub fn title_checksum(&self) -> Option<(u8, u8)> {
+        self.title_checksum
+    }
+
+    // The ROM itself isn't stored, save states are tied to it through the ROM hash instead.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.atomics(&self.ram_data);
+        writer.bool(self.ram_enabled.load(Ordering::Relaxed));
+        writer.u8(self.selected_rom_bank.load(Ordering::Relaxed));
+        writer.u8(self.selected_ram_bank.load(Ordering::Relaxed));
+        writer.bool(self.rom_banking_mode.load(Ordering::Relaxed));
+    }
+
+    pub fn load_state(&self, reader: &mut StateReader) -> io::Result<()> {
+        reader.atomics(&self.ram_data)?;
+        self.ram_enabled.store(reader.bool()?, Ordering::Relaxed);
+        self.selected_rom_bank.store(reader.u8()?, Ordering::Relaxed);
+        self.selected_ram_bank.store(reader.u8()?, Ordering::Relaxed);
+        self.rom_banking_mode.store(reader.bool()?, Ordering::Relaxed);
+        Ok(())
+    }
+
+    // Only the MBC registers go back to their power on values, RAM is kept like on real carts.
+    pub fn reset(&self) {
+        self.ram_enabled.store(false, Ordering::Relaxed);
+        self.selected_rom_bank.store(1, Ordering::Relaxed);
+        self.selected_ram_bank.store(0, Ordering::Relaxed);
+        self.rom_banking_mode.store(true, Ordering::Relaxed);
+    }
+
+    pub fn ram_snapshot(&self) -> Vec<u8> {
+        self.ram_data.iter().map(|byte| byte.load(Ordering::Relaxed)).collect()
+    }
+
+    // Replaces the cart RAM contents, ignoring whatever doesn't fit.
+    pub fn load_ram_snapshot(&mut self, data: &[u8]) {
+        for (byte, value) in self.ram_data.iter().zip(data.iter()) {
+            byte.store(*value, Ordering::Relaxed);
+        }
+    }
+
+    // Keeps cart RAM changes from being written to the save file, for movie playback.
+    pub fn disable_ram_saving(&mut self) {
+        self.has_battery = false;
+    }
+
     pub fn read(&self, address: u16) -> u8 {
 
         if address <= 0x3FFF {
@@ -281,4 +356,29 @@ impl CartData {
         let mut file = File::create(path).unwrap();
         file.write_all(&ram).unwrap();
     }
-}
\ No newline at end of file
+}
+
+// FNV-1a, only used to tell ROMs apart.
+fn rom_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF29CE484222325;
+
+    for byte in data.iter() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001B3);
+    }
+
+    hash
+}
+
+// The CGB bootrom only colorizes games it knows by the sum of their title bytes. It trusts the
+// title of games with Nintendo as the licensee, 0x01 in the old licensee code or "01" in the new one.
+fn title_checksum(data: &[u8]) -> Option<(u8, u8)> {
+    let nintendo = data[0x014B] == 0x01 || (data[0x014B] == 0x33 && &data[0x0144..0x0146] == b"01");
+
+    if !nintendo {
+        return None;
+    }
+
+    let checksum = data[0x0134..0x0144].iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    Some((checksum, data[0x0137]))
+}
@@ -0,0 +1,576 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+
+use log::{info, warn, error};
+use serde::{Deserialize, Serialize};
+
+use sdl2::keyboard::Keycode;
+use sdl2::controller;
+
+use super::joypad;
+use super::savestate;
+use super::rewind::RewindSettings;
+use super::pacing::SpeedSettings;
+use super::joypad::Button;
+use super::palettes::CompatibilitySetting;
+use super::sgb::SgbSettings;
+use super::display;
+use super::display::DisplaySettings;
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Hotkey {
+    ReloadConfig,
+    // Held down, unlike the others.
+    Rewind,
+    FastForward,
+    ToggleSlowMotion,
+    TogglePause,
+    FrameAdvance,
+    ToggleInputDisplay,
+    ToggleMovieReadOnly,
+    ToggleAudioRecording,
+    ToggleVideoRecording,
+    PrintChannelStates,
+    Screenshot,
+    Reset,
+    PowerCycle,
+    // Holding shift solos the channel instead.
+    MuteChannel(usize),
+    // Loads the slot, holding shift saves to it instead.
+    StateSlot(usize),
+}
+
+// Keys are stored by their SDL names ("A", "Return", "Right Shift", "F9"...).
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct KeyboardConfig {
+    pub a: String,
+    pub b: String,
+    pub select: String,
+    pub start: String,
+    pub up: String,
+    pub down: String,
+    pub left: String,
+    pub right: String,
+    pub turbo_a: String,
+    pub turbo_b: String,
+}
+
+impl Default for KeyboardConfig {
+    fn default() -> KeyboardConfig {
+        KeyboardConfig {
+            a: String::from("A"),
+            b: String::from("S"),
+            select: String::from("Right Shift"),
+            start: String::from("Return"),
+            up: String::from("Up"),
+            down: String::from("Down"),
+            left: String::from("Left"),
+            right: String::from("Right"),
+            turbo_a: String::from("Q"),
+            turbo_b: String::from("W"),
+        }
+    }
+}
+
+impl KeyboardConfig {
+    // Second Game Boy with --link-local, on the keys the first one leaves free.
+    fn player_2() -> KeyboardConfig {
+        KeyboardConfig {
+            a: String::from("G"),
+            b: String::from("F"),
+            select: String::from("V"),
+            start: String::from("B"),
+            up: String::from("I"),
+            down: String::from("K"),
+            left: String::from("J"),
+            right: String::from("L"),
+            turbo_a: String::from("T"),
+            turbo_b: String::from("R"),
+        }
+    }
+}
+
+// Buttons use SDL's controller mapping names ("a", "b", "back", "start", "dpup"...).
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct ControllerConfig {
+    pub a: String,
+    pub b: String,
+    pub select: String,
+    pub start: String,
+    pub up: String,
+    pub down: String,
+    pub left: String,
+    pub right: String,
+    pub turbo_a: String,
+    pub turbo_b: String,
+
+    // Use the left stick as a D-pad.
+    pub analog_stick: bool,
+    pub deadzone: i16,
+    // Extra SDL mappings (gamecontrollerdb.txt format), on top of the ones SDL ships with.
+    pub mappings: String,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> ControllerConfig {
+        ControllerConfig {
+            a: String::from("a"),
+            b: String::from("b"),
+            select: String::from("back"),
+            start: String::from("start"),
+            up: String::from("dpup"),
+            down: String::from("dpdown"),
+            left: String::from("dpleft"),
+            right: String::from("dpright"),
+            turbo_a: String::from("x"),
+            turbo_b: String::from("y"),
+
+            analog_stick: true,
+            deadzone: 8000,
+            mappings: String::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct SpeedConfig {
+    // Speed multiplier while fast forwarding, 0 removes the limit.
+    pub fast_forward: f32,
+    pub slow_motion: f32,
+}
+
+impl Default for SpeedConfig {
+    fn default() -> SpeedConfig {
+        SpeedConfig {
+            fast_forward: 4.0,
+            slow_motion: 0.5,
+        }
+    }
+}
+
+impl SpeedConfig {
+    pub fn settings(&self) -> SpeedSettings {
+        SpeedSettings {
+            fast_forward: if self.fast_forward > 0.0 { Some(self.fast_forward) } else { None },
+            slow_motion: self.slow_motion.clamp(0.05, 1.0),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct RewindConfig {
+    pub enabled: bool,
+    // Frames between snapshots. Only 1 rewinds smoothly, higher values use less memory and CPU time but rewind in steps.
+    pub interval: u32,
+    // Snapshots kept, 3600 snapshots one frame apart are about a minute.
+    pub length: usize,
+    pub memory_mb: usize,
+}
+
+impl Default for RewindConfig {
+    fn default() -> RewindConfig {
+        RewindConfig {
+            enabled: true,
+            interval: 1,
+            length: 3600,
+            memory_mb: 64,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct CgbConfig {
+    // DMG games without a bootrom: "off" runs them on a DMG, "auto" on a CGB with the palette its
+    // bootrom would pick, and a palette name on a CGB with that palette.
+    pub dmg_palette: String,
+}
+
+impl Default for CgbConfig {
+    fn default() -> CgbConfig {
+        CgbConfig {
+            dmg_palette: String::from("off"),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct DisplayConfig {
+    // DMG palettes, for the background and window, and the two sprite palettes. A preset ("gray",
+    // "green" or "pocket") or 4 hex colors from the lightest to the darkest.
+    pub background: String,
+    pub sprites_0: String,
+    pub sprites_1: String,
+    pub color_correction: bool,
+    pub screenshot_scale: u32,
+}
+
+impl Default for DisplayConfig {
+    fn default() -> DisplayConfig {
+        DisplayConfig {
+            background: String::from("gray"),
+            sprites_0: String::from("gray"),
+            sprites_1: String::from("gray"),
+            color_correction: false,
+            screenshot_scale: 1,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct SgbConfig {
+    // Runs SGB games on a Super Game Boy, with its colors.
+    pub enabled: bool,
+    pub border: bool,
+}
+
+impl Default for SgbConfig {
+    fn default() -> SgbConfig {
+        SgbConfig {
+            enabled: false,
+            border: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct HotkeysConfig {
+    pub reload_config: String,
+    pub rewind: String,
+    pub fast_forward: String,
+    pub toggle_slow_motion: String,
+    pub toggle_pause: String,
+    pub frame_advance: String,
+    pub toggle_input_display: String,
+    pub toggle_movie_read_only: String,
+    pub toggle_audio_recording: String,
+    pub toggle_video_recording: String,
+    pub print_channel_states: String,
+    pub screenshot: String,
+    pub reset: String,
+    pub power_cycle: String,
+    pub mute_channel_1: String,
+    pub mute_channel_2: String,
+    pub mute_channel_3: String,
+    pub mute_channel_4: String,
+    // One key per save state slot, from slot 1 to 10.
+    pub state_slots: Vec<String>,
+}
+
+impl Default for HotkeysConfig {
+    fn default() -> HotkeysConfig {
+        HotkeysConfig {
+            reload_config: String::from("F5"),
+            rewind: String::from("Backspace"),
+            fast_forward: String::from("Tab"),
+            toggle_slow_motion: String::from("M"),
+            toggle_pause: String::from("P"),
+            frame_advance: String::from("N"),
+            toggle_input_display: String::from("F6"),
+            toggle_movie_read_only: String::from("F7"),
+            toggle_audio_recording: String::from("F9"),
+            toggle_video_recording: String::from("Insert"),
+            print_channel_states: String::from("F10"),
+            screenshot: String::from("F11"),
+            reset: String::from("F8"),
+            power_cycle: String::from("F12"),
+            mute_channel_1: String::from("F1"),
+            mute_channel_2: String::from("F2"),
+            mute_channel_3: String::from("F3"),
+            mute_channel_4: String::from("F4"),
+            state_slots: ["1", "2", "3", "4", "5", "6", "7", "8", "9", "0"].iter().map(|key| String::from(*key)).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(default)]
+pub struct Config {
+    // Frames turbo buttons stay pressed, and then released, for.
+    pub turbo_rate: u32,
+    pub keyboard: KeyboardConfig,
+    // Only used for the second Game Boy with --link-local.
+    pub keyboard_2: KeyboardConfig,
+    pub controller: ControllerConfig,
+    pub speed: SpeedConfig,
+    pub rewind: RewindConfig,
+    pub cgb: CgbConfig,
+    pub sgb: SgbConfig,
+    pub display: DisplayConfig,
+    pub hotkeys: HotkeysConfig,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            turbo_rate: joypad::DEFAULT_TURBO_RATE,
+            keyboard: KeyboardConfig::default(),
+            keyboard_2: KeyboardConfig::player_2(),
+            controller: ControllerConfig::default(),
+            speed: SpeedConfig::default(),
+            rewind: RewindConfig::default(),
+            cgb: CgbConfig::default(),
+            sgb: SgbConfig::default(),
+            display: DisplayConfig::default(),
+            hotkeys: HotkeysConfig::default(),
+        }
+    }
+}
+
+// Config resolved into something the frontend can look events up in.
+pub struct InputBindings {
+    // Keys map to the player they belong to, 0 for the first Game Boy, and the button.
+    pub buttons: HashMap<Keycode, (usize, Button)>,
+    pub turbo_buttons: HashMap<Keycode, (usize, Button)>,
+    pub hotkeys: HashMap<Keycode, Hotkey>,
+
+    pub controller_buttons: HashMap<controller::Button, Button>,
+    pub controller_turbo_buttons: HashMap<controller::Button, Button>,
+    pub analog_stick: bool,
+    pub deadzone: i16,
+    pub controller_mappings: Option<PathBuf>,
+
+    pub turbo_rate: u32,
+    pub speed: SpeedSettings,
+    pub rewind: RewindSettings,
+}
+
+impl Config {
+    pub fn default_path() -> PathBuf {
+        match dirs::config_dir() {
+            Some(dir) => dir.join("rusty-boi").join("config.toml"),
+            None => PathBuf::from("config.toml"),
+        }
+    }
+
+    // Missing files get created with the default config, broken ones are reported and ignored.
+    pub fn load(path: &Path) -> Config {
+        if !path.exists() {
+            let config = Config::default();
+            config.save(path);
+            return config;
+        }
+
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) => {
+                error!("Config: Failed to read {:#?}, error {}. Using the default config", path, error);
+                return Config::default();
+            }
+        };
+
+        match toml::from_str(&contents) {
+            Ok(config) => {
+                info!("Config: Loaded config from {:#?}", path);
+                config
+            },
+            Err(error) => {
+                error!("Config: Failed to parse {:#?}, error {}. Using the default config", path, error);
+                Config::default()
+            }
+        }
+    }
+
+    fn save(&self, path: &Path) {
+        if let Some(dir) = path.parent() {
+            if let Err(error) = fs::create_dir_all(dir) {
+                error!("Config: Failed to create directory for the config, error {}", error);
+                return;
+            }
+        }
+
+        let contents = toml::to_string_pretty(self).unwrap();
+
+        match fs::write(path, contents) {
+            Ok(_) => info!("Config: Created default config at {:#?}", path),
+            Err(error) => error!("Config: Failed to write {:#?}, error {}", path, error),
+        }
+    }
+
+    pub fn compatibility(&self) -> CompatibilitySetting {
+        match CompatibilitySetting::parse(&self.cgb.dmg_palette) {
+            Some(setting) => setting,
+            None => {
+                warn!("Config: Unknown DMG palette {}, DMG games will run on a DMG", self.cgb.dmg_palette);
+                CompatibilitySetting::Off
+            }
+        }
+    }
+
+    pub fn sgb_settings(&self) -> SgbSettings {
+        SgbSettings { enabled: self.sgb.enabled, border: self.sgb.border }
+    }
+
+    pub fn display_settings(&self) -> DisplaySettings {
+        let mut settings = DisplaySettings::default();
+        let palettes = [&self.display.background, &self.display.sprites_0, &self.display.sprites_1];
+
+        for (palette, value) in settings.dmg_palettes.iter_mut().zip(palettes.iter()) {
+            match display::parse_dmg_palette(value) {
+                Some(parsed) => *palette = parsed,
+                None => warn!("Config: Invalid DMG palette {}, using the default one", value),
+            }
+        }
+
+        settings.color_correction = self.display.color_correction;
+        settings.screenshot_scale = self.display.screenshot_scale.clamp(1, 8);
+        settings
+    }
+
+    pub fn input_bindings(&self) -> InputBindings {
+        let mappings = &self.controller.mappings;
+
+        let mut bindings = InputBindings {
+            buttons: HashMap::new(),
+            turbo_buttons: HashMap::new(),
+            hotkeys: HashMap::new(),
+
+            controller_buttons: HashMap::new(),
+            controller_turbo_buttons: HashMap::new(),
+            analog_stick: self.controller.analog_stick,
+            deadzone: self.controller.deadzone.max(0),
+            controller_mappings: if mappings.is_empty() { None } else { Some(PathBuf::from(mappings)) },
+
+            turbo_rate: self.turbo_rate.max(1),
+            speed: self.speed.settings(),
+            rewind: RewindSettings {
+                enabled: self.rewind.enabled,
+                interval: self.rewind.interval.max(1),
+                length: self.rewind.length,
+                memory_budget: self.rewind.memory_mb * 1024 * 1024,
+            },
+        };
+
+        // Player 1 goes last, so its keys win if both layouts use the same one.
+        for (player, keyboard) in [(1, &self.keyboard_2), (0, &self.keyboard)].iter() {
+            let buttons = [
+                (&keyboard.a, Button::A),
+                (&keyboard.b, Button::B),
+                (&keyboard.select, Button::Select),
+                (&keyboard.start, Button::Start),
+                (&keyboard.up, Button::Up),
+                (&keyboard.down, Button::Down),
+                (&keyboard.left, Button::Left),
+                (&keyboard.right, Button::Right),
+            ];
+
+            for (name, button) in buttons.iter() {
+                if let Some(keycode) = Config::parse_key(name) {
+                    bindings.buttons.insert(keycode, (*player, *button));
+                }
+            }
+
+            let turbo_buttons = [
+                (&keyboard.turbo_a, Button::A),
+                (&keyboard.turbo_b, Button::B),
+            ];
+
+            for (name, button) in turbo_buttons.iter() {
+                if let Some(keycode) = Config::parse_key(name) {
+                    bindings.turbo_buttons.insert(keycode, (*player, *button));
+                }
+            }
+        }
+
+        let controller_buttons = [
+            (&self.controller.a, Button::A),
+            (&self.controller.b, Button::B),
+            (&self.controller.select, Button::Select),
+            (&self.controller.start, Button::Start),
+            (&self.controller.up, Button::Up),
+            (&self.controller.down, Button::Down),
+            (&self.controller.left, Button::Left),
+            (&self.controller.right, Button::Right),
+        ];
+
+        for (name, button) in controller_buttons.iter() {
+            if let Some(controller_button) = Config::parse_controller_button(name) {
+                bindings.controller_buttons.insert(controller_button, *button);
+            }
+        }
+
+        let controller_turbo_buttons = [
+            (&self.controller.turbo_a, Button::A),
+            (&self.controller.turbo_b, Button::B),
+        ];
+
+        for (name, button) in controller_turbo_buttons.iter() {
+            if let Some(controller_button) = Config::parse_controller_button(name) {
+                bindings.controller_turbo_buttons.insert(controller_button, *button);
+            }
+        }
+
+        let hotkeys = [
+            (&self.hotkeys.reload_config, Hotkey::ReloadConfig),
+            (&self.hotkeys.rewind, Hotkey::Rewind),
+            (&self.hotkeys.fast_forward, Hotkey::FastForward),
+            (&self.hotkeys.toggle_slow_motion, Hotkey::ToggleSlowMotion),
+            (&self.hotkeys.toggle_pause, Hotkey::TogglePause),
+            (&self.hotkeys.frame_advance, Hotkey::FrameAdvance),
+            (&self.hotkeys.toggle_input_display, Hotkey::ToggleInputDisplay),
+            (&self.hotkeys.toggle_movie_read_only, Hotkey::ToggleMovieReadOnly),
+            (&self.hotkeys.toggle_audio_recording, Hotkey::ToggleAudioRecording),
+            (&self.hotkeys.toggle_video_recording, Hotkey::ToggleVideoRecording),
+            (&self.hotkeys.print_channel_states, Hotkey::PrintChannelStates),
+            (&self.hotkeys.screenshot, Hotkey::Screenshot),
+            (&self.hotkeys.reset, Hotkey::Reset),
+            (&self.hotkeys.power_cycle, Hotkey::PowerCycle),
+            (&self.hotkeys.mute_channel_1, Hotkey::MuteChannel(0)),
+            (&self.hotkeys.mute_channel_2, Hotkey::MuteChannel(1)),
+            (&self.hotkeys.mute_channel_3, Hotkey::MuteChannel(2)),
+            (&self.hotkeys.mute_channel_4, Hotkey::MuteChannel(3)),
+        ];
+
+        for (name, hotkey) in hotkeys.iter() {
+            if let Some(keycode) = Config::parse_key(name) {
+                bindings.hotkeys.insert(keycode, *hotkey);
+            }
+        }
+
+        for (slot, name) in self.hotkeys.state_slots.iter().take(savestate::STATE_SLOTS).enumerate() {
+            if let Some(keycode) = Config::parse_key(name) {
+                bindings.hotkeys.insert(keycode, Hotkey::StateSlot(slot));
+            }
+        }
+
+        bindings
+    }
+
+    // Empty names leave the action unbound.
+    fn parse_key(name: &str) -> Option<Keycode> {
+        if name.is_empty() {
+            return None;
+        }
+
+        let keycode = Keycode::from_name(name);
+
+        if keycode.is_none() {
+            warn!("Config: Unknown key name {}, ignoring it", name);
+        }
+
+        keycode
+    }
+
+    fn parse_controller_button(name: &str) -> Option<controller::Button> {
+        if name.is_empty() {
+            return None;
+        }
+
+        let button = controller::Button::from_string(name);
+
+        if button.is_none() {
+            warn!("Config: Unknown controller button {}, ignoring it", name);
+        }
+
+        button
+    }
+}
@@ -1,11 +1,21 @@
+use std::io;
+use std::thread;
 use std::sync::Arc;
+use std::time::Duration;
 use std::sync::mpsc::Receiver;
 use std::sync::atomic::{AtomicU16, Ordering};
 
-use log::error;
+use log::{error, info};
 use byteorder::{ByteOrder, LittleEndian};
 
+use super::gpu::{Gpu, FrameBuffer};
 use super::timer::TimerModule;
+use super::joypad::InputState;
+use super::movie::Movie;
+use super::rewind::{RewindBuffer, RewindSettings};
+use super::pacing::{Pacer, PacingStatus};
+use super::savestate;
+use super::savestate::{StateReader, StateWriter};
 use super::emulator::InputEvent;
 use super::memory::Memory;
 
@@ -124,6 +134,14 @@ impl Instruction {
     }
 }
 
+// Why the execution loop stopped.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Exit {
+    Quit,
+    // The ROM has to be loaded again, see emulator::start_emulation.
+    PowerCycle,
+}
+
 pub struct Cpu {
 
     // B, C, D, E, H, L, (HL), A.
@@ -140,22 +158,33 @@ pub struct Cpu {
     pub interrupts_enabled: bool,
 
     pub timer: TimerModule,
+    pub gpu: Gpu,
 
     pub memory: Arc<Memory>,
 
     pub input_receiver: Receiver<InputEvent>,
+    pub input_state: InputState,
+    pub frame_counter: u64,
+    pub movie: Option<Movie>,
+
+    pub pacer: Pacer,
+
+    pub rewind: RewindBuffer,
+    pub rewinding: bool,
+    rewind_state: Option<Vec<u8>>,
+    rewind_wait: u32,
 }
 
 impl Cpu {
-    pub fn new(memory: Arc<Memory>, cycles: Arc<AtomicU16>, input: Receiver<InputEvent>, run_bootrom: bool) -> Cpu {
+    pub fn new(memory: Arc<Memory>, cycles: Arc<AtomicU16>, input: Receiver<InputEvent>, run_bootrom: bool, frame: Arc<FrameBuffer>, pacing: Arc<PacingStatus>) -> Cpu {
         
         let timer_cycles = Arc::clone(&cycles);
         
-        Cpu {
+        let mut cpu = Cpu {
             registers: vec![Register::new(); 8],
             cpu_flags: FlagsRegister::new(),
             
-            pc: if run_bootrom {0x0} else {0x100},
+            pc: 0,
             sp: 0,
             cycles: cycles,
 
@@ -164,12 +193,106 @@ impl Cpu {
             interrupts_enabled: false,
 
             timer: TimerModule::new(timer_cycles, Arc::clone(&memory)),
+            gpu: Gpu::new(Arc::clone(&memory), frame),
 
             memory: memory,
+
             input_receiver: input,
+            input_state: InputState::new(),
+            frame_counter: 0,
+            movie: None,
+
+            pacer: Pacer::new(pacing),
+
+            // Stays off until the frontend sends the configured settings.
+            rewind: RewindBuffer::new(RewindSettings { enabled: false, interval: 1, length: 0, memory_budget: 0 }),
+            rewinding: false,
+            rewind_state: None,
+            rewind_wait: 0,
+        };
+
+        cpu.reset_registers(run_bootrom);
+        cpu
+    }
+
+    // Without the bootrom, registers start with the values it leaves behind. A is 0x11 on a CGB,
+    // which is how games tell they can use color.
+    fn reset_registers(&mut self, run_bootrom: bool) {
+        if run_bootrom {
+            for register in self.registers.iter_mut() {
+                register.set(0);
+            }
+
+            self.cpu_flags.set_value(0);
+            self.pc = 0x0;
+            self.sp = 0;
+        }
+        else if self.memory.is_cgb() {
+            self.set_rp2(0, 0x0000);
+            self.set_rp2(1, 0xFF56);
+            self.set_rp2(2, 0x000D);
+            self.set_rp2(3, 0x1180);
+            self.pc = 0x100;
+            self.sp = 0xFFFE;
+        }
+        else if self.memory.is_sgb() {
+            self.set_rp2(0, 0x0014);
+            self.set_rp2(1, 0x0000);
+            self.set_rp2(2, 0xC060);
+            self.set_rp2(3, 0x0100);
+            self.pc = 0x100;
+            self.sp = 0xFFFE;
+        }
+        // DMG games on a CGB see A = 0x11 too.
+        else if self.memory.is_dmg_compat() {
+            self.set_rp2(0, 0x0000);
+            self.set_rp2(1, 0x0008);
+            self.set_rp2(2, 0x007C);
+            self.set_rp2(3, 0x1180);
+            self.pc = 0x100;
+            self.sp = 0xFFFE;
+        }
+        else {
+            self.set_rp2(0, 0x0013);
+            self.set_rp2(1, 0x00D8);
+            self.set_rp2(2, 0x014D);
+            self.set_rp2(3, 0x01B0);
+            self.pc = 0x100;
+            self.sp = 0xFFFE;
+        }
+
+        self.halted = false;
+        self.stopped = false;
+        self.interrupts_enabled = false;
+    }
+
+    // Soft reset, like pressing the reset button: the cart and its RAM stay, everything else starts over.
+    pub fn reset(&mut self) {
+        self.memory.reset();
+        self.reset_registers(self.memory.is_bootrom_loaded());
+        self.cycles.store(0, Ordering::Relaxed);
+
+        self.timer = TimerModule::new(Arc::clone(&self.cycles), Arc::clone(&self.memory));
+        self.gpu = Gpu::new(Arc::clone(&self.memory), self.gpu.frame_buffer());
+        self.frame_counter = 0;
+
+        // Older snapshots would bring back the machine from before the reset.
+        self.rewind.clear();
+        self.set_rewinding(false);
+
+        // Movies only know about input, a reset in the middle can't be played back.
+        if let Some(mut movie) = self.movie.take() {
+            movie.finish();
+            info!("Movie: Stopped by the reset");
         }
     }
 
+    // Starts over with a freshly loaded cart. Input, speed and rewind settings are kept.
+    pub fn power_cycle(&mut self, memory: Arc<Memory>) {
+        self.memory = memory;
+        self.reset();
+    }
+
     pub fn get_rp(&mut self, index: u8) -> u16 {
         match index {
             0 => {
@@ -258,7 +381,7 @@ impl Cpu {
     pub fn get_register(&mut self, index: u8) -> u8 {
         if index == 6 {
             let address = self.get_rp(2);
-            return self.memory.read(address);
+            return self.memory.cpu_read(address);
         }
 
         self.registers[index as usize].get()
@@ -276,7 +399,7 @@ impl Cpu {
 
     fn stack_read(&mut self) -> u16 {
         let sp = self.get_rp(3);
-        let bytes = vec![self.memory.read(sp), self.memory.read(sp + 1)];
+        let bytes = vec![self.memory.cpu_read(sp), self.memory.cpu_read(sp + 1)];
 
         self.set_rp(3, sp + 2);
         LittleEndian::read_u16(&bytes)
@@ -292,83 +415,246 @@ impl Cpu {
         self.set_rp(3, sp - 2);
     }
 
-    pub fn execution_loop(&mut self) {
+    pub fn execution_loop(&mut self) -> Exit {
+
+        let exit = loop {
+            if let Some(exit) = self.update_input() {break exit}
+
+            if !self.pacer.is_running() {
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+
+            // A linked Game Boy has to catch up before this one can go on.
+            if !self.memory.serial_ready() {
+                continue;
+            }
+
+            let cycles_before = self.cycles.load(Ordering::Relaxed);
+
+            // The CPU sits out HDMA transfers, nothing else stops for them.
+            if self.memory.hdma_stall() {
+                self.instruction_finished(0, 4);
+            }
+            else {
+                self.check_interrupts();
+
+                // Time keeps going while halted, DMA, the APU, serial and the timer still need to be clocked.
+                if !self.halted {self.run_instruction()} else {self.instruction_finished(0, 4)}
+            }
+
+            let elapsed = self.cycles.load(Ordering::Relaxed).wrapping_sub(cycles_before);
+            // Double speed only speeds up the CPU, the timer, serial and OAM DMA. The LCD and the APU keep their pace.
+            let lcd_elapsed = if self.memory.is_double_speed() {elapsed / 2} else {elapsed};
 
-        loop {
-            if self.update_input() {break}
-            self.check_interrupts();
-            if !self.halted {self.run_instruction()}
+            self.memory.dma_cycle(elapsed);
+            self.memory.apu_cycle(lcd_elapsed);
+            self.memory.serial_cycle(elapsed);
             self.timer.timer_cycle();
+
+            if self.gpu.gpu_cycle(lcd_elapsed) {
+                self.frame_finished();
+            }
+        };
+
+        if let Some(movie) = &mut self.movie {
+            movie.finish();
         }
+
+        exit
     }
 
-    fn update_input(&mut self) -> bool {
-        let event_type: InputEvent;
-        let event_happened: bool;
-        let event_message = self.input_receiver.try_recv();
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new(self.memory.rom_hash());
 
-        match event_message {
-            Ok(message) => {
-                event_type = message;
-                event_happened = true;
-            },
-            Err(_error) => {
-                event_type = InputEvent::APressed;
-                event_happened = false;
+        for register in self.registers.iter() {
+            writer.u8(register.get());
+        }
+
+        writer.u8(self.cpu_flags.get_value());
+        writer.u16(self.pc);
+        writer.u16(self.sp);
+        writer.u16(self.cycles.load(Ordering::Relaxed));
+
+        writer.bool(self.halted);
+        writer.bool(self.stopped);
+        writer.bool(self.interrupts_enabled);
+        writer.u64(self.frame_counter);
+
+        // Memory goes before the GPU, which refreshes its cached registers from it when loading.
+        self.timer.save_state(&mut writer);
+        self.memory.save_state(&mut writer);
+        self.gpu.save_state(&mut writer);
+
+        writer.finish()
+    }
+
+    // A state that fails halfway through loading leaves the machine as it was before.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), String> {
+        let mut reader = StateReader::new(data, self.memory.rom_hash())?;
+        let backup = self.save_state();
+
+        if let Err(error) = self.read_state(&mut reader) {
+            let mut backup_reader = StateReader::new(&backup, self.memory.rom_hash())?;
+            self.read_state(&mut backup_reader).unwrap();
+
+            return Err(format!("Corrupted save state, error {}", error));
+        }
+
+        Ok(())
+    }
+
+    fn read_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        for register in self.registers.iter_mut() {
+            register.set(reader.u8()?);
+        }
+
+        self.cpu_flags.set_value(reader.u8()?);
+        self.pc = reader.u16()?;
+        self.sp = reader.u16()?;
+        self.cycles.store(reader.u16()?, Ordering::Relaxed);
+
+        self.halted = reader.bool()?;
+        self.stopped = reader.bool()?;
+        self.interrupts_enabled = reader.bool()?;
+        self.frame_counter = reader.u64()?;
+
+        self.timer.load_state(reader)?;
+        self.memory.load_state(reader)?;
+        self.gpu.load_state(reader)
+    }
+
+    fn save_state_slot(&self, slot: usize) {
+        match savestate::write_slot(self.memory.rom_title(), slot, &self.save_state()) {
+            Ok(path) => info!("State: Saved slot {} to {:#?}", slot + 1, path),
+            Err(error) => error!("State: Failed to save slot {}, error {}", slot + 1, error),
+        }
+    }
+
+    fn load_state_slot(&mut self, slot: usize) {
+        let data = match savestate::read_slot(self.memory.rom_title(), slot) {
+            Ok(data) => data,
+            Err(error) => {
+                error!("State: Failed to read slot {}, error {}", slot + 1, error);
+                return;
             }
         };
 
-        if event_type == InputEvent::Quit {
-            return true;
+        match self.load_state(&data) {
+            Ok(_) => {
+                info!("State: Loaded slot {}", slot + 1);
+
+                if let Some(movie) = &mut self.movie {
+                    movie.state_loaded(self.frame_counter);
+                    info!("Movie: Moved to frame {}", self.frame_counter);
+                }
+            },
+            Err(error) => error!("State: Failed to load slot {}, {}", slot + 1, error),
         }
+    }
 
-        let mut input_value = self.memory.read(0xFF00);
+    // Input reaches the joypad once per frame, so the same frames always see the same buttons.
+    fn frame_finished(&mut self) {
+        self.frame_counter += 1;
 
-        if event_happened && (input_value == 0x30 || input_value == 0x20 || input_value == 0x10) {
+        if self.rewinding {
+            self.rewind_frame();
+        }
+        else {
+            self.sample_input();
+        }
 
-            input_value |= 0xCF;
-            let if_value = self.memory.read(0xFF0F) | (1 << 4);
+        self.pacer.frame_finished();
+    }
 
-            if input_value == 0xFF {
-                match event_type {
-                    InputEvent::RightPressed => { input_value = 0xFE },
-                    InputEvent::LeftPressed => { input_value = 0xFD },
-                    InputEvent::UpPressed => { input_value = 0xFB },
-                    InputEvent::DownPressed => { input_value = 0xF7 },
-                    InputEvent::APressed => { input_value = 0xFE },
-                    InputEvent::BPressed => { input_value = 0xFD },
-                    InputEvent::SelectPressed => { input_value = 0xFB },
-                    InputEvent::StartPressed => { input_value = 0xF7 },
-                    _ => {}
-                }
+    fn sample_input(&mut self) {
+        let live = self.input_state.sample(self.frame_counter);
+        let buttons = match &mut self.movie {
+            Some(movie) => movie.next_frame(live),
+            None => live,
+        };
+
+        self.memory.set_joypad_state(buttons);
+
+        if self.rewind.frame_finished() {
+            let state = self.save_state();
+            self.rewind.push(state);
+        }
+    }
+
+    // Goes back one snapshot every interval frames and reloads the current one in between. Only an
+    // interval of 1 runs the game backwards smoothly, longer ones hold each snapshot that many frames.
+    fn rewind_frame(&mut self) {
+        if self.rewind_wait == 0 {
+            if let Some(state) = self.rewind.pop() {
+                self.rewind_state = Some(state);
             }
-            else if input_value == 0xEF {
-                match event_type {
-                    InputEvent::RightPressed => { input_value = 0xEE },
-                    InputEvent::LeftPressed => { input_value = 0xED },
-                    InputEvent::UpPressed => { input_value = 0xEB },
-                    InputEvent::DownPressed => { input_value = 0xE7 },
-                    _ => {}
-                }    
+
+            self.rewind_wait = self.rewind.interval();
+        }
+
+        self.rewind_wait -= 1;
+
+        if let Some(state) = self.rewind_state.take() {
+            if let Err(error) = self.load_state(&state) {
+                error!("Rewind: Failed to load snapshot, {}", error);
             }
-            else if input_value == 0xDF {
-                match event_type {
-                    InputEvent::APressed => { input_value = 0xDE },
-                    InputEvent::BPressed => { input_value = 0xDD },
-                    InputEvent::SelectPressed => { input_value = 0xDB },
-                    InputEvent::StartPressed => { input_value = 0xD7 },
-                    _ => {}
-                }
-    
+
+            self.rewind_state = Some(state);
+        }
+
+        if let Some(movie) = &mut self.movie {
+            movie.state_loaded(self.frame_counter);
+        }
+    }
+
+    fn set_rewinding(&mut self, rewinding: bool) {
+        self.rewinding = rewinding;
+        self.rewind_state = None;
+        self.rewind_wait = 0;
+    }
+
+    // Applies every pending input event, returns why emulation should stop, if it should.
+    fn update_input(&mut self) -> Option<Exit> {
+        while let Ok(event) = self.input_receiver.try_recv() {
+            match event {
+                InputEvent::Quit => return Some(Exit::Quit),
+                InputEvent::PowerCycle => return Some(Exit::PowerCycle),
+                InputEvent::Reset => {
+                    self.reset();
+                    info!("CPU: Reset");
+                },
+                InputEvent::ButtonPressed(button) => self.input_state.set_button(button, true),
+                InputEvent::ButtonReleased(button) => self.input_state.set_button(button, false),
+                InputEvent::TurboPressed(button) => self.input_state.set_turbo_button(button, true),
+                InputEvent::TurboReleased(button) => self.input_state.set_turbo_button(button, false),
+                InputEvent::SetTurboRate(rate) => self.input_state.set_turbo_rate(rate),
+                InputEvent::TogglePause => {
+                    let paused = !self.pacer.is_paused();
+                    self.pacer.set_paused(paused);
+                },
+                InputEvent::SetPaused(paused) => self.pacer.set_paused(paused),
+                InputEvent::FrameAdvance => self.pacer.advance_frame(),
+                InputEvent::SetFastForward(fast_forward) => self.pacer.set_fast_forward(fast_forward),
+                InputEvent::ToggleSlowMotion => self.pacer.toggle_slow_motion(),
+                InputEvent::ConfigureSpeed(settings) => self.pacer.configure(settings),
+                InputEvent::SetRewinding(rewinding) => self.set_rewinding(rewinding),
+                InputEvent::ConfigureRewind(settings) => self.rewind.configure(settings),
+                InputEvent::SaveState(slot) => self.save_state_slot(slot),
+                InputEvent::LoadState(slot) => self.load_state_slot(slot),
+                InputEvent::ToggleMovieReadOnly => {
+                    if let Some(movie) = &mut self.movie {
+                        movie.toggle_read_only();
+                    }
+                },
             }
-            self.memory.write(0xFF0F, if_value, true);
         }
 
-        self.memory.write(0xFF00, input_value, true);
-        false
+        None
     }
 
     fn check_interrupts(&mut self) {
+        // Interrupt lines aren't read or acknowledged through the bus, so OAM DMA doesn't get in the way.
         let if_value = self.memory.read(0xFF0F);
         let ie_value = self.memory.read(0xFFFF);
 
@@ -381,7 +667,7 @@ impl Cpu {
         // Vblank interrupt.
         if vblank_int {
             if self.interrupts_enabled && (ie_value & 1) == 1 {
-                self.memory.write(0xFF0F, if_value & !(1), true);
+                self.memory.write(0xFF0F, if_value & !(1), false);
                 self.stack_write(self.pc);
                 self.pc = 0x0040;
                 self.interrupts_enabled = false;
@@ -391,7 +677,7 @@ impl Cpu {
         // LCDC interrupt.
         else if lcdc_int {
             if self.interrupts_enabled && ((ie_value >> 1) & 1) == 1 {
-                self.memory.write(0xFF0F, if_value & !(1 << 1), true);
+                self.memory.write(0xFF0F, if_value & !(1 << 1), false);
                 self.stack_write(self.pc);
                 self.pc = 0x0048;
                 self.interrupts_enabled = false;
@@ -402,7 +688,7 @@ impl Cpu {
         // Timer interrupt.
         else if timer_int {
             if self.interrupts_enabled && ((ie_value >> 2) & 1) == 1 {
-                self.memory.write(0xFF0F, if_value & !(1 << 2), true);
+                self.memory.write(0xFF0F, if_value & !(1 << 2), false);
                 self.stack_write(self.pc);
                 self.pc = 0x0050;
                 self.interrupts_enabled = false;
@@ -412,7 +698,7 @@ impl Cpu {
         // Serial transfer interrupt.
         else if serial_int {
             if self.interrupts_enabled && ((ie_value >> 3) & 1) == 1 {
-                self.memory.write(0xFF0F, if_value & !(1 << 3), true);
+                self.memory.write(0xFF0F, if_value & !(1 << 3), false);
                 self.stack_write(self.pc);
                 self.pc = 0x0058;
                 self.interrupts_enabled = false;
@@ -422,7 +708,7 @@ impl Cpu {
         // Input interrupt.
         else if input_int {
             if self.interrupts_enabled && ((ie_value >> 4) & 1) == 1 {
-                self.memory.write(0xFF0F, if_value & !(1 << 4), true);
+                self.memory.write(0xFF0F, if_value & !(1 << 4), false);
                 self.stack_write(self.pc);
                 self.pc = 0x0060;
                 self.interrupts_enabled = false;
@@ -432,16 +718,11 @@ impl Cpu {
     }
 
     fn run_instruction(&mut self) {
-        
-        if self.pc == 0x0100 {
-            log::info!("CPU: Bootrom execution finished, executing loaded ROM.");
-            self.memory.bootrom_finished();
-        }
-        
-        let opcode = self.memory.read(self.pc);
+
+        let opcode = self.memory.cpu_read(self.pc);
 
         if opcode == 0xCB {
-            let opcode = self.memory.read(self.pc + 1);
+            let opcode = self.memory.cpu_read(self.pc + 1);
             let instruction = Instruction::new(opcode);
 
             if instruction.x == 0 {
@@ -655,7 +936,7 @@ impl Cpu {
 
     fn save_sp_to_imm(&mut self) {
         let value = self.get_rp(3);
-        let bytes = vec![self.memory.read(self.pc + 1), self.memory.read(self.pc + 2)];
+        let bytes = vec![self.memory.cpu_read(self.pc + 1), self.memory.cpu_read(self.pc + 2)];
         let hi = (value >> 8) as u8;
         let low = value as u8;
         let address = LittleEndian::read_u16(&bytes);
@@ -665,13 +946,20 @@ impl Cpu {
         self.instruction_finished(3, 20);
     }
 
+    // On a CGB, STOP with a speed switch armed in KEY1 switches between normal and double speed.
     fn stop(&mut self) {
-        error!("Unimplemented opcode STOP. Execution will continue, but things may break");
+        if self.memory.switch_speed() {
+            info!("CPU: Switched to {} speed", if self.memory.is_double_speed() {"double"} else {"normal"});
+        }
+        else {
+            error!("Unimplemented opcode STOP. Execution will continue, but things may break");
+        }
+
         self.instruction_finished(2, 4);
     }
 
     fn jr(&mut self) {
-        let value = self.memory.read(self.pc + 1) as i8;
+        let value = self.memory.cpu_read(self.pc + 1) as i8;
         self.pc = self.pc.wrapping_add(value as u16) + 2;
         self.instruction_finished(0, 12);
     }
@@ -695,7 +983,7 @@ impl Cpu {
 
     // Load 16-bit immediate value to a register pair (BC, DE, HL, SP).
     fn load_imm_to_rp(&mut self, index: u8) {
-        let bytes = vec![self.memory.read(self.pc + 1), self.memory.read(self.pc + 2)];
+        let bytes = vec![self.memory.cpu_read(self.pc + 1), self.memory.cpu_read(self.pc + 2)];
         
         self.set_rp(index, LittleEndian::read_u16(&bytes));
         self.instruction_finished(3, 12);
@@ -716,7 +1004,7 @@ impl Cpu {
 
     fn load_a_from_rp(&mut self, index: u8) {
         let address = self.get_rp(index);
-        let value = self.memory.read(address);
+        let value = self.memory.cpu_read(address);
 
         self.set_register(7, value);
         self.instruction_finished(1, 8);
@@ -724,7 +1012,7 @@ impl Cpu {
 
     fn load_a_from_hl_inc(&mut self) {
         let address = self.get_rp(2);
-        let value = self.memory.read(address);
+        let value = self.memory.cpu_read(address);
 
         self.set_register(7, value);
         self.set_rp(2, address.wrapping_add(1));
@@ -733,7 +1021,7 @@ impl Cpu {
 
     fn load_a_from_hl_dec(&mut self) {
         let address = self.get_rp(2);
-        let value = self.memory.read(address);
+        let value = self.memory.cpu_read(address);
 
         self.set_register(7, value);
         self.set_rp(2, address.wrapping_sub(1));
@@ -800,7 +1088,7 @@ impl Cpu {
 
     // Load immediate 8-bit value into a register.
     fn load_imm_into_reg(&mut self, index: u8) {
-        let value = self.memory.read(self.pc + 1);
+        let value = self.memory.cpu_read(self.pc + 1);
         self.set_register(index, value);
         self.instruction_finished(2, if index == 6 {12} else {8});
     }
@@ -1010,7 +1298,7 @@ impl Cpu {
     }
 
     fn save_a_to_ff_imm(&mut self) {
-        let address = 0xFF00 + self.memory.read(self.pc + 1) as u16;
+        let address = 0xFF00 + self.memory.cpu_read(self.pc + 1) as u16;
         let value = self.get_register(7);
 
         self.memory.write(address, value, true);
@@ -1018,7 +1306,7 @@ impl Cpu {
     }
 
     fn add_imm_to_sp(&mut self) {
-        let value = self.memory.read(self.pc + 1) as i8;
+        let value = self.memory.cpu_read(self.pc + 1) as i8;
         let result = self.get_rp(3).wrapping_add(value as u16);
 
         self.set_rp(3, result);
@@ -1032,15 +1320,15 @@ impl Cpu {
 
     // Load the value pointed by 0xFF00 + immediate value into A.
     fn load_a_from_ff_imm(&mut self) {
-        let address = 0xFF00 + self.memory.read(self.pc + 1) as u16;
-        let value = self.memory.read(address);
+        let address = 0xFF00 + self.memory.cpu_read(self.pc + 1) as u16;
+        let value = self.memory.cpu_read(address);
 
         self.set_register(7, value);
         self.instruction_finished(2, 12);
     }
 
     fn load_sp_imm_to_hl(&mut self) {
-        let imm = self.memory.read(self.pc + 1) as i8;
+        let imm = self.memory.cpu_read(self.pc + 1) as i8;
         let result = self.get_rp(3).wrapping_add(imm as u16);
 
         self.set_rp(2, result);
@@ -1107,7 +1395,7 @@ impl Cpu {
 
     // Save the value of A into address at immediate value.
     fn save_a_to_imm(&mut self) {
-        let bytes = vec![self.memory.read(self.pc + 1), self.memory.read(self.pc + 2)];
+        let bytes = vec![self.memory.cpu_read(self.pc + 1), self.memory.cpu_read(self.pc + 2)];
         let value = self.get_register(7);
 
         self.memory.write(LittleEndian::read_u16(&bytes), value, true);
@@ -1117,7 +1405,7 @@ impl Cpu {
     // Read address 0xFF00 + the value of C, and load the value into A.
     fn load_a_from_ff_c(&mut self) {
         let address = 0xFF00 + self.get_register(1) as u16;
-        let value = self.memory.read(address);
+        let value = self.memory.cpu_read(address);
 
         self.set_register(7, value);
         self.instruction_finished(1, 8);
@@ -1125,15 +1413,15 @@ impl Cpu {
 
     // Load value from address at immediate value into A.
     fn load_a_from_imm(&mut self) {
-        let bytes = vec![self.memory.read(self.pc + 1), self.memory.read(self.pc + 2)];
-        let value = self.memory.read(LittleEndian::read_u16(&bytes));
+        let bytes = vec![self.memory.cpu_read(self.pc + 1), self.memory.cpu_read(self.pc + 2)];
+        let value = self.memory.cpu_read(LittleEndian::read_u16(&bytes));
 
         self.set_register(7, value);
         self.instruction_finished(3, 16);
     }
 
     fn jp(&mut self) {
-        let bytes = vec![self.memory.read(self.pc + 1), self.memory.read(self.pc + 2)];
+        let bytes = vec![self.memory.cpu_read(self.pc + 1), self.memory.cpu_read(self.pc + 2)];
         let address = LittleEndian::read_u16(&bytes);
 
         self.pc = address;
@@ -1174,7 +1462,7 @@ impl Cpu {
     }
 
     fn call(&mut self) {
-        let bytes = vec![self.memory.read(self.pc + 1), self.memory.read(self.pc + 2)];
+        let bytes = vec![self.memory.cpu_read(self.pc + 1), self.memory.cpu_read(self.pc + 2)];
         let target_address = LittleEndian::read_u16(&bytes);
         let ret_address = self.pc + 3;
         
@@ -1184,8 +1472,8 @@ impl Cpu {
     }
 
     fn add_imm(&mut self) {
-        let hf = (((self.get_register(7) & 0xF) + (self.memory.read(self.pc + 1) & 0xF)) & 0x10) == 0x10;
-        let result = self.get_register(7) as u16 + self.memory.read(self.pc + 1) as u16;
+        let hf = (((self.get_register(7) & 0xF) + (self.memory.cpu_read(self.pc + 1) & 0xF)) & 0x10) == 0x10;
+        let result = self.get_register(7) as u16 + self.memory.cpu_read(self.pc + 1) as u16;
 
         self.set_register(7, result as u8);
         self.cpu_flags.set_zf(result as u8 == 0);
@@ -1196,8 +1484,8 @@ impl Cpu {
     }
 
     fn adc_imm(&mut self) {
-        let hf = (((self.get_register(7) & 0xF) + (self.memory.read(self.pc + 1) & 0xF) + (self.cpu_flags.get_cf())) & 0x10) == 0x10;
-        let result = self.get_register(7) as u16 + self.memory.read(self.pc + 1) as u16 + self.cpu_flags.get_cf() as u16;
+        let hf = (((self.get_register(7) & 0xF) + (self.memory.cpu_read(self.pc + 1) & 0xF) + (self.cpu_flags.get_cf())) & 0x10) == 0x10;
+        let result = self.get_register(7) as u16 + self.memory.cpu_read(self.pc + 1) as u16 + self.cpu_flags.get_cf() as u16;
 
         self.set_register(7, result as u8);
         self.cpu_flags.set_zf(result as u8 == 0);
@@ -1208,8 +1496,8 @@ impl Cpu {
     }
 
     fn sub_imm(&mut self) {
-        let hf = ((self.get_register(7) as i16 & 0xF) - (self.memory.read(self.pc + 1) as i16 & 0xF)) < 0;
-        let result = self.get_register(7) as i16 - self.memory.read(self.pc + 1) as i16;
+        let hf = ((self.get_register(7) as i16 & 0xF) - (self.memory.cpu_read(self.pc + 1) as i16 & 0xF)) < 0;
+        let result = self.get_register(7) as i16 - self.memory.cpu_read(self.pc + 1) as i16;
 
         self.set_register(7, result as u8);
         self.cpu_flags.set_zf(result as u8 == 0);
@@ -1220,8 +1508,8 @@ impl Cpu {
     }
 
     fn sbc_imm(&mut self) {
-        let hf = ((self.get_register(7) as i16 & 0xF) - (self.memory.read(self.pc + 1) as i16 & 0xF) - self.cpu_flags.get_cf() as i16) < 0;
-        let result = self.get_register(7) as i16 - self.memory.read(self.pc + 1) as i16 - self.cpu_flags.get_cf() as i16;
+        let hf = ((self.get_register(7) as i16 & 0xF) - (self.memory.cpu_read(self.pc + 1) as i16 & 0xF) - self.cpu_flags.get_cf() as i16) < 0;
+        let result = self.get_register(7) as i16 - self.memory.cpu_read(self.pc + 1) as i16 - self.cpu_flags.get_cf() as i16;
 
         self.set_register(7, result as u8);
         self.cpu_flags.set_zf(result as u8 == 0);
@@ -1232,7 +1520,7 @@ impl Cpu {
     }
 
     fn and_imm(&mut self) {
-        let result = self.get_register(7) & self.memory.read(self.pc + 1);
+        let result = self.get_register(7) & self.memory.cpu_read(self.pc + 1);
 
         self.set_register(7, result);
         self.cpu_flags.set_zf(result == 0);
@@ -1243,7 +1531,7 @@ impl Cpu {
     }
 
     fn xor_imm(&mut self) {
-        let result = self.get_register(7) ^ self.memory.read(self.pc + 1);
+        let result = self.get_register(7) ^ self.memory.cpu_read(self.pc + 1);
 
         self.set_register(7, result);
         self.cpu_flags.set_zf(result == 0);
@@ -1254,7 +1542,7 @@ impl Cpu {
     }
 
     fn or_imm(&mut self) {
-        let result = self.get_register(7) | self.memory.read(self.pc + 1);
+        let result = self.get_register(7) | self.memory.cpu_read(self.pc + 1);
 
         self.set_register(7, result);
         self.cpu_flags.set_zf(result == 0);
@@ -1265,8 +1553,8 @@ impl Cpu {
     }
 
     fn cp_imm(&mut self) {
-        let hf = ((self.get_register(7) as i16 & 0xF) - (self.memory.read(self.pc + 1) as i16 & 0xF)) < 0;
-        let values = (self.get_register(7), self.memory.read(self.pc + 1));
+        let hf = ((self.get_register(7) as i16 & 0xF) - (self.memory.cpu_read(self.pc + 1) as i16 & 0xF)) < 0;
+        let values = (self.get_register(7), self.memory.cpu_read(self.pc + 1));
 
         self.cpu_flags.set_zf(values.0 == values.1);
         self.cpu_flags.set_nf(true);
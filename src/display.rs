@@ -0,0 +1,66 @@
+use std::convert::TryInto;
+
+
+// How finished frames are colored before they're presented. Only the last step changes, the GPU
+// keeps drawing DMG shades and CGB colors the same way.
+
+// 4 colors, from the lightest shade to the darkest.
+pub type DmgPalette = [u32; 4];
+
+const DMG_PALETTES: [(&str, DmgPalette); 3] = [
+    ("gray", [0xFFFFFF, 0xC0C0C0, 0x606060, 0x000000]),
+    ("green", [0x9BBC0F, 0x8BAC0F, 0x306230, 0x0F380F]),
+    ("pocket", [0xC4CFA1, 0x8B956D, 0x4D533C, 0x1F1F1F]),
+];
+
+#[derive(Clone, Copy)]
+pub struct DisplaySettings {
+    // For pixels that went through BGP, OBP0 and OBP1.
+    pub dmg_palettes: [DmgPalette; 3],
+    // Mimics the washed out colors of the CGB's LCD, instead of showing palette RAM as it is.
+    pub color_correction: bool,
+    // Screenshots are saved this many times bigger than the screen.
+    pub screenshot_scale: u32,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> DisplaySettings {
+        DisplaySettings {
+            dmg_palettes: [DMG_PALETTES[0].1; 3],
+            color_correction: false,
+            screenshot_scale: 1,
+        }
+    }
+}
+
+// A preset name, or 4 hex colors separated by commas, like "#E0F8D0, #88C070, #346856, #081820".
+pub fn parse_dmg_palette(value: &str) -> Option<DmgPalette> {
+    if let Some((_, palette)) = DMG_PALETTES.iter().find(|(name, _)| *name == value) {
+        return Some(*palette);
+    }
+
+    let colors: Vec<u32> = value.split(',')
+        .map(|color| {
+            let color = color.trim().trim_start_matches('#');
+            if color.len() == 6 {u32::from_str_radix(color, 16).ok()} else {None}
+        })
+        .collect::<Option<Vec<u32>>>()?;
+
+    colors.try_into().ok()
+}
+
+pub fn dmg_rgba(palette: &DmgPalette, shade: u8) -> [u8; 4] {
+    let color = palette[shade as usize];
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8, 255]
+}
+
+// Mixes the channels and darkens them like the CGB's screen does, from the 5 bits per channel of
+// palette RAM. Frames hold them expanded to 8 bits, the low 3 bits are a copy of the high ones.
+pub fn correct_color(pixel: &mut [u8]) {
+    let (red, green, blue) = ((pixel[0] >> 3) as u32, (pixel[1] >> 3) as u32, (pixel[2] >> 3) as u32);
+
+    let mix = |value: u32| (value.min(960) >> 2) as u8;
+    pixel[0] = mix(red * 26 + green * 4 + blue * 2);
+    pixel[1] = mix(green * 24 + blue * 8);
+    pixel[2] = mix(red * 6 + green * 4 + blue * 22);
+}
@@ -2,70 +2,363 @@ use std::io;
 use std::thread;
 use std::io::Read;
 use std::fs::File;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
 use std::sync::atomic::AtomicU16;
+use std::thread::JoinHandle;
+use std::time::Duration;
 
 use log::info;
 use log::error;
 
-use super::cpu::Cpu;
-use super::gpu::Gpu;
+use super::cpu::{Cpu, Exit};
+use super::gpu::FrameBuffer;
+use super::screenshot;
 use super::cart::CartData;
+use super::link::LinkCable;
 use super::memory::Memory;
+use super::movie::{Movie, MovieStatus, StartState};
+use super::rewind::RewindSettings;
+use super::pacing::{PacingStatus, SpeedSettings};
+use super::joypad::Button;
+use super::options::Options;
+use super::config::Config;
+use super::frontend::Frontend;
 
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub enum InputEvent {
     
     // SDL Quit event.
     Quit,
 
-    // Buttons being pressed.
-    APressed,
-    BPressed,
-    UpPressed,
-    DownPressed,
-    LeftPressed,
-    RightPressed,
-    StartPressed,
-    SelectPressed,
+    // Buttons being pressed and released.
+    ButtonPressed(Button),
+    ButtonReleased(Button),
+
+    // Turbo versions of the buttons, toggled by the emulated frame counter.
+    TurboPressed(Button),
+    TurboReleased(Button),
+    SetTurboRate(u32),
+
+    // Speed control. Frame advance only works while paused.
+    TogglePause,
+    SetPaused(bool),
+    FrameAdvance,
+    SetFastForward(bool),
+    ToggleSlowMotion,
+    ConfigureSpeed(SpeedSettings),
+
+    // Rewinding goes on for as long as the key is held.
+    SetRewinding(bool),
+    ConfigureRewind(RewindSettings),
+
+    // Save state slots, from 0 to savestate::STATE_SLOTS - 1.
+    SaveState(usize),
+    LoadState(usize),
+
+    // Switches movie playback between read-only and taking over on input.
+    ToggleMovieReadOnly,
+
+    // Reset keeps the loaded cart, a power cycle loads the ROM from disk again.
+    Reset,
+    PowerCycle,
+}
+
+// Drives a running emulator from outside the CPU thread, can be cloned freely.
+#[derive(Clone)]
+pub struct EmulatorControl {
+    input_tx: Sender<InputEvent>,
+}
+
+impl EmulatorControl {
+    pub fn send(&self, event: InputEvent) {
+        if let Err(error) = self.input_tx.send(event) {
+            error!("Emu: Failed to send event to CPU, error {}", error);
+        }
+    }
+
+    pub fn pause(&self) {
+        self.send(InputEvent::SetPaused(true));
+    }
+
+    pub fn resume(&self) {
+        self.send(InputEvent::SetPaused(false));
+    }
+
+    pub fn toggle_pause(&self) {
+        self.send(InputEvent::TogglePause);
+    }
+
+    pub fn reset(&self) {
+        self.send(InputEvent::Reset);
+    }
+
+    pub fn power_cycle(&self) {
+        self.send(InputEvent::PowerCycle);
+    }
+
+    pub fn quit(&self) {
+        self.send(InputEvent::Quit);
+    }
+}
+
+// One running Game Boy, driven by its own CPU thread.
+pub struct Emulator {
+    memory: Arc<Memory>,
+    // Replaces the memory above after a power cycle.
+    memory_rx: Receiver<Arc<Memory>>,
+    frame: Arc<FrameBuffer>,
+    control: EmulatorControl,
+    pacing: Arc<PacingStatus>,
+    movie_status: Option<Arc<MovieStatus>>,
+
+    cpu_thread: JoinHandle<Arc<Memory>>,
+}
+
+impl Emulator {
+    // Without battery saves the cart RAM never reaches the disk, not even after a power cycle.
+    // The CPU thread draws into the given frame buffer.
+    pub fn start(memory: Arc<Memory>, frame: FrameBuffer, rom_path: &Path, movie: Option<Movie>, realtime: bool, battery_saves: bool) -> Emulator {
+
+        let cpu_cycles = Arc::new(AtomicU16::new(0));
+        let cpu_memory = Arc::clone(&memory);
+
+        let frame = Arc::new(frame);
+        let cpu_frame = Arc::clone(&frame);
+        let movie_status = movie.as_ref().map(|movie| movie.status());
+
+        let pacing = Arc::new(PacingStatus::new());
+        let cpu_pacing = Arc::clone(&pacing);
+
+        let (input_tx, input_rx) = mpsc::channel();
+        let (memory_tx, memory_rx) = mpsc::channel();
+        let rom_path = rom_path.to_path_buf();
+
+        let cpu_thread = thread::Builder::new().name("cpu_thread".to_string()).spawn(move || {
+            let bootrom = cpu_memory.is_bootrom_loaded();
+            let mut current_cpu = Cpu::new(cpu_memory, cpu_cycles, input_rx, bootrom, cpu_frame, cpu_pacing);
+            current_cpu.movie = movie;
+            current_cpu.pacer.set_realtime(realtime);
+
+            while current_cpu.execution_loop() == Exit::PowerCycle {
+                match power_on(&rom_path, battery_saves, &current_cpu.memory) {
+                    Ok(memory) => {
+                        memory.take_outputs(&current_cpu.memory);
+                        memory_tx.send(Arc::clone(&memory)).ok();
+                        current_cpu.power_cycle(memory);
+                        info!("Emu: Power cycled, ROM reloaded from {:#?}", rom_path);
+                    },
+                    Err(error) => error!("Emu: Failed to reload the ROM from {:#?}, error {}. Keeping the current one", rom_path, error),
+                }
+            }
+
+            current_cpu.memory
+        }).unwrap();
+
+        Emulator {
+            memory,
+            memory_rx,
+            frame,
+            control: EmulatorControl { input_tx },
+            pacing,
+            movie_status,
+
+            cpu_thread,
+        }
+    }
+
+    pub fn memory(&self) -> &Arc<Memory> {
+        &self.memory
+    }
+
+    // Picks up the memory a power cycle left behind.
+    pub fn update_memory(&mut self) {
+        while let Ok(memory) = self.memory_rx.try_recv() {
+            self.memory = memory;
+        }
+    }
+
+    pub fn frame(&self) -> &Arc<FrameBuffer> {
+        &self.frame
+    }
+
+    pub fn control(&self) -> &EmulatorControl {
+        &self.control
+    }
+
+    pub fn pacing(&self) -> &Arc<PacingStatus> {
+        &self.pacing
+    }
+
+    // Saves the last finished frame to the screenshots folder. Linked players each get their own file.
+    pub fn save_screenshot(&self, player: Option<usize>) -> io::Result<PathBuf> {
+        let path = screenshot::timestamped_path(self.memory.rom_title(), player);
+        self.frame.save_screenshot(&path)?;
+        Ok(path)
+    }
+
+    pub fn movie_status(&self) -> Option<&Arc<MovieStatus>> {
+        self.movie_status.as_ref()
+    }
+
+    // Waits for the CPU thread to quit, then finishes whatever is being recorded.
+    pub fn join(self) {
+        let memory = self.cpu_thread.join().unwrap();
+        memory.stop_audio_recording();
+        memory.stop_channel_dumps();
+        memory.stop_video_recording();
+    }
 }
 
 pub fn initialize() {
 
-    let cart_data = load_rom();
-    let bootrom_data = load_bootrom();
+    let options = match Options::from_args() {
+        Ok(options) => options,
+        Err(error) => {
+            error!("Options: {}", error);
+            return;
+        }
+    };
+
+    let rom_path = rom_path(&options.rom_path);
+    let mut cart_data = match load_rom(&rom_path) {
+        Ok(cart_data) => cart_data,
+        Err(error) => panic!("Loader: Failed to open the ROM file, error {}. Can't continue operation", error),
+    };
+    let (bootrom, mut use_bootrom) = load_bootrom();
+    let config = Config::load(&options.config_path());
+    let mut compatibility = config.compatibility();
+    let mut sgb_settings = config.sgb_settings();
+    let display = config.display_settings();
+
+    let movie = if let Some(path) = &options.play_movie {
+        let movie = match Movie::load(path, cart_data.rom_hash()) {
+            Ok(movie) => movie,
+            Err(error) => {
+                error!("Movie: {}", error);
+                return;
+            }
+        };
+
+        // Playback has to start from the exact same state to stay in sync.
+        match movie.start_state() {
+            StartState::PowerOn { bootrom: movie_bootrom, cart_ram, compatibility: movie_compatibility, sgb } => {
+                if *movie_bootrom && !use_bootrom {
+                    error!("Movie: The movie was recorded with the bootrom, but none is loaded");
+                    return;
+                }
+
+                use_bootrom = *movie_bootrom;
+                compatibility = *movie_compatibility;
+                sgb_settings.enabled = *sgb;
+                cart_data.load_ram_snapshot(cart_ram);
+                cart_data.disable_ram_saving();
+            }
+        }
+
+        Some(movie)
+    }
+    else if let Some(path) = &options.record_movie {
+        let start_state = StartState::PowerOn { bootrom: use_bootrom, cart_ram: cart_data.ram_snapshot(), compatibility, sgb: sgb_settings.enabled };
+        Some(Movie::record(path, cart_data.rom_hash(), start_state))
+    }
+    else {
+        None
+    };
     
-    let memory = Arc::new(Memory::new(bootrom_data.0, bootrom_data.1, cart_data));
-    let arcs = (Arc::clone(&memory), memory);
+    let memory = Arc::new(Memory::new(bootrom, use_bootrom, cart_data, compatibility, sgb_settings));
+
+    if let Some(path) = &options.record_audio {
+        memory.start_audio_recording(path, options.record_sample_rate);
+    }
+
+    if let Some(path) = &options.dump_channels {
+        memory.start_channel_dumps(path, options.record_sample_rate);
+    }
+
+    if let Some(path) = &options.record_video {
+        memory.start_video_recording(path);
+    }
+
+    let realtime = !options.headless;
+
+    // Scheduled before the CPU thread starts, even the first frames can be captured.
+    let frame = FrameBuffer::new(display);
+
+    if let Some((frame_number, path)) = &options.screenshot_at_frame {
+        frame.schedule_screenshot(*frame_number, path.clone());
+    }
+
+    let emulators = if let Some(second_rom_path) = &options.link_local {
+        let mut second_cart_data = match load_rom(second_rom_path) {
+            Ok(cart_data) => cart_data,
+            Err(error) => {
+                error!("Loader: Failed to open the second ROM file, error {}", error);
+                return;
+            }
+        };
 
-    start_emulation(arcs);
+        // Both carts would save to the same file otherwise, the first Game Boy keeps it.
+        let battery_saves = second_cart_data.rom_title() != memory.rom_title();
+
+        if !battery_saves {
+            info!("Emu: Both Game Boys run the same game, the second one won't save its cart RAM");
+            second_cart_data.disable_ram_saving();
+        }
+
+        let (second_bootrom, second_use_bootrom) = load_bootrom();
+        let second_memory = Arc::new(Memory::new(second_bootrom, second_use_bootrom, second_cart_data, compatibility, sgb_settings));
+
+        let (cable, second_cable) = LinkCable::local_pair();
+        memory.set_serial_device(Box::new(cable));
+        second_memory.set_serial_device(Box::new(second_cable));
+
+        vec![
+            Emulator::start(memory, frame, &rom_path, movie, realtime, true),
+            Emulator::start(second_memory, FrameBuffer::new(display), second_rom_path, None, realtime, battery_saves),
+        ]
+    }
+    else {
+        match options.serial.device() {
+            Ok(device) => memory.set_serial_device(device),
+            Err(error) => {
+                error!("Serial: {}", error);
+                return;
+            }
+        }
+
+        vec![Emulator::start(memory, frame, &rom_path, movie, realtime, true)]
+    };
+
+    run(emulators, &options, &config);
 }
 
-pub fn start_emulation(memory: (Arc<Memory>, Arc<Memory>)) {
-        
-    let cpu_cycles = Arc::new(AtomicU16::new(0));
-    let gpu_cycles = Arc::clone(&cpu_cycles);
+fn run(emulators: Vec<Emulator>, options: &Options, config: &Config) {
 
-    let cpu_memory = memory.0;
-    let gpu_memory = memory.1;
-    
-    let (input_tx, input_rx) = mpsc::channel();
+    let emulators = if options.headless {
+        // Without a frontend the only way out is the frame limit.
+        if let Some(frames) = options.frames {
+            while emulators[0].frame().frame_count() < frames {
+                thread::sleep(Duration::from_millis(1));
+            }
 
-    let cpu_thread = thread::Builder::new().name("cpu_thread".to_string()).spawn(move || {
-        let bootrom = cpu_memory.is_bootrom_loaded();
-        let mut current_cpu = Cpu::new(cpu_memory, cpu_cycles, input_rx, bootrom);
-        current_cpu.execution_loop();
-    }).unwrap();
+            for emulator in emulators.iter() {
+                emulator.control().quit();
+            }
+        }
 
-    let _gpu_thread = thread::Builder::new().name("gpu_thread".to_string()).spawn(move || {
-        let mut current_gpu = Gpu::new(gpu_cycles, gpu_memory, input_tx);
-        current_gpu.execution_loop();
-    }).unwrap();
+        emulators
+    }
+    else {
+        Frontend::new(emulators, options, config).run()
+    };
 
-    cpu_thread.join().unwrap();
+    for emulator in emulators {
+        emulator.join();
+    }
 
     info!("Emu: CPU thread finished execution, stopping emulator...");
 }
@@ -80,8 +373,15 @@ fn load_bootrom() -> (Vec<u8>, bool) {
 
             let result = match bootrom_file.read_to_end(&mut data) {
                 Ok(_) => {
-                    info!("Loader: Bootrom loaded");
-                    (data, true)
+                    // 256 bytes for DMG bootroms, 2304 bytes for CGB ones.
+                    if data.len() == 256 || data.len() == 2304 {
+                        info!("Loader: Bootrom loaded");
+                        (data, true)
+                    }
+                    else {
+                        error!("Loader: Unexpected Bootrom size ({} bytes). The emulator will continue without it", data.len());
+                        (Vec::new(), false)
+                    }
                 },
                 Err(error) => {
                     error!("Loader: Failed to open the Bootrom file. Error: {}. The emulator will continue without it", error);
@@ -98,18 +398,39 @@ fn load_bootrom() -> (Vec<u8>, bool) {
     }
 }
 
-fn load_rom() -> CartData {
+fn rom_path(path: &Option<PathBuf>) -> PathBuf {
+
+    match path {
+        Some(path) => path.clone(),
+        None => {
+            let mut path_str = String::new();
+            info!("Loader: Point me to a Gameboy ROM");
+            io::stdin().read_line(&mut path_str).expect("Loader: Failed to read ROM path");
+            PathBuf::from(path_str.trim())
+        }
+    }
+}
+
+fn load_rom(path: &Path) -> io::Result<CartData> {
     
-    let mut path_str = String::new();
-    info!("Loader: Point me to a Gameboy ROM");
-    io::stdin().read_line(&mut path_str).expect("Loader: Failed to read ROM path");
-    let mut rom_file = File::open(PathBuf::from(path_str.trim())).expect("Loader: Failed to open ROM");
+    let mut rom_file = File::open(path)?;
     let mut data = Vec::new();
 
-    match rom_file.read_to_end(&mut data){
-        Ok(_) => info!("Loader: ROM loaded"),
-        Err(_) => panic!("Loader: Failed to open the ROM file. Can't continue operation"),
-    };
+    rom_file.read_to_end(&mut data)?;
+    info!("Loader: ROM loaded");
 
-    CartData::new(data)
-}
\ No newline at end of file
+    Ok(CartData::new(data))
+}
+
+// Everything a power cycle needs, the ROM and bootrom are read again in case they changed on disk.
+// The hardware settings stay the same as in the old memory.
+fn power_on(rom_path: &Path, battery_saves: bool, old: &Memory) -> io::Result<Arc<Memory>> {
+    let mut cart_data = load_rom(rom_path)?;
+    let (bootrom, use_bootrom) = load_bootrom();
+
+    if !battery_saves {
+        cart_data.disable_ram_saving();
+    }
+
+    Ok(Arc::new(Memory::new(bootrom, use_bootrom, cart_data, old.compatibility(), old.sgb_settings())))
+}
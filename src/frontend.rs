@@ -0,0 +1,593 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+
+use sdl2::audio::AudioQueue;
+use sdl2::audio::AudioSpecDesired;
+
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::controller;
+use sdl2::controller::{Axis, GameController};
+use sdl2::GameControllerSubsystem;
+
+use sdl2::video::Window;
+use sdl2::rect::Rect;
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::render::BlendMode;
+
+use sdl2::render::Canvas;
+use sdl2::render::Texture;
+use sdl2::render::TextureCreator;
+use sdl2::video::WindowContext;
+
+use super::apu;
+use super::gpu::{Frame, SCREEN_WIDTH, SCREEN_HEIGHT};
+use super::movie::MovieMode;
+use super::options::Options;
+use super::joypad::Button;
+use super::config::{Config, Hotkey, InputBindings};
+use super::emulator::{Emulator, EmulatorControl, InputEvent};
+
+
+// Each source keeps its own set of held buttons, so releasing a key doesn't
+// release the same button held on a controller.
+#[derive(Clone, Copy)]
+enum InputSource {
+    Keyboard,
+    Controller,
+    Stick,
+}
+
+// Window, input and audio output. Emulation itself doesn't depend on any of this.
+//
+// With --link-local there are two Game Boys, shown side by side. Audio, movies, the controllers and
+// the [keyboard] keys belong to the first one, the [keyboard_2] keys to the second.
+pub struct Frontend {
+    emulators: Vec<Emulator>,
+
+    record_audio_path: Option<PathBuf>,
+    record_sample_rate: u32,
+    record_video_path: Option<PathBuf>,
+
+    show_input_display: bool,
+    // Set if minimizing the window paused emulation, so restoring it resumes.
+    paused_while_minimized: bool,
+
+    config_path: PathBuf,
+    bindings: InputBindings,
+    // Per player.
+    held_buttons: Vec<[u8; 3]>,
+    held_turbo: Vec<[u8; 3]>,
+
+    event_pump: sdl2::EventPump,
+    controller_subsystem: Option<GameControllerSubsystem>,
+    // Open controllers, by joystick instance id.
+    controllers: HashMap<u32, GameController>,
+
+    game_canvas: Canvas<Window>,
+    // One per emulator, with the size of its last frame. SGB borders make the screen bigger.
+    game_textures: Vec<Texture>,
+    screen_sizes: Vec<(u32, u32)>,
+    texture_creator: TextureCreator<WindowContext>,
+
+    audio_queue: Option<AudioQueue<f32>>,
+}
+
+impl Frontend {
+    pub fn new(emulators: Vec<Emulator>, options: &Options, config: &Config) -> Frontend {
+
+        let config_path = options.config_path();
+
+        let sdl_ctx = sdl2::init().unwrap();
+        let sdl_video = sdl_ctx.video().unwrap();
+
+        // Already connected controllers show up as ControllerDeviceAdded events too, so they get opened in update_inputs.
+        let controller_subsystem = match sdl_ctx.game_controller() {
+            Ok(subsystem) => Some(subsystem),
+            Err(error) => {
+                error!("Input: Failed to initialize game controller support, error {}. Only the keyboard will work", error);
+                None
+            }
+        };
+
+        let screens = emulators.len() as u32;
+        let game_window = sdl_video.window("Rusty Boi - Game - FPS: 0", SCREEN_WIDTH * 4 * screens, SCREEN_HEIGHT * 4).position_centered().build().unwrap();
+        let mut game_canvas = game_window.into_canvas().present_vsync().build().unwrap();
+        game_canvas.set_blend_mode(BlendMode::Blend);
+        let texture_creator = game_canvas.texture_creator();
+        let game_textures = (0..screens).map(|_| texture_creator.create_texture_streaming(PixelFormatEnum::RGBA32, SCREEN_WIDTH, SCREEN_HEIGHT).unwrap()).collect();
+
+        game_canvas.clear();
+        game_canvas.present();
+
+        let audio_spec = AudioSpecDesired {
+            freq: Some(apu::SAMPLE_RATE as i32),
+            channels: Some(2),
+            samples: Some(1024),
+        };
+
+        let audio_queue = match sdl_ctx.audio().and_then(|audio| audio.open_queue::<f32, _>(None, &audio_spec)) {
+            Ok(queue) => {
+                queue.resume();
+                Some(queue)
+            },
+            Err(error) => {
+                error!("Audio: Failed to open the audio device, error {}. The emulator will continue without sound", error);
+                None
+            }
+        };
+
+        let players = emulators.len();
+
+        let frontend = Frontend {
+            emulators,
+
+            record_audio_path: options.record_audio.clone(),
+            record_sample_rate: options.record_sample_rate,
+            record_video_path: options.record_video.clone(),
+
+            show_input_display: false,
+            paused_while_minimized: false,
+
+            config_path,
+            bindings: config.input_bindings(),
+            held_buttons: vec![[0; 3]; players],
+            held_turbo: vec![[0; 3]; players],
+
+            event_pump: sdl_ctx.event_pump().unwrap(),
+            controller_subsystem,
+            controllers: HashMap::new(),
+
+            game_canvas,
+            game_textures,
+            screen_sizes: vec![(SCREEN_WIDTH, SCREEN_HEIGHT); players],
+            texture_creator,
+
+            audio_queue,
+        };
+
+        frontend.load_controller_mappings();
+        frontend.send_input(InputEvent::SetTurboRate(frontend.bindings.turbo_rate));
+        frontend.send_input(InputEvent::ConfigureRewind(frontend.bindings.rewind));
+        frontend.send_input(InputEvent::ConfigureSpeed(frontend.bindings.speed));
+        frontend
+    }
+
+    // Hands the emulators back once the window has been closed.
+    pub fn run(mut self) -> Vec<Emulator> {
+        let mut fps_timer = Instant::now();
+        let mut fps_frames = 0;
+        let mut last_frame = self.emulators[0].frame().frame_count();
+
+        loop {
+            if self.update_inputs() {
+                break;
+            }
+
+            for emulator in self.emulators.iter_mut() {
+                emulator.update_memory();
+            }
+
+            // Linked Game Boys run in lockstep, the first one sets the pace for the window.
+            let current_frame = self.emulators[0].frame().frame_count();
+
+            if current_frame != last_frame {
+                fps_frames += current_frame - last_frame;
+                last_frame = current_frame;
+
+                self.present_frame();
+                self.queue_audio();
+            }
+            else {
+                std::thread::sleep(Duration::from_millis(1));
+            }
+
+            if fps_timer.elapsed() >= Duration::from_millis(1000) {
+                let paused = if self.emulators[0].pacing().is_paused() {" - Paused"} else {""};
+                let title = format!("Rusty Boi - Game - FPS: {:#?}{}{}", fps_frames / fps_timer.elapsed().as_secs(), paused, self.movie_title());
+                self.game_canvas.window_mut().set_title(&title).unwrap();
+                fps_timer = Instant::now();
+                fps_frames = 0;
+            }
+        }
+
+        self.emulators
+    }
+
+    fn present_frame(&mut self) {
+        self.game_canvas.clear();
+
+        let frames: Vec<Frame> = self.emulators.iter().map(|emulator| emulator.frame().frame()).collect();
+        self.resize_screens(&frames);
+
+        let mut x = 0;
+
+        for (index, frame) in frames.iter().enumerate() {
+            let screen = Rect::new(x, 0, frame.width * 4, frame.height * 4);
+            x += screen.width() as i32;
+
+            self.game_textures[index].update(None, &frame.pixels, frame.width as usize * 4).unwrap();
+            self.game_canvas.copy(&self.game_textures[index], None, screen).unwrap();
+
+            if self.show_input_display {
+                self.draw_input_display(index, screen);
+            }
+        }
+
+        self.game_canvas.present();
+    }
+
+    // Screens are laid out side by side, the window grows or shrinks when one of them changes size.
+    fn resize_screens(&mut self, frames: &[Frame]) {
+        let sizes: Vec<(u32, u32)> = frames.iter().map(|frame| (frame.width, frame.height)).collect();
+
+        if sizes == self.screen_sizes {
+            return;
+        }
+
+        for (index, &(width, height)) in sizes.iter().enumerate() {
+            if sizes[index] != self.screen_sizes[index] {
+                self.game_textures[index] = self.texture_creator.create_texture_streaming(PixelFormatEnum::RGBA32, width, height).unwrap();
+            }
+        }
+
+        let width = sizes.iter().map(|(width, _)| width * 4).sum();
+        let height = sizes.iter().map(|(_, height)| height * 4).max().unwrap_or(SCREEN_HEIGHT * 4);
+
+        if let Err(error) = self.game_canvas.window_mut().set_size(width, height) {
+            error!("Video: Failed to resize the window, error {}", error);
+        }
+
+        self.screen_sizes = sizes;
+    }
+
+    fn movie_title(&self) -> String {
+        let info = match self.emulators[0].movie_status() {
+            Some(status) => status.info(),
+            None => return String::new(),
+        };
+
+        match info.mode {
+            MovieMode::Recording => format!(" - Recording movie: {}", info.frame),
+            MovieMode::Playing => format!(" - Playing movie: {}/{}{}", info.frame, info.length, if info.read_only {" (read-only)"} else {""}),
+            MovieMode::Finished => String::from(" - Movie finished"),
+        }
+    }
+
+    // Draws the buttons the joypad currently sees in the bottom left corner of the screen, pressed ones filled in.
+    fn draw_input_display(&mut self, player: usize, screen: Rect) {
+        let pressed = self.emulators[player].memory().joypad_state();
+        let size = 16;
+        let x = screen.x() + 8;
+        let y = screen.bottom() - 8 - size * 3;
+
+        let layout = [
+            (Button::Up, Rect::new(x + size, y, size as u32, size as u32)),
+            (Button::Left, Rect::new(x, y + size, size as u32, size as u32)),
+            (Button::Right, Rect::new(x + size * 2, y + size, size as u32, size as u32)),
+            (Button::Down, Rect::new(x + size, y + size * 2, size as u32, size as u32)),
+            (Button::Select, Rect::new(x + size * 4, y + size * 2, size as u32 * 2, size as u32 / 2)),
+            (Button::Start, Rect::new(x + size * 7, y + size * 2, size as u32 * 2, size as u32 / 2)),
+            (Button::B, Rect::new(x + size * 10, y + size, size as u32, size as u32)),
+            (Button::A, Rect::new(x + size * 12, y + size / 2, size as u32, size as u32)),
+        ];
+
+        for (button, rect) in layout.iter() {
+            if pressed & button.mask() != 0 {
+                self.game_canvas.set_draw_color(Color::RGBA(255, 255, 255, 220));
+            }
+            else {
+                self.game_canvas.set_draw_color(Color::RGBA(0, 0, 0, 120));
+            }
+
+            self.game_canvas.fill_rect(*rect).unwrap();
+            self.game_canvas.set_draw_color(Color::RGBA(255, 255, 255, 220));
+            self.game_canvas.draw_rect(*rect).unwrap();
+        }
+
+        self.game_canvas.set_draw_color(Color::RGB(0, 0, 0));
+    }
+
+    fn queue_audio(&mut self) {
+        let samples = self.emulators[0].memory().take_audio_samples();
+
+        // Only the first Game Boy is heard, the others' samples would just pile up.
+        for emulator in self.emulators.iter().skip(1) {
+            emulator.memory().take_audio_samples();
+        }
+
+        if let Some(queue) = &self.audio_queue {
+            // Fast forward makes audio faster than it can be played, drop samples instead of letting latency pile up.
+            let max_queued = apu::SAMPLE_RATE * 2 * std::mem::size_of::<f32>() as u32 / 10;
+
+            if queue.size() < max_queued {
+                queue.queue(&samples);
+            }
+
+            self.emulators[0].pacing().set_audio_queued(queue.size() / (2 * std::mem::size_of::<f32>() as u32));
+        }
+    }
+
+    fn handle_hotkey(&mut self, hotkey: Hotkey, shift: bool) {
+        match hotkey {
+            Hotkey::ReloadConfig => {
+                let config = Config::load(&self.config_path);
+
+                // A key held down may not be bound to the same button anymore, its release wouldn't get there.
+                self.release_all();
+                self.bindings = config.input_bindings();
+
+                for emulator in self.emulators.iter() {
+                    emulator.frame().set_display(config.display_settings());
+                }
+
+                // Shows the new colors right away, even while paused.
+                self.present_frame();
+
+                self.load_controller_mappings();
+                self.send_input(InputEvent::SetTurboRate(self.bindings.turbo_rate));
+                self.send_input(InputEvent::ConfigureRewind(self.bindings.rewind));
+                self.send_input(InputEvent::ConfigureSpeed(self.bindings.speed));
+            },
+            // Rewinding or loading a state on one side of a link would leave the other one behind.
+            Hotkey::Rewind | Hotkey::StateSlot(_) if self.emulators.len() > 1 => warn!("Input: Rewind and save states aren't available with linked Game Boys"),
+            Hotkey::Rewind => self.send_input(InputEvent::SetRewinding(true)),
+            Hotkey::FastForward => self.send_input(InputEvent::SetFastForward(true)),
+            Hotkey::ToggleSlowMotion => self.send_input(InputEvent::ToggleSlowMotion),
+            Hotkey::TogglePause => self.controls().for_each(EmulatorControl::toggle_pause),
+            Hotkey::FrameAdvance => self.send_input(InputEvent::FrameAdvance),
+            Hotkey::ToggleInputDisplay => self.show_input_display = !self.show_input_display,
+            Hotkey::ToggleMovieReadOnly => self.send_input(InputEvent::ToggleMovieReadOnly),
+            Hotkey::ToggleAudioRecording => self.toggle_audio_recording(),
+            Hotkey::ToggleVideoRecording => self.toggle_video_recording(),
+            Hotkey::PrintChannelStates => self.print_channel_states(),
+            Hotkey::Screenshot => self.save_screenshots(),
+            Hotkey::MuteChannel(channel) => self.toggle_channel(channel, shift),
+            Hotkey::Reset => self.controls().for_each(EmulatorControl::reset),
+            Hotkey::PowerCycle => self.controls().for_each(EmulatorControl::power_cycle),
+            Hotkey::StateSlot(slot) => self.send_input(if shift {InputEvent::SaveState(slot)} else {InputEvent::LoadState(slot)}),
+        }
+    }
+
+    fn load_controller_mappings(&self) {
+        if let (Some(subsystem), Some(path)) = (&self.controller_subsystem, &self.bindings.controller_mappings) {
+            match subsystem.load_mappings(path) {
+                Ok(count) => info!("Input: Loaded {} controller mappings from {:#?}", count, path),
+                Err(error) => error!("Input: Failed to load controller mappings from {:#?}, error {}", path, error),
+            }
+        }
+    }
+
+    fn open_controller(&mut self, joystick_index: u32) {
+        if let Some(subsystem) = &self.controller_subsystem {
+            match subsystem.open(joystick_index) {
+                Ok(controller) => {
+                    info!("Input: Connected controller {}", controller.name());
+                    self.controllers.insert(controller.instance_id(), controller);
+                },
+                Err(error) => warn!("Input: Failed to open controller {}, error {}", joystick_index, error),
+            }
+        }
+    }
+
+    fn close_controller(&mut self, instance_id: u32) {
+        if let Some(controller) = self.controllers.remove(&instance_id) {
+            info!("Input: Disconnected controller {}", controller.name());
+        }
+
+        // Buttons aren't tracked per controller, so drop everything held once the last one is gone.
+        if self.controllers.is_empty() {
+            self.release_source(InputSource::Controller);
+            self.release_source(InputSource::Stick);
+        }
+    }
+
+    fn handle_stick(&mut self, axis: Axis, value: i16) {
+        if !self.bindings.analog_stick {
+            return;
+        }
+
+        let deadzone = self.bindings.deadzone;
+        let (negative, positive) = match axis {
+            Axis::LeftX => (Button::Left, Button::Right),
+            Axis::LeftY => (Button::Up, Button::Down),
+            _ => return,
+        };
+
+        self.set_button(0, InputSource::Stick, negative, value < -deadzone);
+        self.set_button(0, InputSource::Stick, positive, value > deadzone);
+    }
+
+    fn handle_key(&mut self, keycode: Keycode, pressed: bool) {
+        // Keys of a player that isn't there do nothing.
+        if let Some((player, button)) = self.bindings.buttons.get(&keycode).copied().filter(|(player, _)| *player < self.emulators.len()) {
+            self.set_button(player, InputSource::Keyboard, button, pressed);
+        }
+        if let Some((player, button)) = self.bindings.turbo_buttons.get(&keycode).copied().filter(|(player, _)| *player < self.emulators.len()) {
+            self.set_turbo_button(player, InputSource::Keyboard, button, pressed);
+        }
+    }
+
+    fn handle_controller_button(&mut self, controller_button: controller::Button, pressed: bool) {
+        if let Some(button) = self.bindings.controller_buttons.get(&controller_button).copied() {
+            self.set_button(0, InputSource::Controller, button, pressed);
+        }
+        if let Some(button) = self.bindings.controller_turbo_buttons.get(&controller_button).copied() {
+            self.set_turbo_button(0, InputSource::Controller, button, pressed);
+        }
+    }
+
+    // Sets the button in the source's mask, and returns whether the combined state of the button changed.
+    fn update_held(held: &mut [u8; 3], source: InputSource, button: Button, pressed: bool) -> bool {
+        let was_held = held.iter().any(|mask| mask & button.mask() != 0);
+
+        if pressed {
+            held[source as usize] |= button.mask();
+        }
+        else {
+            held[source as usize] &= !button.mask();
+        }
+
+        was_held != held.iter().any(|mask| mask & button.mask() != 0)
+    }
+
+    fn set_button(&mut self, player: usize, source: InputSource, button: Button, pressed: bool) {
+        if Frontend::update_held(&mut self.held_buttons[player], source, button, pressed) {
+            self.emulators[player].control().send(if pressed { InputEvent::ButtonPressed(button) } else { InputEvent::ButtonReleased(button) });
+        }
+    }
+
+    fn set_turbo_button(&mut self, player: usize, source: InputSource, button: Button, pressed: bool) {
+        if Frontend::update_held(&mut self.held_turbo[player], source, button, pressed) {
+            self.emulators[player].control().send(if pressed { InputEvent::TurboPressed(button) } else { InputEvent::TurboReleased(button) });
+        }
+    }
+
+    // Controllers only drive the first player.
+    fn release_source(&mut self, source: InputSource) {
+        for button in Button::ALL.iter() {
+            self.set_button(0, source, *button, false);
+            self.set_turbo_button(0, source, *button, false);
+        }
+    }
+
+    fn release_all(&mut self) {
+        for player in 0..self.emulators.len() {
+            for source in [InputSource::Keyboard, InputSource::Controller, InputSource::Stick].iter() {
+                for button in Button::ALL.iter() {
+                    self.set_button(player, *source, *button, false);
+                    self.set_turbo_button(player, *source, *button, false);
+                }
+            }
+        }
+    }
+
+    fn controls(&self) -> impl Iterator<Item = &EmulatorControl> {
+        self.emulators.iter().map(|emulator| emulator.control())
+    }
+
+    // Everything that isn't a button goes to every emulator.
+    fn send_input(&self, event: InputEvent) {
+        self.controls().for_each(|control| control.send(event.clone()));
+    }
+
+    fn toggle_channel(&mut self, channel: usize, solo: bool) {
+        let name = apu::CHANNEL_NAMES[channel];
+
+        if solo {
+            match self.emulators[0].memory().toggle_channel_solo(channel) {
+                Some(_) => info!("Audio: {} soloed", name),
+                None => info!("Audio: {} unsoloed", name),
+            }
+        }
+        else if self.emulators[0].memory().toggle_channel_mute(channel) {
+            info!("Audio: {} muted", name);
+        }
+        else {
+            info!("Audio: {} unmuted", name);
+        }
+    }
+
+    fn print_channel_states(&self) {
+        for (name, state) in apu::CHANNEL_NAMES.iter().zip(self.emulators[0].memory().audio_channel_states().iter()) {
+            let duty = match state.duty {
+                Some(duty) => format!("{}%", [12.5, 25.0, 50.0, 75.0][duty as usize]),
+                None => String::from("-"),
+            };
+
+            info!("Audio: {:<8} | enabled: {:<5} | muted: {:<5} | {:>9.2} Hz | volume: {:>2} | duty: {}",
+                name, state.enabled, state.muted, state.frequency, state.volume, duty);
+        }
+    }
+
+    fn save_screenshots(&self) {
+        let linked = self.emulators.len() > 1;
+
+        for (player, emulator) in self.emulators.iter().enumerate() {
+            match emulator.save_screenshot(if linked {Some(player)} else {None}) {
+                Ok(path) => info!("Screenshot: Saved to {:#?}", path),
+                Err(error) => error!("Screenshot: Failed to save the screenshot, error {}", error),
+            }
+        }
+    }
+
+    fn toggle_audio_recording(&mut self) {
+        if self.emulators[0].memory().is_recording_audio() {
+            self.emulators[0].memory().stop_audio_recording();
+        }
+        else {
+            let path = match &self.record_audio_path {
+                Some(path) => path.clone(),
+                None => {
+                    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+                    PathBuf::from(format!("rusty_boi_{}.wav", timestamp))
+                }
+            };
+
+            self.emulators[0].memory().start_audio_recording(&path, self.record_sample_rate);
+        }
+    }
+
+    fn toggle_video_recording(&mut self) {
+        if self.emulators[0].memory().is_recording_video() {
+            self.emulators[0].memory().stop_video_recording();
+        }
+        else {
+            let path = match &self.record_video_path {
+                Some(path) => path.clone(),
+                None => {
+                    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+                    PathBuf::from(format!("rusty_boi_{}.avi", timestamp))
+                }
+            };
+
+            self.emulators[0].memory().start_video_recording(&path);
+        }
+    }
+
+    // Returns true once the window has been closed.
+    fn update_inputs(&mut self) -> bool {
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
+
+        for event in events {
+            match event {
+                Event::Quit{..} => {
+                    self.controls().for_each(EmulatorControl::quit);
+                    return true;
+                }
+                Event::KeyDown{keycode: Some(keycode), keymod, repeat: false, ..} => {
+                    if let Some(hotkey) = self.bindings.hotkeys.get(&keycode).copied() {
+                        self.handle_hotkey(hotkey, keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD));
+                    }
+                    else {
+                        self.handle_key(keycode, true);
+                    }
+                },
+                Event::KeyUp{keycode: Some(keycode), ..} => match self.bindings.hotkeys.get(&keycode) {
+                    Some(Hotkey::Rewind) => self.send_input(InputEvent::SetRewinding(false)),
+                    Some(Hotkey::FastForward) => self.send_input(InputEvent::SetFastForward(false)),
+                    _ => self.handle_key(keycode, false),
+                },
+                Event::Window{win_event: WindowEvent::Minimized, ..} if !self.emulators[0].pacing().is_paused() => {
+                    self.controls().for_each(EmulatorControl::pause);
+                    self.paused_while_minimized = true;
+                },
+                Event::Window{win_event: WindowEvent::Restored, ..} if self.paused_while_minimized => {
+                    self.controls().for_each(EmulatorControl::resume);
+                    self.paused_while_minimized = false;
+                },
+                Event::ControllerDeviceAdded{which, ..} => self.open_controller(which),
+                Event::ControllerDeviceRemoved{which, ..} => self.close_controller(which),
+                Event::ControllerButtonDown{button, ..} => self.handle_controller_button(button, true),
+                Event::ControllerButtonUp{button, ..} => self.handle_controller_button(button, false),
+                Event::ControllerAxisMotion{axis, value, ..} => self.handle_stick(axis, value),
+                _ => {}
+            }
+        }
+
+        false
+    }
+}
@@ -1,30 +1,42 @@
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::sync::mpsc::Sender;
-use std::sync::atomic::AtomicU16;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 
-use log::error;
-
-use sdl2;
-
-use sdl2::rect::Rect;
 use sdl2::rect::Point;
 
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-
-use sdl2::video::Window;
-use sdl2::video::WindowContext;
+use sdl2::surface::Surface;
 
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 
 use sdl2::render::Canvas;
-use sdl2::render::Texture;
-use sdl2::render::TextureCreator;
 
+use super::display;
+use super::display::DisplaySettings;
 use super::memory::Memory;
-use super::emulator::InputEvent;
+use super::screenshot;
+use super::savestate::{StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: u32 = 160;
+pub const SCREEN_HEIGHT: u32 = 144;
+
+// 154 lines of 456 cycles each.
+const FRAME_CYCLES: u32 = 70224;
+
+// Only the first 10 sprites in OAM that are on a line get drawn.
+const SPRITES_PER_LINE: usize = 10;
+
+// Background and window points are the color index in the low bits, then the CGB palette and BG priority.
+const POINT_COLOR: u8 = 3;
+const POINT_PRIORITY: u8 = 1 << 7;
+
+// DMG pixels are also kept as shades, with the palette they went through (BGP, OBP0 or OBP1) in the bits above.
+const SHADE_SOURCE_SHIFT: u8 = 2;
+
+const DMG_SHADES: [Color; 4] = [Color::RGB(255, 255, 255), Color::RGB(192, 192, 192), Color::RGB(96, 96, 96), Color::RGB(0, 0, 0)];
 
 #[derive(Clone, Copy, PartialEq)]
 enum InterruptType {
@@ -42,23 +54,154 @@ enum GpuMode {
 }
 
 
+// One OAM entry. Pixels are looked up when drawing, so tile and palette changes show up right away.
 struct SpriteData {
     pub x: u8,
     pub y: u8,
-    pub data: Texture,
+    pub tile: u8,
+    // VRAM bank of the tile, always 0 on a DMG.
+    pub bank: usize,
+    // OBP0/OBP1 on a DMG, one of the 8 sprite palettes on a CGB.
+    pub palette: usize,
     pub flip_x: bool,
     pub flip_y: bool,
+    // Only shows over BG color 0.
+    pub behind_background: bool,
 }
 
 impl SpriteData {
-    pub fn new(coords: (u8, u8), flip: (bool, bool), data: Texture) -> SpriteData {
+    pub fn new(bytes: &[u8], cgb_mode: bool) -> SpriteData {
+        let attributes = bytes[3];
+
         SpriteData {
-            x: coords.0,
-            y: coords.1,
-            data: data,
-            flip_x: flip.0,
-            flip_y: flip.1,
+            x: bytes[1],
+            y: bytes[0],
+            tile: bytes[2],
+            bank: if cgb_mode {((attributes >> 3) & 1) as usize} else {0},
+            palette: if cgb_mode {(attributes & 7) as usize} else {((attributes >> 4) & 1) as usize},
+            flip_x: ((attributes >> 5) & 1) == 1,
+            flip_y: ((attributes >> 6) & 1) == 1,
+            behind_background: ((attributes >> 7) & 1) == 1,
+        }
+    }
+}
+
+// RGBA32 pixels. Frames are the size of the screen, unless there's an SGB border around them.
+#[derive(Clone)]
+pub struct Frame {
+    pub pixels: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+// A frame as the GPU drew it, before the display settings color it.
+#[derive(Clone)]
+enum RawFrame {
+    // DMG shades, with the palette they went through in the upper bits.
+    Dmg(Vec<u8>),
+    // RGBA32 pixels with the colors from palette RAM, DMG compatibility included.
+    Cgb(Vec<u8>),
+    // The SGB colors frames itself, blank frames are always white.
+    Colored(Frame),
+}
+
+impl RawFrame {
+    // Plain DMG frames get the display palettes, and the ones with CGB colors the color correction.
+    fn colored(&self, display: &DisplaySettings) -> Frame {
+        match self {
+            RawFrame::Dmg(shades) => {
+                let pixels = shades.iter().flat_map(|shade| {
+                    display::dmg_rgba(&display.dmg_palettes[(shade >> SHADE_SOURCE_SHIFT) as usize], shade & 3)
+                }).collect();
+
+                Frame { pixels, width: SCREEN_WIDTH, height: SCREEN_HEIGHT }
+            },
+            RawFrame::Cgb(pixels) => {
+                let mut pixels = pixels.clone();
+
+                if display.color_correction {
+                    pixels.chunks_mut(4).for_each(display::correct_color);
+                }
+
+                Frame { pixels, width: SCREEN_WIDTH, height: SCREEN_HEIGHT }
+            },
+            RawFrame::Colored(frame) => frame.clone(),
+        }
+    }
+}
+
+// Last finished frame, shared with whoever presents it, and how the next ones are colored.
+pub struct FrameBuffer {
+    // Kept so the last frame can be colored again when the display settings change.
+    raw: Mutex<RawFrame>,
+    frame: Mutex<Frame>,
+    frame_count: AtomicU64,
+    display: Mutex<DisplaySettings>,
+    // Saved as soon as the frame with this number is published, so no frame is missed.
+    scheduled_screenshot: Mutex<Option<(u64, PathBuf)>>,
+}
+
+impl FrameBuffer {
+    pub fn new(display: DisplaySettings) -> FrameBuffer {
+        let frame = Frame { pixels: vec![255; (SCREEN_WIDTH * SCREEN_HEIGHT * 4) as usize], width: SCREEN_WIDTH, height: SCREEN_HEIGHT };
+
+        FrameBuffer {
+            raw: Mutex::new(RawFrame::Colored(frame.clone())),
+            frame: Mutex::new(frame),
+            frame_count: AtomicU64::new(0),
+            display: Mutex::new(display),
+            scheduled_screenshot: Mutex::new(None),
+        }
+    }
+
+    // Also recolors the last frame, which is all there is to see while paused.
+    pub fn set_display(&self, display: DisplaySettings) {
+        *self.display.lock().unwrap() = display;
+
+        let raw = self.raw.lock().unwrap();
+        *self.frame.lock().unwrap() = raw.colored(&display);
+    }
+
+    fn display(&self) -> DisplaySettings {
+        *self.display.lock().unwrap()
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count.load(Ordering::Relaxed)
+    }
+
+    pub fn frame(&self) -> Frame {
+        self.frame.lock().unwrap().clone()
+    }
+
+    pub fn save_screenshot(&self, path: &Path) -> io::Result<()> {
+        screenshot::save_png(&self.frame(), self.display().screenshot_scale, path)
+    }
+
+    pub fn schedule_screenshot(&self, frame: u64, path: PathBuf) {
+        *self.scheduled_screenshot.lock().unwrap() = Some((frame, path));
+    }
+
+    // Colors the frame with the current display settings, and returns it for video recording.
+    fn publish(&self, raw: RawFrame) -> Frame {
+        // Held while coloring, so a settings change in between recolors this frame too.
+        let mut stored = self.raw.lock().unwrap();
+        let frame = raw.colored(&self.display());
+        *stored = raw;
+
+        let frame_count = self.frame_count.load(Ordering::Relaxed) + 1;
+        let mut scheduled = self.scheduled_screenshot.lock().unwrap();
+
+        if let Some((_, path)) = scheduled.take_if(|(scheduled_frame, _)| *scheduled_frame == frame_count) {
+            match screenshot::save_png(&frame, self.display().screenshot_scale, &path) {
+                Ok(_) => log::info!("Screenshot: Saved frame {} to {:#?}", frame_count, path),
+                Err(error) => log::error!("Screenshot: Failed to save {:#?}, error {}", path, error),
+            }
         }
+
+        *self.frame.lock().unwrap() = frame.clone();
+        self.frame_count.store(frame_count, Ordering::Relaxed);
+        frame
     }
 }
 
@@ -68,12 +211,21 @@ pub struct Gpu {
     gpu_cycles: u16,
     line: u8,
 
+    // Frames keep being counted with the LCD off, so input is still sampled once per frame.
+    lcd_off_cycles: u32,
+    frame_finished: bool,
+
     lcd_enabled: bool,
 
     scroll_x: u8,
     scroll_y: u8,
 
+    cgb_mode: bool,
+    dmg_compat: bool,
+    sgb_mode: bool,
+
     background_tilemap: (u16, u16),
+    // In CGB mode LCDC bit 0 doesn't turn off the background, it lets sprites go over it instead.
     background_enabled: bool,
     
     window_tilemap: (u16, u16),
@@ -87,56 +239,61 @@ pub struct Gpu {
     sprites_enabled: bool,
 
     sprites: Vec<SpriteData>,
-    tile_bank0: Vec<Vec<u8>>,
-    tile_bank1: Vec<Vec<u8>>,
+    sprites_by_x: bool,
+    // The 384 tiles of each VRAM bank, one color index per pixel.
+    tiles: Vec<Vec<Vec<u8>>>,
     background_points: Vec<u8>,
     window_points: Vec<u8>,
 
+    // BGP, OBP0 and OBP1.
+    dmg_palettes: [u8; 3],
     tile_palette: Vec<Color>,
     sprites_palettes: Vec<Vec<Color>>,
+    // CGB palettes, 4 colors for each of the 8.
+    cgb_background_colors: Vec<Color>,
+    cgb_sprite_colors: Vec<Color>,
 
     oam_hash: u64,
     sprites_dirty: bool,
 
+    // The frame being drawn as DMG shades, for the SGB or the display palettes to color.
+    shades: Vec<u8>,
+
     tiles_dirty_flags: u8,
     background_dirty_flags: u8,
 
-    frames: u16,
-    total_cycles: Arc<AtomicU16>,
-
     memory: Arc<Memory>,
+    frame: Arc<FrameBuffer>,
 
-    event_pump: sdl2::EventPump,
-    input_tx: Sender<InputEvent>,
-
-    game_canvas: Canvas<Window>,
-    texture_creator: TextureCreator<WindowContext>,
+    game_canvas: Canvas<Surface<'static>>,
 }
 
 impl Gpu {
-    pub fn new(cycles: Arc<AtomicU16>, mem: Arc<Memory>, tx: Sender<InputEvent>) -> Gpu {
+    pub fn new(mem: Arc<Memory>, frame: Arc<FrameBuffer>) -> Gpu {
 
-        let sdl_ctx = sdl2::init().unwrap();
-        let sdl_video = sdl_ctx.video().unwrap();
+        // Frames are rendered off-screen, presenting them is up to the frontend (if there's one).
+        let game_surface = Surface::new(SCREEN_WIDTH, SCREEN_HEIGHT, PixelFormatEnum::RGBA32).unwrap();
+        let mut game_canvas = game_surface.into_canvas().unwrap();
 
-        let game_window = sdl_video.window("Rusty Boi - Game - FPS: 0", 160 * 4, 144 * 4).position_centered().build().unwrap();
-        let mut game_canvas = game_window.into_canvas().present_vsync().build().unwrap();
-        let texture_creator = game_canvas.texture_creator();
-
-        game_canvas.set_scale(4.0, 4.0).unwrap();
         game_canvas.set_draw_color(Color::RGB(255, 255, 255));
         game_canvas.clear();
-        game_canvas.present();
-        
+
         Gpu {
             gpu_mode: 0,
             gpu_cycles: 0,
             line: 0,
 
+            lcd_off_cycles: 0,
+            frame_finished: false,
+
             lcd_enabled: false,
             scroll_x: 0,
             scroll_y: 0,
 
+            cgb_mode: false,
+            dmg_compat: false,
+            sgb_mode: false,
+
             background_tilemap: (0x9800, 0x9BFF),
             background_enabled: false,
 
@@ -151,70 +308,151 @@ impl Gpu {
             sprites_enabled: false,
 
             sprites: Vec::new(),
-            tile_bank0: vec![vec![0; 64]; 256],
-            tile_bank1: vec![vec![0; 64]; 256],
+            sprites_by_x: true,
+            tiles: vec![vec![vec![0; 64]; 384]; 2],
             background_points: vec![0; 65536],
             window_points: vec![0; 65536],
 
-            tile_palette: vec![Color::RGBA(255, 255, 255, 0), Color::RGBA(192, 192, 192, 255), Color::RGBA(96, 96, 96, 255), 
-            Color::RGBA(0, 0, 0, 255)],
-            sprites_palettes: vec![vec![Color::RGBA(255, 255, 255, 0), Color::RGBA(192, 192, 192, 255), Color::RGBA(96, 96, 96, 255), 
-            Color::RGBA(0, 0, 0, 255)]; 2],
+            dmg_palettes: [0; 3],
+            tile_palette: DMG_SHADES.to_vec(),
+            sprites_palettes: vec![DMG_SHADES.to_vec(); 2],
+            cgb_background_colors: vec![Color::RGB(255, 255, 255); 32],
+            cgb_sprite_colors: vec![Color::RGB(255, 255, 255); 32],
 
             oam_hash: 0,
             sprites_dirty: false,
 
+            shades: vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize],
+
             tiles_dirty_flags: 0,
             background_dirty_flags: 0,
 
-            frames: 0,
-            total_cycles: cycles,
-
             memory: mem,
-            
-            event_pump: sdl_ctx.event_pump().unwrap(),
-            input_tx: tx,
+            frame,
 
             game_canvas: game_canvas,
-            texture_creator: texture_creator,
         }
     }
 
-    pub fn execution_loop(&mut self) {
-        let mut fps_timer = std::time::Instant::now();
-        
-        loop {
-            self.update_inputs();
-            self.update_gpu_values();
+    pub fn frame_buffer(&self) -> Arc<FrameBuffer> {
+        Arc::clone(&self.frame)
+    }
 
-            if self.lcd_enabled {
-                if self.gpu_mode == 0 && self.gpu_cycles >= 204 {
-                    self.hblank_mode();
-                }
-                else if self.gpu_mode == 1 && self.gpu_cycles >= 456 {
-                    self.vblank_mode();
-                }
-                else if self.gpu_mode == 2 && self.gpu_cycles >= 80 {
-                    self.oam_scan_mode();
-                }
-                else if self.gpu_mode == 3 && self.gpu_cycles >= 172 {
-                    self.lcd_transfer_mode();
-                }
+    // Tiles, sprites and palettes are rebuilt from memory, only the timing state is stored.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.gpu_mode);
+        writer.u16(self.gpu_cycles);
+        writer.u8(self.line);
+        writer.u32(self.lcd_off_cycles);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.gpu_mode = reader.u8()? & 3;
+        self.gpu_cycles = reader.u16()?;
+        self.line = reader.u8()?;
+        self.lcd_off_cycles = reader.u32()?;
+
+        // LY only goes up to 153, and lines past 143 are always in VBlank.
+        if self.line > 153 || (self.line >= SCREEN_HEIGHT as u8 && self.gpu_mode != 1) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("LY {} in GPU mode {}", self.line, self.gpu_mode)));
+        }
 
-                let lyc_value = self.memory.read(0xFF45);
+        self.sprites_dirty = true;
+        self.update_gpu_values();
+        Ok(())
+    }
 
-                if lyc_value == self.memory.read(0xFF44) {
-                    let stat_value = self.memory.read(0xFF41);
-                    self.memory.write(0xFF41, stat_value | 2, false);
-                    self.request_interrupt(InterruptType::Lyc);
+    // Advances the GPU by the cycles the CPU just spent, returns true if a frame was finished.
+    pub fn gpu_cycle(&mut self, cycles: u16) -> bool {
+        self.frame_finished = false;
+        self.lcd_enabled = ((self.memory.read(0xFF40) >> 7) & 1) == 1;
+
+        if self.lcd_enabled {
+            self.gpu_cycles += cycles;
+
+            let mode_cycles = match self.gpu_mode {
+                0 => 204,
+                1 => 456,
+                2 => 80,
+                _ => 172,
+            };
+
+            if self.gpu_cycles >= mode_cycles {
+                self.update_gpu_values();
+                self.gpu_cycles -= mode_cycles;
+
+                match self.gpu_mode {
+                    0 => self.hblank_mode(),
+                    1 => self.vblank_mode(),
+                    2 => self.oam_scan_mode(),
+                    _ => self.lcd_transfer_mode(),
                 }
             }
 
-            if fps_timer.elapsed() >= std::time::Duration::from_millis(1000) && self.frames > 0 {
-                let framerate = format!("Rusty Boi - Game - FPS: {:#?}", self.frames as u64 / fps_timer.elapsed().as_secs());
-                self.game_canvas.window_mut().set_title(&framerate).unwrap();
-                fps_timer = std::time::Instant::now();
-                self.frames = 0;
+            let lyc_value = self.memory.read(0xFF45);
+
+            if lyc_value == self.memory.read(0xFF44) {
+                let stat_value = self.memory.read(0xFF41);
+                self.memory.write(0xFF41, stat_value | 2, false);
+                self.request_interrupt(InterruptType::Lyc);
+            }
+        }
+        else {
+            self.lcd_off_cycles += cycles as u32;
+
+            if self.lcd_off_cycles >= FRAME_CYCLES {
+                self.lcd_off_cycles -= FRAME_CYCLES;
+                self.frame_finished = true;
+
+                // The screen goes blank, and frames keep being counted so --frames and videos keep
+                // the emulated frame rate.
+                let blank = self.blank_frame();
+                let frame = self.frame.publish(blank);
+                self.memory.record_video_frame(&frame);
+            }
+        }
+
+        self.frame_finished
+    }
+
+    fn present_frame(&mut self) {
+        self.game_canvas.present();
+
+        if let Some(raw) = self.finished_frame() {
+            let frame = self.frame.publish(raw);
+            self.memory.record_video_frame(&frame);
+        }
+    }
+
+    // White, or color 0 inside the border on an SGB, the same size as the frames drawn in this mode.
+    fn blank_frame(&mut self) -> RawFrame {
+        let pixel_count = (SCREEN_WIDTH * SCREEN_HEIGHT) as usize;
+
+        // Asks the memory, the mode here is only picked up once the LCD runs.
+        if self.memory.is_sgb() {
+            let (pixels, width, height) = self.memory.sgb_frame(&vec![0; pixel_count]);
+            return RawFrame::Colored(Frame { pixels, width, height });
+        }
+
+        RawFrame::Colored(Frame { pixels: vec![255; pixel_count * 4], width: SCREEN_WIDTH, height: SCREEN_HEIGHT })
+    }
+
+    // The SGB colors DMG frames itself, the others are colored once they're published.
+    fn finished_frame(&mut self) -> Option<RawFrame> {
+        if self.sgb_mode {
+            let (pixels, width, height) = self.memory.sgb_frame(&self.shades);
+            return Some(RawFrame::Colored(Frame { pixels, width, height }));
+        }
+
+        if !self.cgb_mode && !self.dmg_compat {
+            return Some(RawFrame::Dmg(self.shades.clone()));
+        }
+
+        match self.game_canvas.read_pixels(None, PixelFormatEnum::RGBA32) {
+            Ok(pixels) => Some(RawFrame::Cgb(pixels)),
+            Err(error) => {
+                log::error!("GPU: Failed to read back the finished frame, error {}", error);
+                None
             }
         }
     }
@@ -222,19 +460,19 @@ impl Gpu {
     fn hblank_mode(&mut self) {
 
         self.set_gpu_mode(GpuMode::Hblank);
+        self.draw_line();
 
-        if self.background_enabled {self.draw_background()}
-        if self.sprites_enabled {self.draw_sprites()}
-        if self.window_enabled {self.draw_window()}
-
-        self.gpu_cycles = 0;
         self.line += 1;
         self.memory.write(0xFF44, self.line, false);
 
         if self.line == 144 {
             self.gpu_mode = 1;
-            self.frames += 1;
-            self.game_canvas.present();
+            self.present_frame();
+            self.frame_finished = true;
+        }
+        else {
+            // Every visible line goes through OAM scan, transfer and HBlank, 456 cycles in total.
+            self.gpu_mode = 2;
         }
 
         self.request_interrupt(InterruptType::Hblank);
@@ -243,7 +481,6 @@ impl Gpu {
     fn vblank_mode(&mut self) {
 
         self.set_gpu_mode(GpuMode::Vblank);
-        self.gpu_cycles = 0;
         self.line += 1;
         self.memory.write(0xFF44, self.line, false);
 
@@ -260,7 +497,6 @@ impl Gpu {
     fn oam_scan_mode(&mut self) {
 
         self.set_gpu_mode(GpuMode::Oam);
-        self.gpu_cycles = 0;
         self.gpu_mode = 3;
 
         if self.sprites_dirty {
@@ -274,12 +510,15 @@ impl Gpu {
     fn lcd_transfer_mode(&mut self) {
 
         self.set_gpu_mode(GpuMode::Lcd);
-        self.gpu_cycles = 0;
         self.gpu_mode = 0;
 
         if self.tiles_dirty_flags > 0 {
             self.make_tiles(0);
-            self.make_tiles(1);
+
+            if self.cgb_mode {
+                self.make_tiles(1);
+            }
+
             self.tiles_dirty_flags = 0;
             self.memory.tiles_dirty_flags.store(0, Ordering::Relaxed);
         }
@@ -289,81 +528,146 @@ impl Gpu {
             self.background_dirty_flags = 0;
             self.memory.background_dirty_flags.store(0, Ordering::Relaxed);
         }
+
+        // The caches were rebuilt above, so like on hardware the copied block only shows from the next line on.
+        self.memory.hblank_dma();
     }
 
-    fn draw_background(&mut self) {
-        let mut point_idx: u16 = 0;
+    // Composes the current line out of the background, the window and the sprites.
+    fn draw_line(&mut self) {
+        let line = self.line;
 
-        // Index offset for the points array in case the current line is not 0.
-        point_idx += 256 * self.line as u16;
+        if line >= SCREEN_HEIGHT as u8 {
+            return;
+        }
 
-        // Draw a whole line from the background map.
-        for point in 0..256 {
-            let target_x = (point as u8).overflowing_sub(self.scroll_x).0;
-            let target_y = self.line.overflowing_sub(self.scroll_y).0;
-            let color = self.tile_palette[self.background_points[point_idx as usize] as usize];
-            let final_point = Point::new(target_x as i32, target_y as i32);
+        // Without the background on a DMG, the window goes too and sprites only have white under them.
+        let background_visible = self.background_enabled || self.cgb_mode;
+        let mut points = [0; SCREEN_WIDTH as usize];
 
-            self.game_canvas.set_draw_color(color);
-            self.game_canvas.draw_point(final_point).unwrap();
-            point_idx += 1;
+        if background_visible {
+            let map_y = line.wrapping_add(self.scroll_y) as usize;
+
+            for (x, point) in points.iter_mut().enumerate() {
+                let map_x = (x as u8).wrapping_add(self.scroll_x) as usize;
+                *point = self.background_points[map_y * 256 + map_x];
+            }
+
+            if self.window_enabled && line >= self.window_y && self.window_x < 167 {
+                let window_line = (line - self.window_y) as usize;
+
+                let start = (self.window_x.max(7) - 7) as usize;
+
+                for (x, point) in points.iter_mut().enumerate().skip(start) {
+                    *point = self.window_points[window_line * 256 + x + 7 - self.window_x as usize];
+                }
+            }
         }
-    }
 
-    fn draw_window(&mut self) {
-        if self.window_x < 166 && self.window_y < 143 {
-            let mut point_idx: u16 = 0;
-    
-            // Index offset for the points array in case the current line is not 0.
-            point_idx += 256 * self.line as u16;
-
-            // Draw a whole line from the window.
-            for point in 0..255 as u8 {
-                let target_x = point.wrapping_add(self.window_x.wrapping_sub(7));
-                let target_y = self.line.wrapping_add(self.window_y);
-                let color = self.tile_palette[self.window_points[point_idx as usize] as usize];
-                let final_point = Point::new(target_x as i32, target_y as i32);
-    
-                self.game_canvas.set_draw_color(color);
-                self.game_canvas.draw_point(final_point).unwrap();
-                point_idx += 1;
+        // Only kept outside of CGB mode. Background pixels go through BGP, which is source 0.
+        let mut shades: Vec<u8> = points.iter().map(|point| {
+            if background_visible {Gpu::shade(self.dmg_palettes[0], point & POINT_COLOR)} else {0}
+        }).collect();
+
+        let mut colors: Vec<Color> = points.iter().map(|point| {
+            if !background_visible {
+                Color::RGB(255, 255, 255)
+            }
+            else if self.cgb_mode {
+                self.cgb_background_colors[(point >> 2 & 7) as usize * 4 + (point & POINT_COLOR) as usize]
             }
+            else {
+                self.tile_palette[(point & POINT_COLOR) as usize]
+            }
+        }).collect();
+
+        if self.sprites_enabled {
+            self.draw_sprites(&points, &mut colors, &mut shades);
         }
-    }
 
-    fn draw_sprites(&mut self) {
-        for sprite in self.sprites.iter() {
-            let target_x = sprite.x.wrapping_sub(8) as i32;
-            let target_y = sprite.y.wrapping_sub(16) as i32;
-            let y_size = if self.big_sprites {16} else {8};
-            self.game_canvas.copy_ex(&sprite.data, None, Rect::new(target_x, target_y, 8, y_size), 0.0, None, sprite.flip_x, sprite.flip_y).unwrap();
+        if !self.cgb_mode {
+            let start = line as usize * SCREEN_WIDTH as usize;
+            self.shades[start..start + SCREEN_WIDTH as usize].copy_from_slice(&shades);
+        }
+
+        for (x, color) in colors.into_iter().enumerate() {
+            self.game_canvas.set_draw_color(color);
+            self.game_canvas.draw_point(Point::new(x as i32, line as i32)).unwrap();
         }
     }
 
-    fn make_tiles(&mut self, target_bank: u8) {
-        let start_position = if target_bank == 0 {0x8000} else {0x8800};
-        let end_position = if target_bank == 0 {0x8FFF} else {0x97FF};
-        let mut memory_position = start_position;
-        let mut tiles_position = 0;
+    // The sprite with the highest priority decides each pixel, even if the background ends up covering it.
+    // That's the first one in OAM on a CGB, and the leftmost one on a DMG.
+    fn draw_sprites(&self, points: &[u8], colors: &mut [Color], shades: &mut [u8]) {
+        let height = if self.big_sprites {16} else {8};
+        let line = self.line as i32;
 
-        while memory_position < end_position {
-            let mut loaded_bytes = 0;
-            let mut tile_bytes: Vec<u8> = vec![0; 16];
+        let mut visible: Vec<&SpriteData> = self.sprites.iter()
+            .filter(|sprite| line + 16 >= sprite.y as i32 && line + 16 < sprite.y as i32 + height)
+            .take(SPRITES_PER_LINE)
+            .collect();
 
-            while loaded_bytes < 16 {
-                tile_bytes[loaded_bytes] = self.memory.read(memory_position);
-                memory_position += 1;
-                loaded_bytes += 1;
-            }
+        if self.sprites_by_x {
+            // Stable, so sprites at the same X stay in OAM order.
+            visible.sort_by_key(|sprite| sprite.x);
+        }
+
+        let mut drawn = [false; SCREEN_WIDTH as usize];
 
-            if target_bank == 0 {
-                self.tile_bank0[tiles_position as usize] = self.make_tile(&tile_bytes);
+        for sprite in visible {
+            let mut row = line + 16 - sprite.y as i32;
+
+            if sprite.flip_y {
+                row = height - 1 - row;
             }
-            else {
-                self.tile_bank1[tiles_position as usize] = self.make_tile(&tile_bytes);
+
+            // Big sprites ignore the lowest bit of the tile number, the bottom half is the next tile.
+            let tile = if height == 16 {(sprite.tile & 0xFE) as usize + row as usize / 8} else {sprite.tile as usize};
+            let tile_data = &self.tiles[sprite.bank][tile];
+
+            for column in 0..8 {
+                let x = sprite.x as i32 - 8 + column;
+
+                if x < 0 || x >= SCREEN_WIDTH as i32 || drawn[x as usize] {
+                    continue;
+                }
+
+                let tile_column = if sprite.flip_x {7 - column} else {column};
+                let color = tile_data[(row as usize % 8) * 8 + tile_column as usize];
+
+                if color == 0 {
+                    continue;
+                }
+
+                drawn[x as usize] = true;
+
+                let point = points[x as usize];
+                let background_wins = point & POINT_COLOR != 0 && if self.cgb_mode {
+                    self.background_enabled && (sprite.behind_background || point & POINT_PRIORITY != 0)
+                }
+                else {
+                    sprite.behind_background
+                };
+
+                if !background_wins {
+                    if self.cgb_mode {
+                        colors[x as usize] = self.cgb_sprite_colors[sprite.palette * 4 + color as usize];
+                    }
+                    else {
+                        colors[x as usize] = self.sprites_palettes[sprite.palette][color as usize];
+                        shades[x as usize] = Gpu::shade(self.dmg_palettes[1 + sprite.palette], color) | ((1 + sprite.palette as u8) << SHADE_SOURCE_SHIFT);
+                    }
+                }
             }
+        }
+    }
+
+    fn make_tiles(&mut self, bank: usize) {
+        for tile in 0..384 {
+            let address = 0x8000 + tile as u16 * 16;
+            let bytes: Vec<u8> = (0..16).map(|offset| self.memory.read_vram(bank, address + offset)).collect();
 
-            tiles_position += 1;
+            self.tiles[bank][tile] = self.make_tile(&bytes);
         }
     }
 
@@ -389,261 +693,58 @@ impl Gpu {
     }
 
     fn make_sprites(&mut self) {
-        let mut current_address = 0xFE00;
-        let mut generated_sprites: usize = 0;
-        let mut sprites_idx = 0;
-        let mut sprites: Vec<SpriteData> = Vec::new();
-    
-        while generated_sprites < 40 {
-    
-            let mut sprite_bytes: Vec<u8> = vec![0; 4];
-            let mut loaded_bytes: usize = 0;
-    
-            while loaded_bytes < 4 {
-                sprite_bytes[loaded_bytes] = self.memory.read(current_address);
-                current_address += 1;
-                loaded_bytes += 1;
-            }
-    
-            // Ignore the sprite if it's outside of the screen.
-            if sprite_bytes[0] > 8 && sprite_bytes[1] > 0 {
-                let new_tile = self.make_sprite(&sprite_bytes);
-                sprites.insert(sprites_idx, new_tile);
-                sprites_idx += 1;
-            }
-    
-            generated_sprites += 1;
-        }
-    
-        self.sprites = sprites;
-    }
-
-    fn make_sprite(&mut self, bytes: &Vec<u8>) -> SpriteData {
-        let position_x = bytes[1];
-        let position_y = bytes[0];
-        let tile_id = bytes[2];
-        let _priority = ((bytes[3] >> 7) & 1) == 1;
-        let flip_y = ((bytes[3] >> 6) & 1) == 1;
-        let flip_x = ((bytes[3] >> 5) & 1) == 1;
-        let palette_id = if ((bytes[3] >> 4) & 1) == 1 {1} else {0};
-        let y_size = if self.big_sprites {16} else {8};
-    
-        let mut new_sprite: Texture = self.texture_creator.create_texture_streaming(PixelFormatEnum::RGBA32, 8, y_size).unwrap();
-        new_sprite.set_blend_mode(sdl2::render::BlendMode::Blend);
-    
-        if y_size == 16 {
-    
-            let mut tile = tile_id & 0xFE;
-            let mut color_idx: usize = 0;
-            let mut tile_data = &self.tile_bank0[tile as usize];
-            let mut sprite_colors: Vec<Color> = vec![Color::RGB(255, 255, 255); 128];
-    
-            for color in tile_data.iter() {
-    
-                // Get the color from the palette used by the sprite.
-                let sprite_color = self.sprites_palettes[palette_id][*color as usize];
-                sprite_colors[color_idx] = sprite_color;
-                color_idx += 1;
-            }
-    
-            tile = tile_id | 0x01;
-            tile_data = &self.tile_bank0[tile as usize];
-    
-            for color in tile_data.iter() {
-    
-                // Get the color from the palette used by the sprite.
-                let sprite_color = self.sprites_palettes[palette_id][*color as usize];
-                sprite_colors[color_idx] = sprite_color;
-                color_idx += 1;
-            }
-    
-            color_idx = 0;
-    
-            new_sprite.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-                for y in 0..16 {
-                    for x in 0..8 {
-                        let offset = y*pitch + x*4;
-                        // Set each color channel for the sprite texture from the palette.
-                        buffer[offset] = sprite_colors[color_idx].r;
-                        buffer[offset + 1] = sprite_colors[color_idx].g;
-                        buffer[offset + 2] = sprite_colors[color_idx].b;
-                        buffer[offset + 3] = sprite_colors[color_idx].a;
-                        color_idx += 1;
-                    }
-                }
-            }).unwrap();
-        }
-        else {
-            
-            let mut color_idx: usize = 0;
-            let tile_data = &self.tile_bank0[tile_id as usize];
-            let mut sprite_colors: Vec<Color> = vec![Color::RGB(255, 255, 255); 64];
-    
-            for color in tile_data.iter() {
-    
-                // Get the color from the palette used by the sprite.
-                let sprite_color = self.sprites_palettes[palette_id][*color as usize];
-                sprite_colors[color_idx] = sprite_color;
-                color_idx += 1;
-            }
-    
-            color_idx = 0;
-    
-            new_sprite.with_lock(None, |buffer: &mut [u8], pitch: usize| {
-                for y in 0..8 {
-                    for x in 0..8 {
-                        let offset = y*pitch + x*4;
-                        // Set each color channel for the sprite texture from the palette.
-                        buffer[offset] = sprite_colors[color_idx].r;
-                        buffer[offset + 1] = sprite_colors[color_idx].g;
-                        buffer[offset + 2] = sprite_colors[color_idx].b;
-                        buffer[offset + 3] = sprite_colors[color_idx].a;
-                        color_idx += 1;
-                    }
-                }
-            }).unwrap();
-        }
-    
-        SpriteData::new((position_x, position_y), (flip_x, flip_y), new_sprite)
-    
+        let oam: Vec<u8> = (0xFE00..0xFEA0).map(|address| self.memory.read(address)).collect();
+        self.sprites = oam.chunks(4).map(|bytes| SpriteData::new(bytes, self.cgb_mode)).collect();
     }
 
     fn make_window(&mut self) {
-        let mut generated_lines: u16 = 0;
-        let mut current_address = self.window_tilemap.0;
-    
-        let lcdc_value =  ((self.memory.read(0xFF40) >> 4) & 1) == 1;
-        let tile_bank = if lcdc_value {&self.tile_bank0} else {&self.tile_bank1};
-    
-        let mut window_index: usize = 0;
-            
-        while generated_lines < 256 {
-    
-            let mut tiles: Vec<&Vec<u8>> = Vec::new();
-            let mut tile_idx: usize = 0;
-    
-            // Loads tile indexes from memory, then gets the tile from GPU State and saves it to tiles.
-            // 32 tiles is the maximum amount of tiles per line.
-            while tile_idx < 32 {
-    
-                let tile_id = self.memory.read(current_address);
-                if lcdc_value {
-                    let target_tile = tile_id;
-                    tiles.insert(tile_idx, &tile_bank[target_tile as usize]);
-                    tile_idx += 1;
-                    current_address += 1;
-                }
-                else {
-                    let target_tile = (tile_id as i8 as i16 + 128) as u16;
-                    tiles.insert(tile_idx, &tile_bank[target_tile as usize]);
-                    tile_idx += 1;
-                    current_address += 1;
-                }
-            }
-    
-            let mut tile_line = 0;
-    
-            while tile_line < 8 {
-    
-                let line = Gpu::make_background_line(&tiles, tile_line);
-                for point in line.into_iter() {
-                    self.window_points[window_index] = point;
-                    window_index += 1;
-                }
-                tile_line += 1;
-                generated_lines += 1;
-            }
-        }
-    
+        self.window_points = self.make_map_points(self.window_tilemap.0);
     }
 
     fn make_background(&mut self) {
-        let mut generated_lines: u16 = 0;
-        
-        let mut current_background = if ((self.memory.read(0xFF40) >> 3) & 1) == 1 {0x9C00} else {0x9800};
-    
-        let lcdc_value =  ((self.memory.read(0xFF40) >> 4) & 1) == 1;
-        let tile_bank = if lcdc_value {&self.tile_bank0} else {&self.tile_bank1};
-    
-        let mut background_idx: usize = 0;
-            
-        while generated_lines < 256 {
-    
-            let mut tiles: Vec<&Vec<u8>> = Vec::new();
-            let mut tile_idx: usize = 0;
-    
-            // Loads tile indexes from memory, then gets the tile from GPU State and saves it to tiles.
-            // 32 tiles is the maximum amount of tiles per line in the background.
-            while tile_idx < 32 {
-    
-                let bg_value = self.memory.read(current_background);
-                if lcdc_value {
-                    let target_tile = bg_value;
-                    tiles.insert(tile_idx, &tile_bank[target_tile as usize]);
-                    tile_idx += 1;
-                    current_background += 1;
-                }
-                else {
-                    let target_tile = (bg_value as i8 as i16 + 128) as u16;
-                    tiles.insert(tile_idx, &tile_bank[target_tile as usize]);
-                    tile_idx += 1;
-                    current_background += 1;
-                }
-            }
-    
-            let mut tile_line = 0;
-    
-            while tile_line < 8 {
-    
-                let line = Gpu::make_background_line(&tiles, tile_line);
-                for point in line.into_iter() {
-                    self.background_points[background_idx] = point;
-                    background_idx += 1;
-                }
-                tile_line += 1;
-                generated_lines += 1;
-            }
-        }
-    
+        self.background_points = self.make_map_points(self.background_tilemap.0);
     }
 
-    fn make_background_line(tiles: &Vec<&Vec<u8>>, tile_line: u8) -> Vec<u8> {
-        let start_idx = vec![0, 8, 16, 24, 32, 40, 48, 56];
-        let mut generated_points = 0;
-        let mut processed_tiles = 0;
-        let mut final_line: Vec<u8> = vec![0; 256];
-    
-        while generated_points < 256 {
-    
-            while processed_tiles < 32 {
-    
-                let end_index = start_idx[tile_line as usize] + 8;
-                let mut color_index = start_idx[tile_line as usize];
-                let current_tile = tiles[processed_tiles as usize];
-    
-                while color_index < end_index {
-    
-                    final_line[generated_points] = current_tile[color_index];
-    
-                    color_index += 1;
-                    generated_points += 1;
+    // Renders a whole 32x32 tile map into 256x256 points. On a CGB every tile also has attributes
+    // in VRAM bank 1: the bank its data is in, the palette, flips and priority over sprites.
+    fn make_map_points(&self, map: u16) -> Vec<u8> {
+        let unsigned_tiles = self.tiles_area.0 == 0x8000;
+        let mut points = vec![0; 65536];
+
+        for map_y in 0..32 {
+            for map_x in 0..32 {
+                let address = map + map_y * 32 + map_x;
+                let tile_id = self.memory.read_vram(0, address);
+                let attributes = if self.cgb_mode {self.memory.read_vram(1, address)} else {0};
+
+                // 0x8000 addressing goes from tile 0 to 255, 0x8800 from -128 to 127 around 0x9000.
+                let tile = if unsigned_tiles {tile_id as usize} else {(256 + tile_id as i8 as i16) as usize};
+                let tile_data = &self.tiles[((attributes >> 3) & 1) as usize][tile];
+                let flip_x = ((attributes >> 5) & 1) == 1;
+                let flip_y = ((attributes >> 6) & 1) == 1;
+                let extra = (attributes & 7) << 2 | (attributes & POINT_PRIORITY);
+
+                for row in 0..8 {
+                    for column in 0..8 {
+                        let tile_row = if flip_y {7 - row} else {row};
+                        let tile_column = if flip_x {7 - column} else {column};
+                        let index = (map_y as usize * 8 + row) * 256 + map_x as usize * 8 + column;
+
+                        points[index] = tile_data[tile_row * 8 + tile_column] | extra;
+                    }
                 }
-                processed_tiles += 1;
             }
-        }  
-    
-        final_line
-    
+        }
+
+        points
     }
 
-    fn make_palette(&mut self, palette: u8) -> Vec<Color> {
-        let base = vec![
-            Color::RGBA(255, 255, 255, 0),
-            Color::RGBA(192, 192, 192, 255),
-            Color::RGBA(96, 96, 96, 255),
-            Color::RGBA(0, 0, 0, 255),
-        ];
+    fn shade(palette: u8, color: u8) -> u8 {
+        (palette >> (color * 2)) & 3
+    }
 
+    // Each color index picks one of 4 shades, the DMG grays or a CGB palette in DMG compatibility.
+    fn make_palette(palette: u8, base: &[Color]) -> Vec<Color> {
         let color_0 = base[(palette & 3) as usize];
         let color_1 = base[((palette >> 2) & 3) as usize];
         let color_2 = base[((palette >> 4) & 3) as usize];
@@ -652,12 +753,25 @@ impl Gpu {
         vec![color_0, color_1, color_2, color_3]
     }
 
+    // RGB555 to RGB888, the top bits are repeated so white stays white.
+    fn make_cgb_colors(colors: &[u16]) -> Vec<Color> {
+        colors.iter().map(|color| {
+            let channel = |shift: u16| {
+                let value = ((color >> shift) & 0x1F) as u8;
+                (value << 3) | (value >> 2)
+            };
+
+            Color::RGB(channel(0), channel(5), channel(10))
+        }).collect()
+    }
+
     fn update_gpu_values(&mut self) {
         let lcdc_value = self.memory.read(0xFF40);
+        let maps = (self.background_tilemap, self.window_tilemap, self.tiles_area);
 
         self.lcd_enabled = ((lcdc_value >> 7) & 1) == 1;
 
-        self.window_tilemap = if ((lcdc_value >> 6) & 1) == 1 {(0x9C00, 0x9FFF)} else {(0x8800, 0x97FF)};
+        self.window_tilemap = if ((lcdc_value >> 6) & 1) == 1 {(0x9C00, 0x9FFF)} else {(0x9800, 0x9BFF)};
         self.window_enabled = ((lcdc_value >> 5) & 1) == 1;
         
         self.tiles_area = if ((lcdc_value >> 4) & 1) == 1 {(0x8000, 0x8FFF)} else {(0x8800, 0x97FF)};
@@ -676,11 +790,39 @@ impl Gpu {
 
         self.tiles_dirty_flags = self.memory.tiles_dirty_flags.load(Ordering::Relaxed);
         self.background_dirty_flags = self.memory.background_dirty_flags.load(Ordering::Relaxed);
-        self.gpu_cycles = self.total_cycles.load(Ordering::Relaxed);
 
-        self.tile_palette = self.make_palette(self.memory.read(0xFF47));
-        self.sprites_palettes[0] = self.make_palette(self.memory.read(0xFF48));
-        self.sprites_palettes[1] = self.make_palette(self.memory.read(0xFF49));
+        // The cached maps depend on which map and tile area LCDC points to.
+        if maps != (self.background_tilemap, self.window_tilemap, self.tiles_area) {
+            self.background_dirty_flags = 1;
+        }
+
+        let cgb_mode = self.memory.is_cgb();
+        self.dmg_compat = self.memory.is_dmg_compat();
+        self.sgb_mode = self.memory.is_sgb();
+        self.dmg_palettes = [self.memory.read(0xFF47), self.memory.read(0xFF48), self.memory.read(0xFF49)];
+
+        if cgb_mode != self.cgb_mode {
+            self.cgb_mode = cgb_mode;
+            self.sprites_dirty = true;
+        }
+
+        if self.cgb_mode || self.dmg_compat {
+            self.cgb_background_colors = Gpu::make_cgb_colors(&self.memory.cgb_palettes(false));
+            self.cgb_sprite_colors = Gpu::make_cgb_colors(&self.memory.cgb_palettes(true));
+        }
+
+        if self.dmg_compat {
+            self.tile_palette = Gpu::make_palette(self.memory.read(0xFF47), &self.cgb_background_colors[0..4]);
+            self.sprites_palettes[0] = Gpu::make_palette(self.memory.read(0xFF48), &self.cgb_sprite_colors[0..4]);
+            self.sprites_palettes[1] = Gpu::make_palette(self.memory.read(0xFF49), &self.cgb_sprite_colors[4..8]);
+        }
+        else {
+            self.tile_palette = Gpu::make_palette(self.memory.read(0xFF47), &DMG_SHADES);
+            self.sprites_palettes[0] = Gpu::make_palette(self.memory.read(0xFF48), &DMG_SHADES);
+            self.sprites_palettes[1] = Gpu::make_palette(self.memory.read(0xFF49), &DMG_SHADES);
+        }
+
+        self.sprites_by_x = self.memory.sprite_priority_by_x();
 
         let oam_hash = self.memory.get_oam_hash();
         if oam_hash != self.oam_hash {
@@ -689,106 +831,6 @@ impl Gpu {
         }
     }
 
-    fn update_inputs(&mut self) {
-        for event in self.event_pump.poll_iter() {
-            match event {
-                Event::Quit{..} => {
-                    self.input_tx.send(InputEvent::Quit).unwrap();
-                }
-                Event::KeyDown{keycode: Some(Keycode::A), ..} => {
-                    let mut count = 5;
-                    while count > 0 {
-                        let result = self.input_tx.send(InputEvent::APressed);
-                        match result {
-                            Ok(_) => {},
-                            Err(error) => {error!("Input: Failed to send event to CPU, error {}", error); count = 0},
-                        }
-                        count -= 1;
-                    }
-                },
-                Event::KeyDown{keycode: Some(Keycode::S), ..} => {
-                    let mut count = 5;
-                    while count > 0 {
-                        let result = self.input_tx.send(InputEvent::BPressed);
-                        match result {
-                            Ok(_) => {},
-                            Err(error) => {error!("Input: Failed to send event to CPU, error {}", error); count = 0},
-                        }
-                        count -= 1;
-                    }
-                },
-                Event::KeyDown{keycode: Some(Keycode::Return), ..} => {
-                    let mut count = 5;
-                    while count > 0 {
-                        let result = self.input_tx.send(InputEvent::StartPressed);
-                        match result {
-                            Ok(_) => {},
-                            Err(error) => {error!("Input: Failed to send event to CPU, error {}", error); count = 0},
-                        }
-                        count -= 1;
-                    }
-                },
-                Event::KeyDown{keycode: Some(Keycode::RShift), ..} => {
-                    let mut count = 5;
-                    while count > 0 {
-                        let result = self.input_tx.send(InputEvent::SelectPressed);
-                        match result {
-                            Ok(_) => {},
-                            Err(error) => {error!("Input: Failed to send event to CPU, error {}", error); count = 0},
-                        }
-                        count -= 1;
-                    }
-                },
-                Event::KeyDown{keycode: Some(Keycode::Up), ..} => {
-                    let mut count = 5;
-                    while count > 0 {
-                        let result = self.input_tx.send(InputEvent::UpPressed);
-                        match result {
-                            Ok(_) => {},
-                            Err(error) => {error!("Input: Failed to send event to CPU, error {}", error); count = 0},
-                        }
-                        count -= 1;
-                    }
-                },
-                Event::KeyDown{keycode: Some(Keycode::Down), ..} => {
-                    let mut count = 5;
-                    while count > 0 {
-                        let result = self.input_tx.send(InputEvent::DownPressed);
-                        match result {
-                            Ok(_) => {},
-                            Err(error) => {error!("Input: Failed to send event to CPU, error {}", error); count = 0},
-                        }
-                        count -= 1;
-                    }
-                },
-                Event::KeyDown{keycode: Some(Keycode::Left), ..} => {
-                    let mut count = 5;
-                    while count > 0 {
-                        let result = self.input_tx.send(InputEvent::LeftPressed);
-                        match result {
-                            Ok(_) => {},
-                            Err(error) => {error!("Input: Failed to send event to CPU, error {}", error); count = 0},
-                        }
-                        count -= 1;
-                    }
-                },
-                Event::KeyDown{keycode: Some(Keycode::Right), ..} => {
-                    let mut count = 5;
-                    while count > 0 {
-                        let result = self.input_tx.send(InputEvent::RightPressed);
-                        match result {
-                            Ok(_) => {},
-                            Err(error) => {error!("Input: Failed to send event to CPU, error {}", error); count = 0},
-                        }
-                        count -= 1;
-                    }
-                },
-                _ => {}
-            }
-        }
-    
-    }
-
     fn request_interrupt(&self, interrupt: InterruptType) {
         let mut if_value = self.memory.read(0xFF0F);
 
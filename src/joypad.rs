@@ -0,0 +1,98 @@
+pub const DEFAULT_TURBO_RATE: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right, Button::Left, Button::Up, Button::Down,
+        Button::A, Button::B, Button::Select, Button::Start,
+    ];
+
+    // Directions take the low nibble and buttons the high one, in the same order P1 reports them.
+    pub fn mask(&self) -> u8 {
+        match self {
+            Button::Right => 1,
+            Button::Left => 1 << 1,
+            Button::Up => 1 << 2,
+            Button::Down => 1 << 3,
+            Button::A => 1 << 4,
+            Button::B => 1 << 5,
+            Button::Select => 1 << 6,
+            Button::Start => 1 << 7,
+        }
+    }
+}
+
+// Computes the low nibble of P1 for a pressed buttons mask and the select bits written by the game.
+// Lines are active low, and both groups can be selected at the same time.
+pub fn p1_lines(pressed: u8, select: u8) -> u8 {
+    let mut lines = 0x0F;
+
+    if (select >> 4) & 1 == 0 {
+        lines &= !(pressed & 0x0F);
+    }
+    if (select >> 5) & 1 == 0 {
+        lines &= !(pressed >> 4);
+    }
+
+    lines
+}
+
+// Live input coming from the frontend. The joypad only sees it once per frame, through sample().
+pub struct InputState {
+    buttons: u8,
+    turbo_buttons: u8,
+    turbo_rate: u32,
+}
+
+impl InputState {
+    pub fn new() -> InputState {
+        InputState {
+            buttons: 0,
+            turbo_buttons: 0,
+            turbo_rate: DEFAULT_TURBO_RATE,
+        }
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.buttons |= button.mask();
+        }
+        else {
+            self.buttons &= !button.mask();
+        }
+    }
+
+    pub fn set_turbo_button(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.turbo_buttons |= button.mask();
+        }
+        else {
+            self.turbo_buttons &= !button.mask();
+        }
+    }
+
+    pub fn set_turbo_rate(&mut self, rate: u32) {
+        self.turbo_rate = rate.max(1);
+    }
+
+    // Turbo buttons are pressed for turbo_rate frames, then released for as many.
+    pub fn sample(&self, frame: u64) -> u8 {
+        if (frame / self.turbo_rate as u64) & 1 == 0 {
+            self.buttons | self.turbo_buttons
+        }
+        else {
+            self.buttons
+        }
+    }
+}
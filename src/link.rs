@@ -0,0 +1,378 @@
+use std::io;
+use std::io::{Read, Write};
+use std::cell::Cell;
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use log::{error, info};
+
+use super::serial::SerialDevice;
+
+
+const LINK_MAGIC: &[u8; 4] = b"RBLK";
+// Bump whenever the messages change, both sides have to speak the same version.
+const LINK_VERSION: u8 = 1;
+
+// Both sides stop and swap a message every this many cycles. A quarter of a transfer, so the answer
+// to a transfer started anywhere in a slice is back before the transfer ends.
+const SYNC_CYCLES: u32 = 1024;
+
+// How long to wait for the other side before letting the CPU thread look at its input events.
+const READ_TIMEOUT: Duration = Duration::from_millis(10);
+
+const MESSAGE_SIZE: usize = 3;
+const FLAG_START: u8 = 1;
+const FLAG_REPLY: u8 = 1 << 1;
+
+#[derive(Clone, PartialEq)]
+pub enum LinkAddress {
+    TcpListen(String),
+    Tcp(String),
+    #[cfg(unix)]
+    UnixListen(PathBuf),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl LinkAddress {
+    pub fn parse(value: &str) -> Option<LinkAddress> {
+        let (kind, address) = value.split_once(':')?;
+
+        match kind {
+            "tcp-listen" => Some(LinkAddress::TcpListen(address.to_string())),
+            "tcp" => Some(LinkAddress::Tcp(address.to_string())),
+            #[cfg(unix)]
+            "unix-listen" => Some(LinkAddress::UnixListen(PathBuf::from(address))),
+            #[cfg(unix)]
+            "unix" => Some(LinkAddress::Unix(PathBuf::from(address))),
+            _ => None,
+        }
+    }
+}
+
+trait LinkStream: Read + Write + Send {
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl LinkStream for TcpStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+#[cfg(unix)]
+impl LinkStream for UnixStream {
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(timeout)
+    }
+}
+
+// One end of an in-memory cable, for two Game Boys in the same process.
+struct Pipe {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    // Received, but not read yet.
+    pending: Vec<u8>,
+    timeout: Cell<Option<Duration>>,
+}
+
+impl Pipe {
+    fn pair() -> (Pipe, Pipe) {
+        let (first_tx, second_rx) = mpsc::channel();
+        let (second_tx, first_rx) = mpsc::channel();
+
+        let first = Pipe { tx: first_tx, rx: first_rx, pending: Vec::new(), timeout: Cell::new(None) };
+        let second = Pipe { tx: second_tx, rx: second_rx, pending: Vec::new(), timeout: Cell::new(None) };
+        (first, second)
+    }
+}
+
+impl Read for Pipe {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            let received = match self.timeout.get() {
+                Some(timeout) => self.rx.recv_timeout(timeout),
+                None => self.rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            self.pending = match received {
+                Ok(data) => data,
+                Err(RecvTimeoutError::Timeout) => return Err(io::Error::new(io::ErrorKind::TimedOut, "nothing received")),
+                // Same as a closed socket.
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            };
+        }
+
+        let length = buffer.len().min(self.pending.len());
+        buffer[..length].copy_from_slice(&self.pending[..length]);
+        self.pending.drain(..length);
+        Ok(length)
+    }
+}
+
+impl Write for Pipe {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        self.tx.send(buffer.to_vec()).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the other end is gone"))?;
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl LinkStream for Pipe {
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.timeout.set(timeout);
+        Ok(())
+    }
+}
+
+// What one side did during a slice.
+#[derive(Clone, Copy)]
+struct Message {
+    // Byte of an internally clocked transfer started during the slice.
+    start: Option<u8>,
+    // What was in the port when the other side's transfer reached it.
+    reply: Option<u8>,
+}
+
+// Link cable to another instance. Both run in lockstep: every SYNC_CYCLES each side sends what
+// happened on its end and waits for the other one to do the same before going on.
+//
+// A transfer started in one slice reaches the other side at the end of that slice. Its port gets
+// the byte, if it was waiting for an external clock, and its contents go back in the next message.
+// That's in time for the transfer to end 4096 cycles after it started, like on hardware. Transfers
+// on the CGB's fast clock end before the reply can arrive and shift in 0xFF.
+pub struct LinkCable {
+    stream: Option<Box<dyn LinkStream>>,
+
+    cycles: u32,
+    outgoing: Message,
+    // Received, to be applied on the next clock.
+    incoming: Option<Message>,
+    buffer: Vec<u8>,
+    // Sent this slice's message, the other side's isn't here yet.
+    waiting: bool,
+
+    // Answer to the transfer this side started.
+    reply: Option<u8>,
+}
+
+impl LinkCable {
+    pub fn connect(address: &LinkAddress) -> Result<LinkCable, String> {
+        let stream = LinkCable::open(address).map_err(|error| format!("Failed to connect the link cable, error {}", error))?;
+        let mut cable = LinkCable::new(stream);
+
+        cable.handshake().map_err(|error| format!("Link cable handshake failed, error {}", error))?;
+        info!("Link: Connected");
+        Ok(cable)
+    }
+
+    // Both ends of a cable between two Game Boys in this process. Both are built by the same
+    // binary, so there's no handshake.
+    pub fn local_pair() -> (LinkCable, LinkCable) {
+        let (first, second) = Pipe::pair();
+        first.set_timeout(Some(READ_TIMEOUT)).unwrap();
+        second.set_timeout(Some(READ_TIMEOUT)).unwrap();
+
+        (LinkCable::new(Box::new(first)), LinkCable::new(Box::new(second)))
+    }
+
+    fn new(stream: Box<dyn LinkStream>) -> LinkCable {
+        LinkCable {
+            stream: Some(stream),
+
+            cycles: 0,
+            outgoing: Message { start: None, reply: None },
+            incoming: None,
+            buffer: Vec::with_capacity(MESSAGE_SIZE),
+            waiting: false,
+
+            reply: None,
+        }
+    }
+
+    fn open(address: &LinkAddress) -> io::Result<Box<dyn LinkStream>> {
+        match address {
+            LinkAddress::TcpListen(address) => {
+                let listener = TcpListener::bind(address)?;
+                info!("Link: Waiting for the other Game Boy on {}", listener.local_addr()?);
+
+                let (stream, peer) = listener.accept()?;
+                info!("Link: {} plugged in", peer);
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            },
+            LinkAddress::Tcp(address) => {
+                info!("Link: Connecting to {}", address);
+                let stream = TcpStream::connect(address)?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            },
+            #[cfg(unix)]
+            LinkAddress::UnixListen(path) => {
+                // A socket file left behind by an earlier run would make binding fail. Anything
+                // else at the path is left alone, it's most likely a typo.
+                if let Ok(metadata) = std::fs::symlink_metadata(path) {
+                    if !metadata.file_type().is_socket() {
+                        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:#?} already exists and isn't a socket", path)));
+                    }
+
+                    std::fs::remove_file(path)?;
+                }
+
+                let listener = UnixListener::bind(path)?;
+                info!("Link: Waiting for the other Game Boy on {:#?}", path);
+
+                let (stream, _) = listener.accept()?;
+                info!("Link: Other Game Boy plugged in");
+                Ok(Box::new(stream))
+            },
+            #[cfg(unix)]
+            LinkAddress::Unix(path) => {
+                info!("Link: Connecting to {:#?}", path);
+                Ok(Box::new(UnixStream::connect(path)?))
+            },
+        }
+    }
+
+    fn handshake(&mut self) -> io::Result<()> {
+        let stream = self.stream.as_mut().unwrap();
+        let mut hello = LINK_MAGIC.to_vec();
+        hello.push(LINK_VERSION);
+        stream.write_all(&hello)?;
+
+        let mut other = [0; 5];
+        stream.read_exact(&mut other)?;
+
+        if &other[..4] != LINK_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the other side isn't a Rusty Boi link"));
+        }
+
+        if other[4] != LINK_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("link version {} on the other side, expected {}", other[4], LINK_VERSION)));
+        }
+
+        stream.set_timeout(Some(READ_TIMEOUT))
+    }
+
+    // Pulls the cable, the port behaves as if nothing was plugged in from here on.
+    fn disconnect(&mut self, reason: &str) {
+        error!("Link: {}, unplugging the cable", reason);
+        self.stream = None;
+        self.waiting = false;
+    }
+
+    fn send(&mut self) {
+        let message = [
+            (self.outgoing.start.is_some() as u8 * FLAG_START) | (self.outgoing.reply.is_some() as u8 * FLAG_REPLY),
+            self.outgoing.start.unwrap_or(0),
+            self.outgoing.reply.unwrap_or(0),
+        ];
+
+        self.outgoing = Message { start: None, reply: None };
+
+        if let Some(stream) = &mut self.stream {
+            match stream.write_all(&message) {
+                Ok(_) => self.waiting = true,
+                Err(error) => self.disconnect(&format!("Failed to send, error {}", error)),
+            }
+        }
+    }
+
+    // Returns false if the message isn't complete yet.
+    fn receive(&mut self) -> bool {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return true,
+        };
+
+        let mut data = [0; MESSAGE_SIZE];
+        let missing = MESSAGE_SIZE - self.buffer.len();
+
+        match stream.read(&mut data[..missing]) {
+            Ok(0) => {
+                self.disconnect("The other side left");
+                return true;
+            },
+            Ok(read) => self.buffer.extend_from_slice(&data[..read]),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock || error.kind() == io::ErrorKind::TimedOut => return false,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => return false,
+            Err(error) => {
+                self.disconnect(&format!("Failed to receive, error {}", error));
+                return true;
+            }
+        }
+
+        if self.buffer.len() < MESSAGE_SIZE {
+            return false;
+        }
+
+        let flags = self.buffer[0];
+        self.incoming = Some(Message {
+            start: if flags & FLAG_START != 0 {Some(self.buffer[1])} else {None},
+            reply: if flags & FLAG_REPLY != 0 {Some(self.buffer[2])} else {None},
+        });
+
+        self.buffer.clear();
+        true
+    }
+}
+
+impl SerialDevice for LinkCable {
+    fn transfer_started(&mut self, byte: u8) {
+        self.outgoing.start = Some(byte);
+        self.reply = None;
+    }
+
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        self.reply.take().unwrap_or(0xFF)
+    }
+
+    fn clock(&mut self, cycles: u16, data: u8, waiting: bool) -> Option<u8> {
+        let mut shifted_in = None;
+
+        if let Some(message) = self.incoming.take() {
+            // The other side's transfer reaches this port. Whatever was in it goes back either way,
+            // but only a port waiting for an external clock takes the byte in.
+            if let Some(byte) = message.start {
+                self.outgoing.reply = Some(data);
+
+                if waiting {
+                    shifted_in = Some(byte);
+                }
+            }
+
+            if message.reply.is_some() {
+                self.reply = message.reply;
+            }
+        }
+
+        if self.stream.is_some() {
+            self.cycles += cycles as u32;
+
+            if self.cycles >= SYNC_CYCLES {
+                self.cycles -= SYNC_CYCLES;
+                self.send();
+            }
+        }
+
+        shifted_in
+    }
+
+    fn ready(&mut self) -> bool {
+        if self.waiting && self.receive() {
+            self.waiting = false;
+        }
+
+        !self.waiting
+    }
+}
@@ -1,9 +1,27 @@
 mod cpu;
+mod display;
 mod gpu;
+mod apu;
+mod avi;
 mod cart;
 mod timer;
+mod serial;
+mod link;
+mod printer;
+mod joypad;
+mod palettes;
+mod sgb;
 mod memory;
+mod movie;
+mod savestate;
+mod screenshot;
+mod rewind;
+mod pacing;
 mod emulator;
+mod config;
+mod options;
+mod frontend;
+mod wav;
 
 use log::info;
 
@@ -1,60 +1,621 @@
+use std::io;
+use std::sync::Mutex;
+use std::path::Path;
 use std::hash::{Hash, Hasher};
 use std::collections::hash_map::DefaultHasher;
-use std::sync::atomic::{AtomicU8, AtomicU64, AtomicBool, Ordering};
+use std::sync::atomic::{AtomicU8, AtomicU16, AtomicU64, AtomicBool, Ordering};
 
-use log::warn;
+use log::{info, warn, error};
 
+use super::apu;
+use super::apu::{Apu, ChannelState};
+use super::avi::AviWriter;
+use super::gpu;
+use super::gpu::Frame;
 use super::cart::CartData;
+use super::serial::{Serial, SerialDevice};
+use super::savestate::{StateReader, StateWriter};
+use super::joypad;
+use super::palettes;
+use super::palettes::{CompatibilityPalette, CompatibilitySetting};
+use super::sgb;
+use super::sgb::{Sgb, SgbSettings, Transfer};
 
 
+// VRAM and WRAM banks are always there, only CGB mode makes them switchable.
+const CHAR_RAM_SIZE: usize = 6144;
+const BACKGROUND_MEMORY_SIZE: usize = 2048;
+const RAM_BANK_SIZE: usize = 4096;
+
 pub struct Memory {
     bootrom: Vec<u8>,
     loaded_cart: CartData,
 
+    // How DMG games run when there's no bootrom to decide.
+    compatibility: CompatibilitySetting,
+    sgb_settings: SgbSettings,
+
+    // Set on power on for SGB games, if they're allowed to run on one.
+    sgb_mode: AtomicBool,
+
+    // Set on power on for CGB games, unless a DMG bootrom is going to run.
+    cgb_mode: AtomicBool,
+    // A DMG game on a CGB. The CGB registers are locked, but BGP, OBP0 and OBP1 pick colors from
+    // the first CGB palettes.
+    dmg_compat: AtomicBool,
+    // Set until the first input after an automatic DMG compatibility boot, held buttons can still
+    // pick the palette then, like during the bootrom's logo.
+    compat_buttons_pending: AtomicBool,
+
+    // Both VRAM banks, one after the other. Bank 1 has the BG map attributes where bank 0 has the maps.
     char_ram: Vec<AtomicU8>,
     background_memory: Vec<AtomicU8>,
 
+    // 8 banks, 0xD000-0xDFFF maps any of them but bank 0.
     ram: Vec<AtomicU8>,
     oam_mem: Vec<AtomicU8>,
     io_registers: Vec<AtomicU8>,
     
     hram: Vec<AtomicU8>,
 
+    // 8 palettes of 4 RGB555 colors each, for BG and for sprites.
+    background_palettes: Vec<AtomicU8>,
+    sprite_palettes: Vec<AtomicU8>,
+
+    apu: Mutex<Apu>,
+    serial: Mutex<Serial>,
+    sgb: Mutex<Sgb>,
+    video_recorder: Mutex<Option<AviWriter>>,
+
     using_bootrom: AtomicBool,
     interrupts_enabled: AtomicU8,
 
+    // Bit set means pressed, see joypad::Button for the layout.
+    joypad_state: AtomicU8,
+
     oam_hash: AtomicU64,
+
+    dma_active: AtomicBool,
+    dma_source: AtomicU16,
+    dma_index: AtomicU16,
+    dma_pending: AtomicBool,
+    dma_pending_source: AtomicU16,
+    dma_delay: AtomicU8,
+    dma_cycles: AtomicU16,
+
+    // VRAM DMA. The source and destination advance as blocks are copied, the destination is an offset into VRAM.
+    // HDMA5 itself holds what reading it returns, the blocks left minus one.
+    hdma_source: AtomicU16,
+    hdma_destination: AtomicU16,
+    hdma_active: AtomicBool,
+    // Cycles the CPU still has to sit out for the blocks copied so far.
+    hdma_stall: AtomicU16,
+
     pub tiles_dirty_flags: AtomicU8,
     pub background_dirty_flags: AtomicU8,
 }
 
 impl Memory {
 
-    pub fn new(bootrom_data: Vec<u8>, use_bootrom: bool, loaded_cart: CartData) -> Memory {
+    pub fn new(bootrom_data: Vec<u8>, use_bootrom: bool, loaded_cart: CartData, compatibility: CompatibilitySetting, sgb_settings: SgbSettings) -> Memory {
 
-        Memory {
-            bootrom: bootrom_data,
+        let memory = Memory {
+            // Resets go through the bootrom too, so it's only kept if it's going to be used.
+            bootrom: if use_bootrom {bootrom_data} else {Vec::new()},
             loaded_cart: loaded_cart,
-            char_ram: new_atomic_vec(6144),
-            background_memory: new_atomic_vec(2048),
-            ram: new_atomic_vec(8192),
+            compatibility,
+            sgb_settings,
+            sgb_mode: AtomicBool::new(false),
+            cgb_mode: AtomicBool::new(false),
+            dmg_compat: AtomicBool::new(false),
+            compat_buttons_pending: AtomicBool::new(false),
+            char_ram: new_atomic_vec(CHAR_RAM_SIZE * 2),
+            background_memory: new_atomic_vec(BACKGROUND_MEMORY_SIZE * 2),
+            ram: new_atomic_vec(RAM_BANK_SIZE * 8),
             oam_mem: new_atomic_vec(160),
             io_registers: new_atomic_vec(128),
             hram: new_atomic_vec(128),
+            background_palettes: new_atomic_vec(64),
+            sprite_palettes: new_atomic_vec(64),
+            apu: Mutex::new(Apu::new()),
+            serial: Mutex::new(Serial::new()),
+            sgb: Mutex::new(Sgb::new()),
+            video_recorder: Mutex::new(None),
             using_bootrom: AtomicBool::from(use_bootrom),
             interrupts_enabled: AtomicU8::new(0),
+            joypad_state: AtomicU8::new(0),
             oam_hash: AtomicU64::new(0),
+
+            dma_active: AtomicBool::new(false),
+            dma_source: AtomicU16::new(0),
+            dma_index: AtomicU16::new(0),
+            dma_pending: AtomicBool::new(false),
+            dma_pending_source: AtomicU16::new(0),
+            dma_delay: AtomicU8::new(0),
+            dma_cycles: AtomicU16::new(0),
+
+            hdma_source: AtomicU16::new(0),
+            hdma_destination: AtomicU16::new(0),
+            hdma_active: AtomicBool::new(false),
+            hdma_stall: AtomicU16::new(0),
+
             tiles_dirty_flags: AtomicU8::new(0),
             background_dirty_flags: AtomicU8::new(0),
+        };
+
+        memory.reset();
+        memory
+    }
+
+    // Back to power on, except for the cart RAM. Without a bootrom the registers it would
+    // have set up are written directly instead.
+    pub fn reset(&self) {
+        let regions = [&self.char_ram, &self.background_memory, &self.ram, &self.oam_mem, &self.io_registers, &self.hram, &self.background_palettes, &self.sprite_palettes];
+
+        for region in regions.iter() {
+            for byte in region.iter() {
+                byte.store(0, Ordering::Relaxed);
+            }
         }
+
+        self.apu.lock().unwrap().reset();
+        self.serial.lock().unwrap().reset();
+        *self.sgb.lock().unwrap() = Sgb::new();
+        self.loaded_cart.reset();
+
+        self.using_bootrom.store(!self.bootrom.is_empty(), Ordering::Relaxed);
+        self.interrupts_enabled.store(0, Ordering::Relaxed);
+
+        // An SGB runs its games, unless they're for the CGB too or the bootrom is a CGB one.
+        let sgb_mode = self.sgb_settings.enabled && self.loaded_cart.supports_sgb() && !self.loaded_cart.supports_cgb() && self.bootrom.len() <= 0x0100;
+        self.sgb_mode.store(sgb_mode, Ordering::Relaxed);
+
+        // A DMG bootrom is only 256 bytes long, and only runs on a DMG. A CGB one runs DMG games
+        // too, and switches to DMG compatibility by itself.
+        let cgb_mode = if sgb_mode {
+            false
+        }
+        else if self.bootrom.is_empty() {
+            self.loaded_cart.supports_cgb() || !matches!(self.compatibility, CompatibilitySetting::Off)
+        }
+        else {
+            self.bootrom.len() != 0x0100
+        };
+
+        self.cgb_mode.store(cgb_mode, Ordering::Relaxed);
+        self.dmg_compat.store(false, Ordering::Relaxed);
+        self.compat_buttons_pending.store(false, Ordering::Relaxed);
+
+        self.dma_active.store(false, Ordering::Relaxed);
+        self.dma_source.store(0, Ordering::Relaxed);
+        self.dma_index.store(0, Ordering::Relaxed);
+        self.dma_pending.store(false, Ordering::Relaxed);
+        self.dma_pending_source.store(0, Ordering::Relaxed);
+        self.dma_delay.store(0, Ordering::Relaxed);
+        self.dma_cycles.store(0, Ordering::Relaxed);
+
+        self.hdma_source.store(0, Ordering::Relaxed);
+        self.hdma_destination.store(0, Ordering::Relaxed);
+        self.hdma_active.store(false, Ordering::Relaxed);
+        self.hdma_stall.store(0, Ordering::Relaxed);
+        self.io_registers[0x55].store(0xFF, Ordering::Relaxed);
+
+        if self.bootrom.is_empty() {
+            let post_boot = [(0xFF00, 0x30), (0xFF26, 0x80), (0xFF24, 0x77), (0xFF25, 0xF3), (0xFF40, 0x91), (0xFF47, 0xFC), (0xFF48, 0xFF), (0xFF49, 0xFF)];
+
+            for (address, value) in post_boot.iter() {
+                self.write(*address, *value, false);
+            }
+
+            // The CGB bootrom leaves every palette white for CGB games.
+            if cgb_mode && self.loaded_cart.supports_cgb() {
+                for byte in self.background_palettes.iter().chain(self.sprite_palettes.iter()) {
+                    byte.store(0xFF, Ordering::Relaxed);
+                }
+            }
+            else if cgb_mode {
+                self.enter_dmg_compat(self.compatibility_palette());
+                self.compat_buttons_pending.store(matches!(self.compatibility, CompatibilitySetting::Auto), Ordering::Relaxed);
+            }
+        }
+
+        self.hash_oam();
+        self.tiles_dirty_flags.store(1, Ordering::Relaxed);
+        self.background_dirty_flags.store(1, Ordering::Relaxed);
+    }
+
+    // Mute, solo, recordings and the serial device survive power cycles, which replace the whole Memory.
+    pub fn take_outputs(&self, old: &Memory) {
+        self.apu.lock().unwrap().take_outputs(&mut old.apu.lock().unwrap());
+        self.serial.lock().unwrap().set_device(old.serial.lock().unwrap().take_device());
+        *self.video_recorder.lock().unwrap() = old.video_recorder.lock().unwrap().take();
+    }
+
+    pub fn compatibility(&self) -> CompatibilitySetting {
+        self.compatibility
+    }
+
+    pub fn sgb_settings(&self) -> SgbSettings {
+        self.sgb_settings
+    }
+
+    pub fn rom_hash(&self) -> u64 {
+        self.loaded_cart.rom_hash()
+    }
+
+    pub fn rom_title(&self) -> &str {
+        self.loaded_cart.rom_title()
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.cgb_mode.load(Ordering::Relaxed));
+        writer.bool(self.dmg_compat.load(Ordering::Relaxed));
+        writer.bool(self.sgb_mode.load(Ordering::Relaxed));
+        writer.atomics(&self.char_ram);
+        writer.atomics(&self.background_memory);
+        writer.atomics(&self.ram);
+        writer.atomics(&self.oam_mem);
+        writer.atomics(&self.io_registers);
+        writer.atomics(&self.hram);
+        writer.atomics(&self.background_palettes);
+        writer.atomics(&self.sprite_palettes);
+
+        writer.bool(self.using_bootrom.load(Ordering::Relaxed));
+        writer.u8(self.interrupts_enabled.load(Ordering::Relaxed));
+        writer.u8(self.joypad_state.load(Ordering::Relaxed));
+
+        writer.bool(self.dma_active.load(Ordering::Relaxed));
+        writer.u16(self.dma_source.load(Ordering::Relaxed));
+        writer.u16(self.dma_index.load(Ordering::Relaxed));
+        writer.bool(self.dma_pending.load(Ordering::Relaxed));
+        writer.u16(self.dma_pending_source.load(Ordering::Relaxed));
+        writer.u8(self.dma_delay.load(Ordering::Relaxed));
+        writer.u16(self.dma_cycles.load(Ordering::Relaxed));
+
+        writer.u16(self.hdma_source.load(Ordering::Relaxed));
+        writer.u16(self.hdma_destination.load(Ordering::Relaxed));
+        writer.bool(self.hdma_active.load(Ordering::Relaxed));
+        writer.u16(self.hdma_stall.load(Ordering::Relaxed));
+
+        self.apu.lock().unwrap().save_state(writer);
+        self.serial.lock().unwrap().save_state(writer);
+        self.sgb.lock().unwrap().save_state(writer);
+        self.loaded_cart.save_state(writer);
+    }
+
+    pub fn load_state(&self, reader: &mut StateReader) -> io::Result<()> {
+        self.cgb_mode.store(reader.bool()?, Ordering::Relaxed);
+        self.dmg_compat.store(reader.bool()?, Ordering::Relaxed);
+        self.compat_buttons_pending.store(false, Ordering::Relaxed);
+        self.sgb_mode.store(reader.bool()?, Ordering::Relaxed);
+        reader.atomics(&self.char_ram)?;
+        reader.atomics(&self.background_memory)?;
+        reader.atomics(&self.ram)?;
+        reader.atomics(&self.oam_mem)?;
+        reader.atomics(&self.io_registers)?;
+        reader.atomics(&self.hram)?;
+        reader.atomics(&self.background_palettes)?;
+        reader.atomics(&self.sprite_palettes)?;
+
+        self.using_bootrom.store(reader.bool()? && !self.bootrom.is_empty(), Ordering::Relaxed);
+        self.interrupts_enabled.store(reader.u8()?, Ordering::Relaxed);
+        self.joypad_state.store(reader.u8()?, Ordering::Relaxed);
+
+        self.dma_active.store(reader.bool()?, Ordering::Relaxed);
+        self.dma_source.store(reader.u16()?, Ordering::Relaxed);
+        self.dma_index.store(reader.u16()?, Ordering::Relaxed);
+        self.dma_pending.store(reader.bool()?, Ordering::Relaxed);
+        self.dma_pending_source.store(reader.u16()?, Ordering::Relaxed);
+        self.dma_delay.store(reader.u8()?, Ordering::Relaxed);
+        self.dma_cycles.store(reader.u16()?, Ordering::Relaxed);
+
+        self.hdma_source.store(reader.u16()?, Ordering::Relaxed);
+        self.hdma_destination.store(reader.u16()?, Ordering::Relaxed);
+        self.hdma_active.store(reader.bool()?, Ordering::Relaxed);
+        self.hdma_stall.store(reader.u16()?, Ordering::Relaxed);
+
+        self.apu.lock().unwrap().load_state(reader)?;
+        self.serial.lock().unwrap().load_state(reader)?;
+        self.sgb.lock().unwrap().load_state(reader)?;
+        self.loaded_cart.load_state(reader)?;
+
+        // Everything the GPU caches has to be rebuilt from the new contents.
+        self.hash_oam();
+        self.tiles_dirty_flags.store(1, Ordering::Relaxed);
+        self.background_dirty_flags.store(1, Ordering::Relaxed);
+        Ok(())
     }
 
     pub fn is_bootrom_loaded(&self) -> bool {
         self.using_bootrom.load(Ordering::Relaxed)
     }
 
-    pub fn bootrom_finished(&self) {
-        self.using_bootrom.store(false, Ordering::Relaxed);
+    pub fn is_cgb(&self) -> bool {
+        self.cgb_mode.load(Ordering::Relaxed)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        self.sgb_mode.load(Ordering::Relaxed)
+    }
+
+    // Colors a finished frame the way the SNES would, see Sgb::render.
+    pub fn sgb_frame(&self, shades: &[u8]) -> (Vec<u8>, u32, u32) {
+        self.sgb.lock().unwrap().render(shades, self.sgb_settings.border)
+    }
+
+    // The SNES copies what's on screen: the tiles the BG map shows, row by row, 20 tiles per row.
+    // Games set the map up so that's 256 tiles in order.
+    fn sgb_transfer_data(&self) -> Vec<u8> {
+        let lcdc = self.io_registers[0x40].load(Ordering::Relaxed);
+        let map = if lcdc & 0x08 != 0 {0x9C00} else {0x9800};
+        let mut data = Vec::with_capacity(sgb::TRANSFER_SIZE);
+
+        for tile in 0..sgb::TRANSFER_SIZE / 16 {
+            let tile_id = self.read_vram(0, map + (tile / 20 * 32 + tile % 20) as u16);
+            let address = if lcdc & 0x10 != 0 {0x8000 + tile_id as u16 * 16} else {(0x9000 + (tile_id as i8 as i32) * 16) as u16};
+
+            data.extend((0..16).map(|offset| self.read_vram(0, address + offset)));
+        }
+
+        data
+    }
+
+    pub fn is_dmg_compat(&self) -> bool {
+        self.dmg_compat.load(Ordering::Relaxed)
+    }
+
+    // Picked by title here, held buttons are only known once the first input arrives.
+    fn compatibility_palette(&self) -> CompatibilityPalette {
+        match self.compatibility {
+            CompatibilitySetting::Palette(palette) => palette,
+            _ => palettes::for_title(self.loaded_cart.title_checksum()),
+        }
+    }
+
+    // What the CGB bootrom does before starting a DMG game: the colors go to the first BG palette
+    // and the first two sprite palettes, then the CGB registers are locked.
+    fn enter_dmg_compat(&self, palette: CompatibilityPalette) {
+        self.store_compatibility_palette(palette);
+
+        self.cgb_mode.store(false, Ordering::Relaxed);
+        self.dmg_compat.store(true, Ordering::Relaxed);
+    }
+
+    fn store_compatibility_palette(&self, palette: CompatibilityPalette) {
+        Memory::store_colors(&self.background_palettes[0..8], &palette.background);
+        Memory::store_colors(&self.sprite_palettes[0..8], &palette.sprites[0]);
+        Memory::store_colors(&self.sprite_palettes[8..16], &palette.sprites[1]);
+
+        info!("Memory: Running a DMG game on a CGB with palette combination {}.", palette.combination);
+    }
+
+    fn store_colors(palette: &[AtomicU8], colors: &[u16; 4]) {
+        for (bytes, color) in palette.chunks(2).zip(colors.iter()) {
+            bytes[0].store(*color as u8, Ordering::Relaxed);
+            bytes[1].store((color >> 8) as u8, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.io_registers[0x4D].load(Ordering::Relaxed) & 0x80 != 0
+    }
+
+    // Called by STOP. Returns true if KEY1 had a speed switch armed, which happens right away.
+    pub fn switch_speed(&self) -> bool {
+        let key1 = self.io_registers[0x4D].load(Ordering::Relaxed);
+
+        if !self.is_cgb() || key1 & 1 == 0 {
+            return false;
+        }
+
+        self.io_registers[0x4D].store((key1 ^ 0x80) & 0x80, Ordering::Relaxed);
+        true
+    }
+
+    // In CGB mode OPRI picks whether sprites are prioritized by OAM position, or by X coordinate like on a DMG.
+    pub fn sprite_priority_by_x(&self) -> bool {
+        !self.is_cgb() || self.io_registers[0x6C].load(Ordering::Relaxed) & 1 != 0
+    }
+
+    // Reads VRAM without going through VBK, for the GPU.
+    pub fn read_vram(&self, bank: usize, address: u16) -> u8 {
+        if address <= 0x97FF {
+            self.char_ram[bank * CHAR_RAM_SIZE + address as usize - 0x8000].load(Ordering::Relaxed)
+        }
+        else {
+            self.background_memory[bank * BACKGROUND_MEMORY_SIZE + address as usize - 0x9800].load(Ordering::Relaxed)
+        }
+    }
+
+    // All 32 colors, palette by palette.
+    pub fn cgb_palettes(&self, sprites: bool) -> Vec<u16> {
+        let palettes = if sprites {&self.sprite_palettes} else {&self.background_palettes};
+
+        palettes.chunks(2).map(|color| {
+            color[0].load(Ordering::Relaxed) as u16 | ((color[1].load(Ordering::Relaxed) as u16) << 8)
+        }).collect()
+    }
+
+    fn vram_bank(&self) -> usize {
+        if self.is_cgb() {(self.io_registers[0x4F].load(Ordering::Relaxed) & 1) as usize} else {0}
+    }
+
+    // Where 0xD000 starts in WRAM. Bank 0 can't be mapped there, selecting it maps bank 1.
+    fn ram_bank_offset(&self) -> usize {
+        let bank = if self.is_cgb() {(self.io_registers[0x70].load(Ordering::Relaxed) & 7).max(1)} else {1};
+        bank as usize * RAM_BANK_SIZE
+    }
+
+    fn ram_index(&self, address: u16) -> usize {
+        match address & 0x1FFF {
+            offset if offset < 0x1000 => offset as usize,
+            offset => self.ram_bank_offset() + offset as usize - 0x1000,
+        }
+    }
+
+    fn is_cgb_register(address: u16) -> bool {
+        address == 0xFF4C || address == 0xFF4D || address == 0xFF4F || (0xFF51..=0xFF55).contains(&address) || (0xFF68..=0xFF6C).contains(&address) || address == 0xFF70
+    }
+
+    // These don't exist on a DMG, they read as 0xFF and ignore writes in DMG mode.
+    fn read_cgb_register(&self, address: u16) -> u8 {
+        if !self.is_cgb() {
+            return 0xFF;
+        }
+
+        let value = self.io_registers[address as usize - 0xFF00].load(Ordering::Relaxed);
+
+        match address {
+            0xFF4C => 0xFF,
+            0xFF4D => value | 0x7E,
+            0xFF4F => value | 0xFE,
+            // The HDMA source and destination can't be read back.
+            0xFF51..=0xFF54 => 0xFF,
+            0xFF55 => value,
+            0xFF68 | 0xFF6A => value | 0x40,
+            0xFF69 => self.background_palettes[(self.io_registers[0x68].load(Ordering::Relaxed) & 0x3F) as usize].load(Ordering::Relaxed),
+            0xFF6B => self.sprite_palettes[(self.io_registers[0x6A].load(Ordering::Relaxed) & 0x3F) as usize].load(Ordering::Relaxed),
+            0xFF6C => value | 0xFE,
+            _ => value | 0xF8,
+        }
+    }
+
+    fn write_cgb_register(&self, address: u16, value: u8) {
+        if !self.is_cgb() {
+            return;
+        }
+
+        let register = &self.io_registers[address as usize - 0xFF00];
+
+        match address {
+            // KEY0 is only written by the CGB bootrom, 0x04 runs the game in DMG compatibility.
+            0xFF4C => {
+                if self.using_bootrom.load(Ordering::Relaxed) {
+                    register.store(value, Ordering::Relaxed);
+                }
+            },
+            // Only the switch can be armed, the current speed changes on STOP.
+            0xFF4D => register.store((register.load(Ordering::Relaxed) & 0x80) | (value & 1), Ordering::Relaxed),
+            0xFF4F | 0xFF6C => register.store(value & 1, Ordering::Relaxed),
+            0xFF51 => Memory::set_byte(&self.hdma_source, true, value),
+            0xFF52 => Memory::set_byte(&self.hdma_source, false, value & 0xF0),
+            0xFF53 => Memory::set_byte(&self.hdma_destination, true, value & 0x1F),
+            0xFF54 => Memory::set_byte(&self.hdma_destination, false, value & 0xF0),
+            0xFF55 => self.start_hdma(value),
+            0xFF68 | 0xFF6A => register.store(value & 0xBF, Ordering::Relaxed),
+            0xFF69 => self.write_palette(&self.background_palettes, &self.io_registers[0x68], value),
+            0xFF6B => self.write_palette(&self.sprite_palettes, &self.io_registers[0x6A], value),
+            _ => register.store(value & 7, Ordering::Relaxed),
+        }
+    }
+
+    fn set_byte(register: &AtomicU16, high: bool, value: u8) {
+        let old = register.load(Ordering::Relaxed);
+        let new = if high {(old & 0x00FF) | ((value as u16) << 8)} else {(old & 0xFF00) | value as u16};
+        register.store(new, Ordering::Relaxed);
+    }
+
+    // Bit 7 picks between general purpose DMA, which copies everything right away, and HBlank DMA,
+    // which copies a block at the start of every HBlank. Writing with bit 7 clear during an HBlank DMA
+    // cancels it instead.
+    fn start_hdma(&self, value: u8) {
+        let hdma5 = &self.io_registers[0x55];
+
+        if self.hdma_active.load(Ordering::Relaxed) && value & 0x80 == 0 {
+            self.hdma_active.store(false, Ordering::Relaxed);
+            hdma5.store(hdma5.load(Ordering::Relaxed) | 0x80, Ordering::Relaxed);
+            return;
+        }
+
+        if value & 0x80 == 0 {
+            for _ in 0..=(value & 0x7F) {
+                self.copy_hdma_block();
+            }
+
+            hdma5.store(0xFF, Ordering::Relaxed);
+        }
+        else {
+            hdma5.store(value & 0x7F, Ordering::Relaxed);
+            self.hdma_active.store(true, Ordering::Relaxed);
+
+            // With the LCD off there's no HBlank to wait for, the first block goes right away.
+            if self.io_registers[0x40].load(Ordering::Relaxed) & 0x80 == 0 {
+                self.hblank_dma();
+            }
+        }
+    }
+
+    // 16 bytes take 8 microseconds, twice as many CPU cycles in double speed.
+    fn copy_hdma_block(&self) {
+        let source = self.hdma_source.load(Ordering::Relaxed);
+        let destination = self.hdma_destination.load(Ordering::Relaxed);
+
+        for offset in 0..16 {
+            let value = self.read(source.wrapping_add(offset));
+            self.write(0x8000 | ((destination + offset) & 0x1FFF), value, false);
+        }
+
+        self.hdma_source.store(source.wrapping_add(16), Ordering::Relaxed);
+        self.hdma_destination.store((destination + 16) & 0x1FFF, Ordering::Relaxed);
+
+        let cycles = if self.is_double_speed() {64} else {32};
+        self.hdma_stall.fetch_add(cycles, Ordering::Relaxed);
+    }
+
+    // Called by the GPU when a visible line enters HBlank.
+    pub fn hblank_dma(&self) {
+        if !self.hdma_active.load(Ordering::Relaxed) {
+            return;
+        }
+
+        self.copy_hdma_block();
+
+        let hdma5 = &self.io_registers[0x55];
+        let blocks_left = hdma5.load(Ordering::Relaxed);
+
+        if blocks_left == 0 {
+            hdma5.store(0xFF, Ordering::Relaxed);
+            self.hdma_active.store(false, Ordering::Relaxed);
+        }
+        else {
+            hdma5.store(blocks_left - 1, Ordering::Relaxed);
+        }
+    }
+
+    // Takes an M-cycle off the time the CPU has to wait for HDMA, returns false if there's none left.
+    pub fn hdma_stall(&self) -> bool {
+        let stall = self.hdma_stall.load(Ordering::Relaxed);
+
+        if stall == 0 {
+            return false;
+        }
+
+        self.hdma_stall.store(stall.saturating_sub(4), Ordering::Relaxed);
+        true
+    }
+
+    // Bit 7 of the index register moves it to the next byte after every write.
+    fn write_palette(&self, palettes: &[AtomicU8], index_register: &AtomicU8, value: u8) {
+        let index = index_register.load(Ordering::Relaxed);
+        palettes[(index & 0x3F) as usize].store(value, Ordering::Relaxed);
+
+        if index & 0x80 != 0 {
+            index_register.store(0x80 | (index.wrapping_add(1) & 0x3F), Ordering::Relaxed);
+        }
+    }
+
+    // DMG bootroms only cover 0x0000-0x00FF, CGB ones are 2304 bytes long and also map 0x0200-0x08FF,
+    // leaving the cart header in 0x0100-0x01FF visible.
+    fn is_bootrom_address(&self, address: u16) -> bool {
+        if address < 0x0100 {
+            true
+        }
+        else if self.bootrom.len() > 0x0100 {
+            address >= 0x0200 && (address as usize) < self.bootrom.len()
+        }
+        else {
+            false
+        }
     }
 
     pub fn get_oam_hash(&self) -> u64 {
@@ -74,38 +635,40 @@ impl Memory {
         self.oam_hash.store(hasher.finish(), Ordering::Relaxed);
     }
 
+    // While OAM DMA is running the CPU can only reach HRAM, which is on a separate bus from the one
+    // the DMA uses. Everything else reads as 0xFF and ignores writes.
+    fn cpu_blocked_by_dma(&self, address: u16) -> bool {
+        self.dma_active.load(Ordering::Relaxed) && !(0xFF80..=0xFFFE).contains(&address)
+    }
+
+    // Reads coming from the CPU.
+    pub fn cpu_read(&self, address: u16) -> u8 {
+        if self.cpu_blocked_by_dma(address) {
+            return 0xFF;
+        }
+
+        self.read(address)
+    }
+
     pub fn read(&self, address: u16) -> u8 {
-        if address < 0x0100 {
-            if self.using_bootrom.load(Ordering::Relaxed) {
-                self.bootrom[address as usize]
-            }
-            else {
-                self.loaded_cart.read(address)
-            }
+        if self.using_bootrom.load(Ordering::Relaxed) && self.is_bootrom_address(address) {
+            self.bootrom[address as usize]
         }
 
         else if address <= 0x7FFF {
             self.loaded_cart.read(address)
         }
 
-        else if address >= 0x8000 && address <= 0x97FF {
-            self.char_ram[address as usize - 0x8000].load(Ordering::Relaxed)
-        }
-
-        else if address >= 0x9800 && address <= 0x9FFF {
-            self.background_memory[address as usize - 0x9800].load(Ordering::Relaxed)
+        else if address >= 0x8000 && address <= 0x9FFF {
+            self.read_vram(self.vram_bank(), address)
         }
 
         else if address >= 0xA000 && address <= 0xBFFF {
             self.loaded_cart.read(address)
         }
 
-        else if address >= 0xC000 && address <= 0xDFFF {
-            self.ram[address as usize - 0xC000].load(Ordering::Relaxed)
-        }
-
-        else if address >= 0xE000 && address <= 0xFDFF {
-            self.ram[address as usize - 0xE000].load(Ordering::Relaxed)
+        else if address >= 0xC000 && address <= 0xFDFF {
+            self.ram[self.ram_index(address)].load(Ordering::Relaxed)
         }
 
         else if address >= 0xFE00 && address <= 0xFE9F {
@@ -117,6 +680,22 @@ impl Memory {
             0
         }
 
+        else if address == 0xFF00 {
+            self.read_p1()
+        }
+
+        else if address == 0xFF01 || address == 0xFF02 {
+            self.serial.lock().unwrap().read(address, self.is_cgb())
+        }
+
+        else if address >= 0xFF10 && address <= 0xFF3F {
+            self.apu.lock().unwrap().read(address)
+        }
+
+        else if Memory::is_cgb_register(address) {
+            self.read_cgb_register(address)
+        }
+
         else if address >= 0xFF00 && address <= 0xFF7F {
             self.io_registers[address as usize - 0xFF00].load(Ordering::Relaxed)
         }
@@ -136,8 +715,8 @@ impl Memory {
 
     pub fn write(&self, address: u16, value: u8, cpu: bool) {
 
-        if address < 0x0100 && !self.using_bootrom.load(Ordering::Relaxed) {
-            self.loaded_cart.write(address, value);
+        if cpu && self.cpu_blocked_by_dma(address) {
+            return;
         }
 
         if address <= 0x7FFF {
@@ -145,13 +724,13 @@ impl Memory {
         }
 
         else if address >= 0x8000 && address <= 0x97FF {
-            self.char_ram[address as usize - 0x8000].store(value, Ordering::Relaxed);
+            self.char_ram[self.vram_bank() * CHAR_RAM_SIZE + address as usize - 0x8000].store(value, Ordering::Relaxed);
             self.tiles_dirty_flags.fetch_add(1, Ordering::Relaxed);
             self.background_dirty_flags.fetch_add(1, Ordering::Relaxed);
         }
 
         else if address >= 0x9800 && address <= 0x9FFF {
-            self.background_memory[address as usize - 0x9800].store(value, Ordering::Relaxed);
+            self.background_memory[self.vram_bank() * BACKGROUND_MEMORY_SIZE + address as usize - 0x9800].store(value, Ordering::Relaxed);
             self.background_dirty_flags.fetch_add(1, Ordering::Relaxed);
         }
 
@@ -160,12 +739,12 @@ impl Memory {
         }
 
         else if address >= 0xC000 && address <= 0xDFFF {
-            self.ram[address as usize - 0xC000].store(value, Ordering::Relaxed);
+            self.ram[self.ram_index(address)].store(value, Ordering::Relaxed);
         }
 
         else if address >= 0xE000 && address <= 0xFDFF {
             warn!("Memory: Write to Echo RAM at 0x{:X} with value {:X}", address, value);
-            self.ram[address as usize - 0xE000].store(value, Ordering::Relaxed);
+            self.ram[self.ram_index(address)].store(value, Ordering::Relaxed);
         }
 
         else if address >= 0xFE00 && address <= 0xFE9F {
@@ -177,6 +756,33 @@ impl Memory {
             warn!("Memory: Write to unusable memory at 0x{:X} with value {:X}", address, value);
         }
 
+        else if address == 0xFF00 {
+            // Only the group select bits are writable.
+            let old_lines = self.read_p1() & 0x0F;
+            self.io_registers[0].store(value & 0x30, Ordering::Relaxed);
+            self.check_joypad_interrupt(old_lines);
+
+            if self.is_sgb() {
+                let transfer = self.sgb.lock().unwrap().write_p1(value);
+
+                if let Some(transfer) = transfer {
+                    self.sgb_transfer(transfer);
+                }
+            }
+        }
+
+        else if address == 0xFF01 || address == 0xFF02 {
+            self.serial.lock().unwrap().write(address, value, self.is_cgb());
+        }
+
+        else if address >= 0xFF10 && address <= 0xFF3F {
+            self.apu.lock().unwrap().write(address, value);
+        }
+
+        else if Memory::is_cgb_register(address) {
+            self.write_cgb_register(address, value);
+        }
+
         else if address >= 0xFF00 && address <= 0xFF7F {
 
             if cpu {
@@ -188,6 +794,18 @@ impl Memory {
 
             self.io_registers[address as usize - 0xFF00].store(value, Ordering::Relaxed);
 
+            // Any nonzero write to 0xFF50 unmaps the bootrom until the next reset.
+            if address == 0xFF50 && value != 0 && self.using_bootrom.load(Ordering::Relaxed) {
+                info!("Memory: Bootrom unmapped, executing loaded ROM.");
+                self.using_bootrom.store(false, Ordering::Relaxed);
+
+                if self.is_cgb() && self.io_registers[0x4C].load(Ordering::Relaxed) & 0x0C == 0x04 {
+                    self.cgb_mode.store(false, Ordering::Relaxed);
+                    self.dmg_compat.store(true, Ordering::Relaxed);
+                    info!("Memory: The bootrom switched to DMG compatibility.");
+                }
+            }
+
             if address == 0xFF46 {
                 self.dma_transfer(value);
             }
@@ -202,19 +820,246 @@ impl Memory {
         }
     }
 
+    // Writing to DMA doesn't copy anything right away, the transfer starts one M-cycle later.
+    // If there's a transfer running already it keeps going until the new one takes over.
     fn dma_transfer(&self, value: u8) {
-        let address = (value as u16) << 8;
-        let end_address = address + 0x009F;
+        self.dma_pending_source.store((value as u16) << 8, Ordering::Relaxed);
+        self.dma_delay.store(1, Ordering::Relaxed);
+        self.dma_pending.store(true, Ordering::Relaxed);
+    }
 
-        let mut transfer_progress = (address, 0xFE00);
+    fn sgb_transfer(&self, transfer: Transfer) {
+        let data = self.sgb_transfer_data();
+        self.sgb.lock().unwrap().transfer(transfer, &data);
+    }
+
+    fn read_p1(&self) -> u8 {
+        let select = self.io_registers[0].load(Ordering::Relaxed) & 0x30;
+        let mut pressed = self.joypad_state.load(Ordering::Relaxed);
+
+        // With SGB multiplayer on, nothing selected reads the joypad ID, and only the first joypad has buttons pressed.
+        if self.is_sgb() {
+            if let Some(player) = self.sgb.lock().unwrap().joypad_id() {
+                if select == 0x30 {
+                    return 0xC0 | select | (0x0F - player);
+                }
+
+                if player != 0 {
+                    pressed = 0;
+                }
+            }
+        }
+
+        0xC0 | select | joypad::p1_lines(pressed, select)
+    }
 
-        while transfer_progress.0 < end_address {
-            let value = self.read(transfer_progress.0);
-            self.write(transfer_progress.1, value, true);
-            transfer_progress.0 += 1;
-            transfer_progress.1 += 1;
+    // The joypad interrupt fires when any of the P1 input lines goes from high to low.
+    fn check_joypad_interrupt(&self, old_lines: u8) {
+        let new_lines = self.read_p1() & 0x0F;
+
+        if old_lines & !new_lines != 0 {
+            let if_value = self.io_registers[0x0F].load(Ordering::Relaxed);
+            self.io_registers[0x0F].store(if_value | (1 << 4), Ordering::Relaxed);
+        }
+    }
+
+    pub fn joypad_state(&self) -> u8 {
+        self.joypad_state.load(Ordering::Relaxed)
+    }
+
+    // Replaces the pressed buttons mask, see joypad::Button for the layout.
+    pub fn set_joypad_state(&self, pressed: u8) {
+        let old_lines = self.read_p1() & 0x0F;
+
+        self.joypad_state.store(pressed, Ordering::Relaxed);
+        self.check_joypad_interrupt(old_lines);
+
+        // Like the CGB bootrom: buttons held while it starts win over the palette picked by title.
+        if self.compat_buttons_pending.swap(false, Ordering::Relaxed) {
+            if let Some(palette) = palettes::for_buttons(pressed) {
+                self.store_compatibility_palette(palette);
+            }
         }
     }
+
+    pub fn apu_cycle(&self, cycles: u16) {
+        self.apu.lock().unwrap().apu_cycle(cycles);
+    }
+
+    pub fn serial_cycle(&self, cycles: u16) {
+        if self.serial.lock().unwrap().serial_cycle(cycles) {
+            let if_value = self.read(0xFF0F) | (1 << 3);
+            self.write(0xFF0F, if_value, false);
+        }
+    }
+
+    // False while the serial device needs the CPU to wait.
+    pub fn serial_ready(&self) -> bool {
+        self.serial.lock().unwrap().ready()
+    }
+
+    pub fn set_serial_device(&self, device: Box<dyn SerialDevice>) {
+        self.serial.lock().unwrap().set_device(device);
+    }
+
+    pub fn take_audio_samples(&self) -> Vec<f32> {
+        self.apu.lock().unwrap().take_samples()
+    }
+
+    pub fn start_audio_recording(&self, path: &Path, sample_rate: u32) {
+        match self.apu.lock().unwrap().start_recording(path, sample_rate) {
+            Ok(_) => info!("Memory: Recording audio to {:#?} at {} Hz.", path, sample_rate),
+            Err(error) => error!("Memory: Failed to start audio recording to {:#?}, error {}", path, error),
+        }
+    }
+
+    pub fn stop_audio_recording(&self) {
+        let mut apu = self.apu.lock().unwrap();
+
+        if apu.is_recording() {
+            match apu.stop_recording() {
+                Ok(_) => info!("Memory: Audio recording stopped."),
+                Err(error) => error!("Memory: Failed to finish audio recording, error {}", error),
+            }
+        }
+    }
+
+    pub fn is_recording_audio(&self) -> bool {
+        self.apu.lock().unwrap().is_recording()
+    }
+
+    // Frames are as big as the screen, or as the SGB border around it.
+    pub fn start_video_recording(&self, path: &Path) {
+        let (width, height) = if self.is_sgb() && self.sgb_settings.border {
+            (sgb::BORDER_WIDTH, sgb::BORDER_HEIGHT)
+        }
+        else {
+            (gpu::SCREEN_WIDTH, gpu::SCREEN_HEIGHT)
+        };
+
+        self.stop_video_recording();
+
+        match AviWriter::create(path, width, height, apu::SAMPLE_RATE) {
+            Ok(writer) => {
+                *self.video_recorder.lock().unwrap() = Some(writer);
+                self.apu.lock().unwrap().set_video_recording(true);
+                info!("Memory: Recording video to {:#?}.", path);
+            },
+            Err(error) => error!("Memory: Failed to start video recording to {:#?}, error {}", path, error),
+        }
+    }
+
+    pub fn stop_video_recording(&self) {
+        if let Some(writer) = self.video_recorder.lock().unwrap().take() {
+            self.apu.lock().unwrap().set_video_recording(false);
+
+            match writer.finish() {
+                Ok(_) => info!("Memory: Video recording stopped."),
+                Err(error) => error!("Memory: Failed to finish video recording, error {}", error),
+            }
+        }
+    }
+
+    pub fn is_recording_video(&self) -> bool {
+        self.video_recorder.lock().unwrap().is_some()
+    }
+
+    // Called once per emulated frame, with the audio played since the last one.
+    pub fn record_video_frame(&self, frame: &Frame) {
+        let mut recorder = self.video_recorder.lock().unwrap();
+
+        if let Some(writer) = recorder.as_mut() {
+            let samples = self.apu.lock().unwrap().take_video_samples();
+
+            if let Err(error) = writer.write_frame(frame, &samples) {
+                error!("Memory: Failed to write video frame, error {}. Recording stopped", error);
+                self.apu.lock().unwrap().set_video_recording(false);
+
+                if let Some(writer) = recorder.take() {
+                    if let Err(error) = writer.finish() {
+                        error!("Memory: Failed to finish video recording, error {}", error);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn start_channel_dumps(&self, path: &Path, sample_rate: u32) {
+        let paths = apu::channel_dump_paths(path);
+
+        match self.apu.lock().unwrap().start_channel_dumps(&paths, sample_rate) {
+            Ok(_) => info!("Memory: Dumping audio channels to {:#?} at {} Hz.", paths, sample_rate),
+            Err(error) => error!("Memory: Failed to start channel dumps to {:#?}, error {}", paths, error),
+        }
+    }
+
+    pub fn stop_channel_dumps(&self) {
+        let mut apu = self.apu.lock().unwrap();
+
+        if apu.is_dumping_channels() {
+            match apu.stop_channel_dumps() {
+                Ok(_) => info!("Memory: Channel dumps stopped."),
+                Err(error) => error!("Memory: Failed to finish channel dumps, error {}", error),
+            }
+        }
+    }
+
+    pub fn toggle_channel_mute(&self, channel: usize) -> bool {
+        self.apu.lock().unwrap().toggle_mute(channel)
+    }
+
+    pub fn toggle_channel_solo(&self, channel: usize) -> Option<usize> {
+        self.apu.lock().unwrap().toggle_solo(channel)
+    }
+
+    pub fn audio_channel_states(&self) -> Vec<ChannelState> {
+        self.apu.lock().unwrap().channel_states()
+    }
+
+    // Advances OAM DMA by the amount of cycles the CPU just spent, one byte per M-cycle.
+    pub fn dma_cycle(&self, cycles: u16) {
+        let mut cycles = self.dma_cycles.load(Ordering::Relaxed) + cycles;
+
+        while cycles >= 4 {
+            cycles -= 4;
+
+            if self.dma_pending.load(Ordering::Relaxed) {
+                let delay = self.dma_delay.load(Ordering::Relaxed);
+
+                if delay == 0 {
+                    self.dma_source.store(self.dma_pending_source.load(Ordering::Relaxed), Ordering::Relaxed);
+                    self.dma_index.store(0, Ordering::Relaxed);
+                    self.dma_active.store(true, Ordering::Relaxed);
+                    self.dma_pending.store(false, Ordering::Relaxed);
+                }
+                else {
+                    self.dma_delay.store(delay - 1, Ordering::Relaxed);
+                }
+            }
+
+            if self.dma_active.load(Ordering::Relaxed) {
+                let index = self.dma_index.load(Ordering::Relaxed);
+                let mut source = self.dma_source.load(Ordering::Relaxed) + index;
+
+                // Sources past 0xDFFF end up reading from WRAM, like Echo RAM does.
+                if source >= 0xE000 {
+                    source -= 0x2000;
+                }
+
+                self.oam_mem[index as usize].store(self.read(source), Ordering::Relaxed);
+
+                if index == 159 {
+                    self.dma_active.store(false, Ordering::Relaxed);
+                    self.hash_oam();
+                }
+                else {
+                    self.dma_index.store(index + 1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        self.dma_cycles.store(cycles, Ordering::Relaxed);
+    }
 }
 
 fn new_atomic_vec(size: usize) -> Vec<AtomicU8> {
@@ -0,0 +1,289 @@
+use std::fs;
+use std::io;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use log::{error, info};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use super::palettes;
+use super::palettes::CompatibilitySetting;
+
+
+const MOVIE_MAGIC: &[u8; 4] = b"RBMV";
+const MOVIE_VERSION: u16 = 2;
+
+// What the machine looked like when the movie started.
+#[derive(Clone)]
+pub enum StartState {
+    // Power on, with or without the bootrom, and the cart RAM contents at that point. How DMG games
+    // run and whether SGB games run on an SGB decide which hardware the game sees, so they're kept too.
+    PowerOn { bootrom: bool, cart_ram: Vec<u8>, compatibility: CompatibilitySetting, sgb: bool },
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum MovieMode {
+    Recording,
+    Playing,
+    Finished,
+}
+
+#[derive(Clone, Copy)]
+pub struct MovieInfo {
+    pub mode: MovieMode,
+    pub frame: usize,
+    pub length: usize,
+    pub read_only: bool,
+}
+
+// Movie progress, shared with the frontend for display.
+pub struct MovieStatus {
+    info: Mutex<MovieInfo>,
+}
+
+impl MovieStatus {
+    pub fn info(&self) -> MovieInfo {
+        *self.info.lock().unwrap()
+    }
+}
+
+// Joypad state for every frame, since power on. Frames are counted by the GPU, see Cpu::frame_finished.
+pub struct Movie {
+    path: PathBuf,
+    rom_hash: u64,
+    start_state: StartState,
+
+    inputs: Vec<u8>,
+    position: usize,
+
+    mode: MovieMode,
+    read_only: bool,
+    // Only write the file back if something was recorded.
+    modified: bool,
+
+    status: Arc<MovieStatus>,
+}
+
+impl Movie {
+    pub fn record(path: &Path, rom_hash: u64, start_state: StartState) -> Movie {
+        info!("Movie: Recording to {:#?}", path);
+        Movie::new(path, rom_hash, start_state, Vec::new(), MovieMode::Recording)
+    }
+
+    pub fn load(path: &Path, rom_hash: u64) -> Result<Movie, String> {
+        let data = fs::read(path).map_err(|error| format!("Failed to read {:#?}, error {}", path, error))?;
+        let mut reader = Cursor::new(data);
+
+        let (movie_hash, start_state, inputs) = Movie::parse(&mut reader)
+            .map_err(|error| format!("Failed to parse {:#?}, error {}", path, error))?;
+
+        if movie_hash != rom_hash {
+            return Err(format!("{:#?} was recorded with a different ROM", path));
+        }
+
+        info!("Movie: Playing {:#?}, {} frames", path, inputs.len());
+        Ok(Movie::new(path, rom_hash, start_state, inputs, MovieMode::Playing))
+    }
+
+    fn new(path: &Path, rom_hash: u64, start_state: StartState, inputs: Vec<u8>, mode: MovieMode) -> Movie {
+        let info = MovieInfo {
+            mode,
+            frame: 0,
+            length: inputs.len(),
+            read_only: true,
+        };
+
+        Movie {
+            path: path.to_path_buf(),
+            rom_hash,
+            start_state,
+
+            inputs,
+            position: 0,
+
+            mode,
+            read_only: true,
+            modified: false,
+
+            status: Arc::new(MovieStatus { info: Mutex::new(info) }),
+        }
+    }
+
+    fn parse(reader: &mut Cursor<Vec<u8>>) -> io::Result<(u64, StartState, Vec<u8>)> {
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+
+        if &magic != MOVIE_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a movie file"));
+        }
+
+        let version = reader.read_u16::<LittleEndian>()?;
+
+        if version != MOVIE_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported version {}", version)));
+        }
+
+        let rom_hash = reader.read_u64::<LittleEndian>()?;
+
+        let start_state = match reader.read_u8()? {
+            0 => {
+                let bootrom = reader.read_u8()? != 0;
+                let mut cart_ram = vec![0; reader.read_u32::<LittleEndian>()? as usize];
+                reader.read_exact(&mut cart_ram)?;
+                let compatibility = Movie::read_compatibility(reader)?;
+                let sgb = reader.read_u8()? != 0;
+
+                StartState::PowerOn { bootrom, cart_ram, compatibility, sgb }
+            },
+            kind => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown start state {}", kind))),
+        };
+
+        let mut inputs = vec![0; reader.read_u32::<LittleEndian>()? as usize];
+        reader.read_exact(&mut inputs)?;
+
+        Ok((rom_hash, start_state, inputs))
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut data = Vec::new();
+
+        data.write_all(MOVIE_MAGIC)?;
+        data.write_u16::<LittleEndian>(MOVIE_VERSION)?;
+        data.write_u64::<LittleEndian>(self.rom_hash)?;
+
+        match &self.start_state {
+            StartState::PowerOn { bootrom, cart_ram, compatibility, sgb } => {
+                data.write_u8(0)?;
+                data.write_u8(*bootrom as u8)?;
+                data.write_u32::<LittleEndian>(cart_ram.len() as u32)?;
+                data.write_all(cart_ram)?;
+                Movie::write_compatibility(&mut data, compatibility)?;
+                data.write_u8(*sgb as u8)?;
+            }
+        }
+
+        data.write_u32::<LittleEndian>(self.inputs.len() as u32)?;
+        data.write_all(&self.inputs)?;
+
+        fs::write(&self.path, data)
+    }
+
+    // Off, auto, or a fixed palette followed by its combination number.
+    fn read_compatibility(reader: &mut Cursor<Vec<u8>>) -> io::Result<CompatibilitySetting> {
+        match reader.read_u8()? {
+            0 => Ok(CompatibilitySetting::Off),
+            1 => Ok(CompatibilitySetting::Auto),
+            2 => {
+                let combination = reader.read_u8()?;
+                palettes::by_combination(combination as usize)
+                    .map(CompatibilitySetting::Palette)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("unknown palette combination {}", combination)))
+            },
+            kind => Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown DMG compatibility setting {}", kind))),
+        }
+    }
+
+    fn write_compatibility(data: &mut Vec<u8>, compatibility: &CompatibilitySetting) -> io::Result<()> {
+        match compatibility {
+            CompatibilitySetting::Off => data.write_u8(0),
+            CompatibilitySetting::Auto => data.write_u8(1),
+            CompatibilitySetting::Palette(palette) => {
+                data.write_u8(2)?;
+                data.write_u8(palette.combination as u8)
+            },
+        }
+    }
+
+    pub fn start_state(&self) -> &StartState {
+        &self.start_state
+    }
+
+    pub fn status(&self) -> Arc<MovieStatus> {
+        Arc::clone(&self.status)
+    }
+
+    // Called once per frame with the live input, returns the buttons the joypad should see.
+    pub fn next_frame(&mut self, live: u8) -> u8 {
+        // Pressing anything during read-write playback takes over, the rest of the movie gets recorded again.
+        if self.mode == MovieMode::Playing && !self.read_only && live != 0 {
+            info!("Movie: Taking over at frame {}, recording from here", self.position);
+            self.inputs.truncate(self.position);
+            self.mode = MovieMode::Recording;
+        }
+
+        let buttons = match self.mode {
+            MovieMode::Recording => {
+                self.inputs.push(live);
+                self.position += 1;
+                self.modified = true;
+                live
+            },
+            MovieMode::Playing => match self.inputs.get(self.position) {
+                Some(buttons) => {
+                    self.position += 1;
+                    *buttons
+                },
+                None => {
+                    info!("Movie: Playback finished after {} frames", self.position);
+                    self.mode = MovieMode::Finished;
+                    live
+                }
+            },
+            MovieMode::Finished => live,
+        };
+
+        self.publish();
+        buttons
+    }
+
+    // Loading a state moves the movie to the frame the state was saved at. Outside of read-only playback
+    // recording continues from there, which is how a movie gets re-recorded.
+    pub fn state_loaded(&mut self, frame: u64) {
+        let frame = frame as usize;
+
+        if self.mode != MovieMode::Recording && self.read_only {
+            self.position = frame.min(self.inputs.len());
+            self.mode = if frame < self.inputs.len() {MovieMode::Playing} else {MovieMode::Finished};
+        }
+        else {
+            self.inputs.truncate(frame);
+            self.position = self.inputs.len();
+            self.mode = MovieMode::Recording;
+            self.modified = true;
+        }
+
+        self.publish();
+    }
+
+    pub fn toggle_read_only(&mut self) {
+        self.read_only = !self.read_only;
+        info!("Movie: {}", if self.read_only {"Read-only"} else {"Read-write, input will take over playback"});
+        self.publish();
+    }
+
+    pub fn finish(&mut self) {
+        self.mode = MovieMode::Finished;
+        self.publish();
+
+        if !self.modified {
+            return;
+        }
+
+        match self.save() {
+            Ok(_) => info!("Movie: Saved {} frames to {:#?}", self.inputs.len(), self.path),
+            Err(error) => error!("Movie: Failed to save {:#?}, error {}", self.path, error),
+        }
+
+        self.modified = false;
+    }
+
+    fn publish(&self) {
+        *self.status.info.lock().unwrap() = MovieInfo {
+            mode: self.mode,
+            frame: self.position,
+            length: self.inputs.len(),
+            read_only: self.read_only,
+        };
+    }
+}
@@ -0,0 +1,140 @@
+use std::env;
+use std::path::PathBuf;
+
+use super::apu;
+use super::config::Config;
+use super::serial::SerialConfig;
+
+
+pub struct Options {
+    pub rom_path: Option<PathBuf>,
+    pub config: Option<PathBuf>,
+
+    // Run without a window or audio device, for regression tests.
+    pub headless: bool,
+    // Stop emulation after this many frames.
+    pub frames: Option<u64>,
+
+    pub record_audio: Option<PathBuf>,
+    pub record_sample_rate: u32,
+    pub dump_channels: Option<PathBuf>,
+    pub record_video: Option<PathBuf>,
+
+    pub record_movie: Option<PathBuf>,
+    pub play_movie: Option<PathBuf>,
+
+    // Frame number and path of a screenshot to save, for regression tests.
+    pub screenshot_at_frame: Option<(u64, PathBuf)>,
+
+    // What's plugged into the link port.
+    pub serial: SerialConfig,
+    // Second Game Boy in the same window, linked to the first one.
+    pub link_local: Option<PathBuf>,
+}
+
+impl Options {
+    pub fn from_args() -> Result<Options, String> {
+        let mut options = Options {
+            rom_path: None,
+            config: None,
+
+            headless: false,
+            frames: None,
+
+            record_audio: None,
+            record_sample_rate: apu::SAMPLE_RATE,
+            dump_channels: None,
+            record_video: None,
+
+            record_movie: None,
+            play_movie: None,
+
+            screenshot_at_frame: None,
+
+            serial: SerialConfig::Stdout,
+            link_local: None,
+        };
+
+        let mut args = env::args().skip(1);
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => options.config = Some(Options::parse_path(&arg, args.next())?),
+                "--headless" => options.headless = true,
+                "--frames" => options.frames = Some(Options::parse_number(&arg, args.next())?),
+                "--record-audio" => options.record_audio = Some(Options::parse_path(&arg, args.next())?),
+                "--record-sample-rate" => options.record_sample_rate = Options::parse_number(&arg, args.next())?,
+                "--dump-channels" => options.dump_channels = Some(Options::parse_path(&arg, args.next())?),
+                "--record-video" => options.record_video = Some(Options::parse_path(&arg, args.next())?),
+                "--record-movie" => options.record_movie = Some(Options::parse_path(&arg, args.next())?),
+                "--play-movie" => options.play_movie = Some(Options::parse_path(&arg, args.next())?),
+                "--screenshot-at-frame" => {
+                    let frame = Options::parse_number(&arg, args.next())?;
+                    options.screenshot_at_frame = Some((frame, Options::parse_path(&arg, args.next())?));
+                },
+                "--serial" => options.serial = SerialConfig::parse(&args.next().ok_or(format!("Missing device for {}", arg))?)?,
+                "--link-local" => options.link_local = Some(Options::parse_path(&arg, args.next())?),
+                _ => {
+                    if arg.starts_with("--") || options.rom_path.is_some() {
+                        return Err(format!("Unexpected argument {}", arg));
+                    }
+
+                    options.rom_path = Some(PathBuf::from(arg));
+                }
+            }
+        }
+
+        if options.headless && options.rom_path.is_none() {
+            return Err(String::from("A ROM path is required in headless mode"));
+        }
+
+        if options.record_movie.is_some() && options.play_movie.is_some() {
+            return Err(String::from("Can't record and play a movie at the same time"));
+        }
+
+        // Past these the WAV sizes and the resampling math overflow, and 0 records nothing.
+        if !(8000..=192000).contains(&options.record_sample_rate) {
+            return Err(format!("The recording sample rate has to be between 8000 and 192000 Hz, not {}", options.record_sample_rate));
+        }
+
+        if let (Some((frame, _)), Some(frames)) = (&options.screenshot_at_frame, options.frames) {
+            if *frame == 0 || *frame > frames {
+                return Err(format!("The screenshot frame has to be between 1 and {}, the last frame before stopping", frames));
+            }
+        }
+
+        if options.link_local.is_some() {
+            // The port is taken by the other Game Boy, and a movie only holds one joypad.
+            if options.serial != SerialConfig::Stdout {
+                return Err(String::from("--link-local already plugs the other Game Boy into the serial port, --serial can't be used with it"));
+            }
+
+            if options.record_movie.is_some() || options.play_movie.is_some() {
+                return Err(String::from("Movies can't be recorded or played with --link-local"));
+            }
+        }
+
+        Ok(options)
+    }
+
+    pub fn config_path(&self) -> PathBuf {
+        match &self.config {
+            Some(path) => path.clone(),
+            None => Config::default_path(),
+        }
+    }
+
+    fn parse_path(arg: &str, value: Option<String>) -> Result<PathBuf, String> {
+        match value {
+            Some(value) => Ok(PathBuf::from(value)),
+            None => Err(format!("Missing path for {}", arg)),
+        }
+    }
+
+    fn parse_number<T: std::str::FromStr>(arg: &str, value: Option<String>) -> Result<T, String> {
+        match value {
+            Some(value) => value.parse().map_err(|_| format!("Invalid value {} for {}", value, arg)),
+            None => Err(format!("Missing value for {}", arg)),
+        }
+    }
+}
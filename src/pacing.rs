@@ -0,0 +1,187 @@
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use super::apu;
+use super::config::SpeedConfig;
+
+
+// 70224 cycles per frame at 4194304 Hz, about 59.73 frames per second.
+const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+
+// How much audio the frontend should have queued at normal speed.
+const TARGET_AUDIO_BACKLOG: Duration = Duration::from_millis(60);
+const AUDIO_BACKLOG_TOLERANCE: Duration = Duration::from_millis(20);
+// Small enough to not be heard, big enough to absorb clock drift between the audio device and the system.
+const AUDIO_SYNC_STEP: Duration = Duration::from_micros(500);
+
+// Past this, the time is gone instead of being caught up with a burst of frames.
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct SpeedSettings {
+    // Speed multiplier while fast forwarding, None for as fast as possible.
+    pub fast_forward: Option<f32>,
+    pub slow_motion: f32,
+}
+
+// Shared between the CPU thread, which does the pacing, and the frontend.
+pub struct PacingStatus {
+    audio_enabled: AtomicBool,
+    // Stereo samples waiting in the audio device queue.
+    audio_queued: AtomicU32,
+    paused: AtomicBool,
+}
+
+impl PacingStatus {
+    pub fn new() -> PacingStatus {
+        PacingStatus {
+            audio_enabled: AtomicBool::new(false),
+            audio_queued: AtomicU32::new(0),
+            paused: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_audio_queued(&self, samples: u32) {
+        self.audio_enabled.store(true, Ordering::Relaxed);
+        self.audio_queued.store(samples, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    fn audio_backlog(&self) -> Option<Duration> {
+        if self.audio_enabled.load(Ordering::Relaxed) {
+            let samples = self.audio_queued.load(Ordering::Relaxed) as u64;
+            Some(Duration::from_micros(samples * 1_000_000 / apu::SAMPLE_RATE as u64))
+        }
+        else {
+            None
+        }
+    }
+}
+
+// Keeps emulation at the Game Boy frame rate, sleeping at the end of every frame.
+pub struct Pacer {
+    // Off in headless mode, where emulation runs as fast as it can.
+    realtime: bool,
+    settings: SpeedSettings,
+
+    fast_forward: bool,
+    slow_motion: bool,
+    paused: bool,
+    // Runs a single frame while paused.
+    advancing: bool,
+
+    next_frame: Instant,
+    status: Arc<PacingStatus>,
+}
+
+impl Pacer {
+    pub fn new(status: Arc<PacingStatus>) -> Pacer {
+        Pacer {
+            realtime: true,
+            // Until the config's settings come in.
+            settings: SpeedConfig::default().settings(),
+
+            fast_forward: false,
+            slow_motion: false,
+            paused: false,
+            advancing: false,
+
+            next_frame: Instant::now(),
+            status,
+        }
+    }
+
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime;
+    }
+
+    pub fn configure(&mut self, settings: SpeedSettings) {
+        self.settings = settings;
+    }
+
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+    }
+
+    pub fn toggle_slow_motion(&mut self) {
+        self.slow_motion = !self.slow_motion;
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.advancing = false;
+        self.next_frame = Instant::now();
+        self.status.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn advance_frame(&mut self) {
+        if self.paused {
+            self.advancing = true;
+        }
+    }
+
+    // Whether the CPU should keep running.
+    pub fn is_running(&self) -> bool {
+        !self.paused || self.advancing
+    }
+
+    fn speed(&self) -> Option<f32> {
+        if self.fast_forward {
+            self.settings.fast_forward
+        }
+        else if self.slow_motion {
+            Some(self.settings.slow_motion)
+        }
+        else {
+            Some(1.0)
+        }
+    }
+
+    pub fn frame_finished(&mut self) {
+        if self.advancing {
+            self.advancing = false;
+            return;
+        }
+
+        let speed = match self.speed() {
+            Some(speed) if self.realtime && speed > 0.0 => speed,
+            _ => {
+                self.next_frame = Instant::now();
+                return;
+            }
+        };
+
+        self.next_frame += FRAME_DURATION.div_f32(speed);
+
+        // At normal speed the audio device is the clock. Nudge the deadline so its queue stays
+        // around the target, instead of slowly running dry or piling up latency.
+        if speed == 1.0 {
+            if let Some(backlog) = self.status.audio_backlog() {
+                if backlog > TARGET_AUDIO_BACKLOG + AUDIO_BACKLOG_TOLERANCE {
+                    self.next_frame += AUDIO_SYNC_STEP;
+                }
+                else if backlog + AUDIO_BACKLOG_TOLERANCE < TARGET_AUDIO_BACKLOG {
+                    self.next_frame -= AUDIO_SYNC_STEP;
+                }
+            }
+        }
+
+        let now = Instant::now();
+
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        }
+        else if now - self.next_frame > MAX_LAG {
+            self.next_frame = now;
+        }
+    }
+}
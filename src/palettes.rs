@@ -0,0 +1,249 @@
+use super::joypad::Button;
+
+
+// The palettes in the CGB bootrom, RGB555 like palette RAM.
+const PALETTES: [[u16; 4]; 30] = [
+    [0x7FFF, 0x32BF, 0x00D0, 0x0000],
+    [0x639F, 0x4279, 0x15B0, 0x04CB],
+    [0x7FFF, 0x6E31, 0x454A, 0x0000],
+    [0x7FFF, 0x1BEF, 0x0200, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x5294, 0x294A, 0x0000],
+    [0x7FFF, 0x03FF, 0x012F, 0x0000],
+    [0x7FFF, 0x03EF, 0x01D6, 0x0000],
+    [0x7FFF, 0x42B5, 0x3DC8, 0x0000],
+    [0x7E74, 0x03FF, 0x0180, 0x0000],
+    [0x67FF, 0x77AC, 0x1A13, 0x2D6B],
+    [0x7ED6, 0x4BFF, 0x2175, 0x0000],
+    [0x53FF, 0x4A5F, 0x7E52, 0x0000],
+    [0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0],
+    [0x03ED, 0x7FFF, 0x255F, 0x0000],
+    [0x036A, 0x021F, 0x03FF, 0x7FFF],
+    [0x7FFF, 0x01DF, 0x0112, 0x0000],
+    [0x231F, 0x035F, 0x00F2, 0x0009],
+    [0x7FFF, 0x03EA, 0x011F, 0x0000],
+    [0x299F, 0x001A, 0x000C, 0x0000],
+    [0x7FFF, 0x027F, 0x001F, 0x0000],
+    [0x7FFF, 0x03E0, 0x0206, 0x0120],
+    [0x7FFF, 0x7EEB, 0x001F, 0x7C00],
+    [0x7FFF, 0x3FFF, 0x7E00, 0x001F],
+    [0x7FFF, 0x03FF, 0x001F, 0x0000],
+    [0x03FF, 0x001F, 0x000C, 0x0000],
+    [0x7FFF, 0x033F, 0x0193, 0x0000],
+    [0x0000, 0x4200, 0x037F, 0x7FFF],
+    [0x7FFF, 0x7E8C, 0x7C00, 0x0000],
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+];
+
+// Where OBJ0, OBJ1 and BG take their 4 colors from, counted in colors from the start of the
+// palettes above. Most start on a palette, a few start in the middle of one like in the bootrom.
+const fn combination(obj0: usize, obj1: usize, background: usize) -> [usize; 3] {
+    [obj0 * 4, obj1 * 4, background * 4]
+}
+
+const fn raw_combination(obj0: usize, obj1: usize, background: usize) -> [usize; 3] {
+    [obj0, obj1, background]
+}
+
+const COMBINATIONS: [[usize; 3]; 51] = [
+    combination(4, 4, 29),
+    combination(18, 18, 18),
+    combination(20, 20, 20),
+    combination(24, 24, 24),
+    combination(9, 9, 9),
+    combination(0, 0, 0),
+    combination(27, 27, 27),
+    combination(5, 5, 5),
+    combination(12, 12, 12),
+    combination(26, 26, 26),
+    combination(16, 8, 8),
+    combination(4, 28, 28),
+    combination(4, 2, 2),
+    combination(3, 4, 4),
+    combination(4, 29, 29),
+    combination(28, 4, 28),
+    combination(2, 17, 2),
+    combination(16, 16, 8),
+    combination(4, 4, 7),
+    combination(4, 4, 18),
+    combination(4, 4, 20),
+    combination(19, 19, 9),
+    raw_combination(4 * 4 - 1, 4 * 4 - 1, 11 * 4),
+    combination(17, 17, 2),
+    combination(4, 4, 2),
+    combination(4, 4, 3),
+    combination(28, 28, 0),
+    combination(3, 3, 0),
+    combination(0, 0, 1),
+    combination(18, 22, 18),
+    combination(20, 22, 20),
+    combination(24, 22, 24),
+    combination(16, 22, 8),
+    combination(17, 4, 13),
+    raw_combination(28 * 4 - 1, 0, 14 * 4),
+    raw_combination(28 * 4 - 1, 4 * 4, 15 * 4),
+    combination(19, 22, 9),
+    combination(16, 28, 10),
+    combination(4, 23, 28),
+    combination(17, 22, 2),
+    combination(4, 0, 2),
+    combination(4, 28, 3),
+    combination(28, 3, 0),
+    combination(3, 28, 4),
+    combination(21, 28, 4),
+    combination(3, 28, 0),
+    combination(25, 3, 28),
+    combination(0, 28, 8),
+    combination(4, 3, 28),
+    combination(28, 3, 6),
+    combination(4, 28, 29),
+];
+
+// Title checksums of the games the bootrom knows. Checksums from FIRST_DUPLICATE on are shared by
+// several games, those also have to match the fourth letter of the title.
+const TITLE_CHECKSUMS: [u8; 94] = [
+    0x00, 0x88, 0x16, 0x36, 0xD1, 0xDB, 0xF2, 0x3C, 0x8C, 0x92, 0x3D, 0x5C, 0x58, 0xC9, 0x3E, 0x70,
+    0x1D, 0x59, 0x69, 0x19, 0x35, 0xA8, 0x14, 0xAA, 0x75, 0x95, 0x99, 0x34, 0x6F, 0x15, 0xFF, 0x97,
+    0x4B, 0x90, 0x17, 0x10, 0x39, 0xF7, 0xF6, 0xA2, 0x49, 0x4E, 0x43, 0x68, 0xE0, 0x8B, 0xF0, 0xCE,
+    0x0C, 0x29, 0xE8, 0xB7, 0x86, 0x9A, 0x52, 0x01, 0x9D, 0x71, 0x9C, 0xBD, 0x5D, 0x6D, 0x67, 0x3F,
+    0x6B,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3, 0x46, 0x28, 0xA5, 0xC6, 0xD3, 0x27, 0x61, 0x18, 0x66, 0x6A, 0xBF, 0x0D, 0xF4,
+    0xB3,
+];
+
+const FIRST_DUPLICATE: usize = 65;
+const DUPLICATE_LETTERS: &[u8; 29] = b"BEFAARBEKEK R-URAR INAILICE R";
+
+// The combination for every checksum above. The first one is the default, for unknown games.
+const CHECKSUM_COMBINATIONS: [u8; 94] = [
+    0, 4, 5, 35, 34, 3, 31, 15, 10, 5, 19, 36, 7, 37, 30, 44,
+    21, 32, 31, 20, 5, 33, 13, 14, 5, 29, 5, 18, 9, 3, 2, 26,
+    25, 25, 41, 42, 26, 45, 42, 45, 36, 38, 26, 42, 30, 41, 34, 34,
+    5, 42, 6, 5, 33, 25, 42, 42, 40, 2, 16, 25, 42, 42, 5, 0,
+    39,
+    36, 32, 25, 6, 32, 12, 36, 11, 39, 18, 39, 24, 31, 50,
+    17, 46, 6, 27, 0, 47, 41, 41, 0, 0, 19, 34, 23, 18,
+    29,
+];
+
+// Held on the bootrom's logo: a direction, alone or with A or B.
+const BUTTON_DIRECTIONS: [Button; 4] = [Button::Up, Button::Left, Button::Down, Button::Right];
+const BUTTON_COMBINATIONS: [[usize; 3]; 4] = [
+    [5, 43, 28],
+    [48, 40, 7],
+    [8, 3, 49],
+    [1, 0, 6],
+];
+
+// The ones that can be picked by name in the config, the button combinations and a few games.
+const NAMED_COMBINATIONS: [(&str, usize); 16] = [
+    ("brown", 5),
+    ("red", 43),
+    ("dark_brown", 28),
+    ("blue", 48),
+    ("dark_blue", 40),
+    ("grayscale", 7),
+    ("pastel", 8),
+    ("orange", 3),
+    ("yellow", 49),
+    ("green", 1),
+    ("dark_green", 0),
+    ("inverted", 6),
+    ("pokemon_red", 13),
+    ("pokemon_blue", 11),
+    ("pokemon_green", 14),
+    ("super_mario_land", 32),
+];
+
+// Colors the CGB bootrom gives a DMG game: one palette for the background and one for each
+// sprite palette. The game's BGP, OBP0 and OBP1 then pick from these instead of the DMG shades.
+#[derive(Clone, Copy)]
+pub struct CompatibilityPalette {
+    pub combination: usize,
+    pub background: [u16; 4],
+    pub sprites: [[u16; 4]; 2],
+}
+
+impl CompatibilityPalette {
+    fn new(combination: usize) -> CompatibilityPalette {
+        let [obj0, obj1, background] = COMBINATIONS[combination];
+
+        CompatibilityPalette {
+            combination,
+            background: colors(background),
+            sprites: [colors(obj0), colors(obj1)],
+        }
+    }
+}
+
+fn colors(start: usize) -> [u16; 4] {
+    let mut colors = [0; 4];
+
+    for (index, color) in colors.iter_mut().enumerate() {
+        let position = start + index;
+        *color = PALETTES[position / 4][position % 4];
+    }
+
+    colors
+}
+
+// How DMG games are run, set in the config.
+#[derive(Clone, Copy)]
+pub enum CompatibilitySetting {
+    // On a DMG, in shades of gray.
+    Off,
+    // On a CGB, with the palette its bootrom would pick.
+    Auto,
+    // On a CGB, always with the same palette.
+    Palette(CompatibilityPalette),
+}
+
+impl CompatibilitySetting {
+    pub fn parse(value: &str) -> Option<CompatibilitySetting> {
+        match value {
+            "off" => Some(CompatibilitySetting::Off),
+            "auto" => Some(CompatibilitySetting::Auto),
+            name => by_name(name).map(CompatibilitySetting::Palette),
+        }
+    }
+}
+
+pub fn by_name(name: &str) -> Option<CompatibilityPalette> {
+    NAMED_COMBINATIONS.iter()
+        .find(|(combination_name, _)| *combination_name == name)
+        .map(|(_, combination)| CompatibilityPalette::new(*combination))
+}
+
+// Movies store the palette by its combination number.
+pub fn by_combination(combination: usize) -> Option<CompatibilityPalette> {
+    if combination < COMBINATIONS.len() {Some(CompatibilityPalette::new(combination))} else {None}
+}
+
+// The title checksum only counts for games by Nintendo, see CartData::title_checksum.
+pub fn for_title(checksum: Option<(u8, u8)>) -> CompatibilityPalette {
+    let index = checksum.and_then(|(checksum, fourth_letter)| {
+        TITLE_CHECKSUMS.iter().enumerate().position(|(index, title_checksum)| {
+            *title_checksum == checksum && (index < FIRST_DUPLICATE || DUPLICATE_LETTERS[index - FIRST_DUPLICATE] == fourth_letter)
+        })
+    });
+
+    CompatibilityPalette::new(CHECKSUM_COMBINATIONS[index.unwrap_or(0)] as usize)
+}
+
+// A takes precedence over B, and Up over the other directions.
+pub fn for_buttons(pressed: u8) -> Option<CompatibilityPalette> {
+    let direction = BUTTON_DIRECTIONS.iter().position(|button| pressed & button.mask() != 0)?;
+
+    let modifier = if pressed & Button::A.mask() != 0 {
+        1
+    }
+    else if pressed & Button::B.mask() != 0 {
+        2
+    }
+    else {
+        0
+    };
+
+    Some(CompatibilityPalette::new(BUTTON_COMBINATIONS[direction][modifier]))
+}
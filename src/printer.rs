@@ -0,0 +1,324 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+
+use super::serial::SerialDevice;
+
+
+pub const DEFAULT_PRINTER_DIR: &str = "printouts";
+
+// The paper is as wide as the screen, 20 tiles. A data packet holds up to two rows of tiles.
+const PAPER_WIDTH: usize = 160;
+const TILE_BYTES: usize = 16;
+// 8 KiB of image memory, enough for 9 data packets.
+const BUFFER_SIZE: usize = 0x2000;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_STATUS: u8 = 0x0F;
+
+const STATUS_CHECKSUM_ERROR: u8 = 1;
+const STATUS_BUSY: u8 = 1 << 1;
+const STATUS_FULL: u8 = 1 << 2;
+const STATUS_UNPROCESSED: u8 = 1 << 3;
+
+// Status packets that report the printer as busy after a print. Games wait for it to finish,
+// some only after they've seen it start.
+const BUSY_POLLS: u8 = 4;
+
+// Paper shades, from white to black.
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+// Where the next byte goes in a packet.
+#[derive(Clone, Copy, PartialEq)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    // The printer answers 0x81 on these two bytes, and then its status.
+    Alive,
+    Status,
+}
+
+// Game Boy Printer. Every packet goes 0x88 0x33, command, compression flag, data length, data,
+// checksum, and two more bytes the printer answers with 0x81 and its status.
+//
+// Printed lines are kept until a print leaves a margin after them, like the tear-off edge on
+// the real paper, and then saved as a PNG file.
+pub struct Printer {
+    directory: PathBuf,
+
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+
+    status: u8,
+    busy_polls: u8,
+
+    // Decompressed tile data, waiting for a print command.
+    buffer: Vec<u8>,
+    // Printed lines, one shade per pixel.
+    paper: Vec<u8>,
+    printed: usize,
+}
+
+impl Printer {
+    pub fn new(directory: &Path) -> Printer {
+        info!("Printer: Printouts will be saved to {:#?}", directory);
+
+        Printer {
+            directory: directory.to_path_buf(),
+
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+
+            status: 0,
+            busy_polls: 0,
+
+            buffer: Vec::with_capacity(BUFFER_SIZE),
+            paper: Vec::new(),
+            printed: 0,
+        }
+    }
+
+    // Returns what the printer shifts out while receiving the byte.
+    fn receive(&mut self, byte: u8) -> u8 {
+        match self.state {
+            PacketState::Magic1 => {
+                if byte == 0x88 {
+                    self.state = PacketState::Magic2;
+                }
+            },
+            PacketState::Magic2 => self.state = if byte == 0x33 {PacketState::Command} else {PacketState::Magic1},
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                self.state = PacketState::Compression;
+            },
+            PacketState::Compression => {
+                self.compressed = byte & 1 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.state = PacketState::LengthLow;
+            },
+            PacketState::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.state = PacketState::LengthHigh;
+            },
+            PacketState::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                self.state = if self.length > 0 {PacketState::Data} else {PacketState::ChecksumLow};
+            },
+            PacketState::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+
+                if self.data.len() == self.length as usize {
+                    self.state = PacketState::ChecksumLow;
+                }
+            },
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                self.state = PacketState::ChecksumHigh;
+            },
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                self.state = PacketState::Alive;
+            },
+            PacketState::Alive => {
+                // Handled here, so the status that follows already reflects the packet.
+                if self.received_checksum == self.checksum {
+                    self.status &= !STATUS_CHECKSUM_ERROR;
+                    self.run_command();
+                }
+                else {
+                    warn!("Printer: Checksum mismatch on command {:#04X}, ignoring the packet", self.command);
+                    self.status |= STATUS_CHECKSUM_ERROR;
+                }
+
+                self.state = PacketState::Status;
+                return 0x81;
+            },
+            PacketState::Status => {
+                self.state = PacketState::Magic1;
+                return self.status;
+            },
+        }
+
+        0x00
+    }
+
+    fn run_command(&mut self) {
+        match self.command {
+            COMMAND_INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.busy_polls = 0;
+            },
+            COMMAND_DATA => {
+                // An empty data packet ends the image, there's nothing else to do with it.
+                if !self.data.is_empty() {
+                    let data = std::mem::take(&mut self.data);
+
+                    if self.compressed {
+                        self.decompress(&data);
+                    }
+                    else {
+                        self.buffer.extend_from_slice(&data);
+                    }
+
+                    self.buffer.truncate(BUFFER_SIZE);
+                    self.status |= STATUS_UNPROCESSED;
+
+                    if self.buffer.len() == BUFFER_SIZE {
+                        self.status |= STATUS_FULL;
+                    }
+                }
+            },
+            COMMAND_PRINT => {
+                if self.data.len() < 4 {
+                    warn!("Printer: Print command with only {} bytes of arguments, ignoring it", self.data.len());
+                    return;
+                }
+
+                let (sheets, margins, palette) = (self.data[0], self.data[1], self.data[2]);
+
+                // No sheets only feeds the paper.
+                if sheets > 0 {
+                    self.print(palette);
+                }
+
+                self.buffer.clear();
+                self.status = (self.status & !(STATUS_UNPROCESSED | STATUS_FULL)) | STATUS_BUSY;
+                self.busy_polls = BUSY_POLLS;
+
+                if margins & 0x0F != 0 {
+                    self.cut_paper();
+                }
+            },
+            COMMAND_STATUS => {
+                if self.busy_polls > 0 {
+                    self.busy_polls -= 1;
+
+                    if self.busy_polls == 0 {
+                        self.status &= !STATUS_BUSY;
+                    }
+                }
+            },
+            command => warn!("Printer: Unknown command {:#04X}, ignoring it", command),
+        }
+    }
+
+    // Runs start with a control byte. With bit 7 set the next byte repeats (control & 0x7F) + 2
+    // times, otherwise (control + 1) bytes follow as they are.
+    fn decompress(&mut self, data: &[u8]) {
+        let mut index = 0;
+
+        while index < data.len() {
+            let control = data[index];
+            index += 1;
+
+            if control & 0x80 != 0 {
+                if let Some(byte) = data.get(index) {
+                    let count = (control & 0x7F) as usize + 2;
+                    self.buffer.resize(self.buffer.len() + count, *byte);
+                }
+
+                index += 1;
+            }
+            else {
+                let end = (index + control as usize + 1).min(data.len());
+                self.buffer.extend_from_slice(&data[index..end]);
+                index = end;
+            }
+        }
+    }
+
+    // Tiles come in rows of 20, each palette entry picks the shade for a color.
+    fn print(&mut self, palette: u8) {
+        let rows = self.buffer.len() / (TILE_BYTES * PAPER_WIDTH / 8);
+
+        for tile_row in 0..rows {
+            for line in 0..8 {
+                for tile in 0..PAPER_WIDTH / 8 {
+                    let offset = (tile_row * PAPER_WIDTH / 8 + tile) * TILE_BYTES + line * 2;
+                    let (low, high) = (self.buffer[offset], self.buffer[offset + 1]);
+
+                    for bit in (0..8).rev() {
+                        let color = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+                        let shade = (palette >> (color * 2)) & 3;
+                        self.paper.push(SHADES[shade as usize]);
+                    }
+                }
+            }
+        }
+    }
+
+    // Saves whatever has been printed since the last cut.
+    fn cut_paper(&mut self) {
+        if self.paper.is_empty() {
+            return;
+        }
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        let path = self.directory.join(format!("print_{}_{}.png", timestamp, self.printed));
+
+        match self.save_png(&path) {
+            Ok(_) => info!("Printer: Saved a {} pixel tall printout to {:#?}", self.paper.len() / PAPER_WIDTH, path),
+            Err(error) => error!("Printer: Failed to save {:#?}, error {}", path, error),
+        }
+
+        self.paper.clear();
+        self.printed += 1;
+    }
+
+    fn save_png(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+
+        let file = BufWriter::new(File::create(path)?);
+        let mut encoder = png::Encoder::new(file, PAPER_WIDTH as u32, (self.paper.len() / PAPER_WIDTH) as u32);
+        encoder.set_color(png::ColorType::Grayscale);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.paper)?;
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+// Games that never leave a margin would lose their last printout on exit otherwise.
+impl Drop for Printer {
+    fn drop(&mut self) {
+        self.cut_paper();
+    }
+}
+
+impl SerialDevice for Printer {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        self.receive(byte)
+    }
+}
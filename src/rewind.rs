@@ -0,0 +1,195 @@
+use std::collections::VecDeque;
+
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct RewindSettings {
+    pub enabled: bool,
+    // Frames between snapshots.
+    pub interval: u32,
+    // Maximum number of snapshots kept.
+    pub length: usize,
+    // Maximum size of all snapshots together, in bytes.
+    pub memory_budget: usize,
+}
+
+// Save states taken every few frames. Only the newest one is kept whole, every older one is stored
+// as the difference to the one after it, so going back means undoing one difference at a time.
+pub struct RewindBuffer {
+    settings: RewindSettings,
+
+    newest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+    used_bytes: usize,
+
+    frames_since_snapshot: u32,
+}
+
+impl RewindBuffer {
+    pub fn new(settings: RewindSettings) -> RewindBuffer {
+        RewindBuffer {
+            settings,
+
+            newest: None,
+            deltas: VecDeque::new(),
+            used_bytes: 0,
+
+            frames_since_snapshot: 0,
+        }
+    }
+
+    pub fn configure(&mut self, settings: RewindSettings) {
+        self.settings = settings;
+
+        if !settings.enabled {
+            self.clear();
+        }
+
+        self.trim();
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.deltas.clear();
+        self.used_bytes = 0;
+    }
+
+    // Called at the end of every frame, returns true if a snapshot should be taken.
+    pub fn frame_finished(&mut self) -> bool {
+        if !self.settings.enabled {
+            return false;
+        }
+
+        self.frames_since_snapshot += 1;
+
+        if self.frames_since_snapshot >= self.interval() {
+            self.frames_since_snapshot = 0;
+            return true;
+        }
+
+        false
+    }
+
+    pub fn interval(&self) -> u32 {
+        self.settings.interval.max(1)
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.newest.take() {
+            let delta = encode_delta(&state, &newest);
+
+            self.used_bytes += delta.len();
+            self.used_bytes -= newest.len();
+            self.deltas.push_back(delta);
+        }
+
+        self.used_bytes += state.len();
+        self.newest = Some(state);
+        self.trim();
+    }
+
+    // Takes the newest snapshot out, the one before it becomes the newest.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        let newest = self.newest.take()?;
+        self.used_bytes -= newest.len();
+
+        if let Some(delta) = self.deltas.pop_back() {
+            let previous = apply_delta(&newest, &delta);
+
+            self.used_bytes -= delta.len();
+            self.used_bytes += previous.len();
+            self.newest = Some(previous);
+        }
+
+        Some(newest)
+    }
+
+    // Drops the oldest snapshots until both limits are respected. The newest one always stays.
+    fn trim(&mut self) {
+        while self.deltas.len() + 1 > self.settings.length.max(1) || (self.used_bytes > self.settings.memory_budget && !self.deltas.is_empty()) {
+            match self.deltas.pop_front() {
+                Some(delta) => self.used_bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+// The delta is the XOR of both snapshots, with runs of unchanged bytes left out. It's made of
+// the target length, followed by (unchanged run, changed run, changed bytes) groups.
+fn encode_delta(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut index = 0;
+
+    write_varint(&mut delta, to.len());
+
+    while index < to.len() {
+        let unchanged_start = index;
+        while index < to.len() && to[index] == byte_at(from, index) {
+            index += 1;
+        }
+
+        let changed_start = index;
+        while index < to.len() && to[index] != byte_at(from, index) {
+            index += 1;
+        }
+
+        write_varint(&mut delta, changed_start - unchanged_start);
+        write_varint(&mut delta, index - changed_start);
+
+        delta.extend((changed_start..index).map(|position| to[position] ^ byte_at(from, position)));
+    }
+
+    delta
+}
+
+fn apply_delta(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_varint(delta, &mut position);
+
+    let mut result: Vec<u8> = (0..length).map(|index| byte_at(from, index)).collect();
+    let mut index = 0;
+
+    while position < delta.len() {
+        index += read_varint(delta, &mut position);
+        let changed = read_varint(delta, &mut position);
+
+        for _ in 0..changed {
+            result[index] ^= delta[position];
+            index += 1;
+            position += 1;
+        }
+    }
+
+    result
+}
+
+fn byte_at(data: &[u8], index: usize) -> u8 {
+    data.get(index).copied().unwrap_or(0)
+}
+
+// LEB128, 7 bits per byte with the top bit set while more bytes follow.
+fn write_varint(data: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        data.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+
+    data.push(value as u8);
+}
+
+fn read_varint(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = data[*position];
+        *position += 1;
+
+        value |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return value;
+        }
+    }
+}
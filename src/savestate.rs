@@ -0,0 +1,174 @@
+use std::fs;
+use std::io;
+use std::io::{Cursor, Read};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+
+const STATE_MAGIC: &[u8; 4] = b"RBSS";
+// Bump whenever the layout of any component's state changes, old states are rejected.
+const STATE_VERSION: u16 = 6;
+
+pub const STATE_SLOTS: usize = 10;
+
+// Slots are numbered from 1 for the user, like the files.
+pub fn slot_path(rom_title: &str, slot: usize) -> PathBuf {
+    PathBuf::from(format!("save_states/{}.ss{}", rom_title, slot + 1))
+}
+
+pub fn write_slot(rom_title: &str, slot: usize, data: &[u8]) -> io::Result<PathBuf> {
+    let path = slot_path(rom_title, slot);
+
+    fs::create_dir_all("save_states")?;
+    fs::write(&path, data)?;
+    Ok(path)
+}
+
+pub fn read_slot(rom_title: &str, slot: usize) -> io::Result<Vec<u8>> {
+    fs::read(slot_path(rom_title, slot))
+}
+
+// Writes into a Vec can't fail, so every component can write its state without error handling.
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u64) -> StateWriter {
+        let mut writer = StateWriter {
+            data: Vec::new(),
+        };
+
+        writer.data.extend_from_slice(STATE_MAGIC);
+        writer.u16(STATE_VERSION);
+        writer.u64(rom_hash);
+        writer
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.write_u16::<LittleEndian>(value).unwrap();
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.write_u32::<LittleEndian>(value).unwrap();
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.write_u64::<LittleEndian>(value).unwrap();
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.data.write_i32::<LittleEndian>(value).unwrap();
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn atomics(&mut self, bytes: &[AtomicU8]) {
+        self.u32(bytes.len() as u32);
+
+        for byte in bytes.iter() {
+            self.data.push(byte.load(Ordering::Relaxed));
+        }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub struct StateReader<'a> {
+    cursor: Cursor<&'a [u8]>,
+}
+
+impl<'a> StateReader<'a> {
+    // Fails if the data isn't a state for this version, or was made with a different ROM.
+    pub fn new(data: &'a [u8], rom_hash: u64) -> Result<StateReader<'a>, String> {
+        let mut reader = StateReader {
+            cursor: Cursor::new(data),
+        };
+
+        let mut magic = [0; 4];
+        reader.cursor.read_exact(&mut magic).map_err(|_| String::from("Not a save state"))?;
+
+        if &magic != STATE_MAGIC {
+            return Err(String::from("Not a save state"));
+        }
+
+        let version = reader.u16().map_err(|error| error.to_string())?;
+
+        if version != STATE_VERSION {
+            return Err(format!("Unsupported save state version {}, expected {}", version, STATE_VERSION));
+        }
+
+        if reader.u64().map_err(|error| error.to_string())? != rom_hash {
+            return Err(String::from("The save state was made with a different ROM"));
+        }
+
+        Ok(reader)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        self.cursor.read_u8()
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        self.cursor.read_u16::<LittleEndian>()
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        self.cursor.read_u32::<LittleEndian>()
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        self.cursor.read_u64::<LittleEndian>()
+    }
+
+    pub fn i32(&mut self) -> io::Result<i32> {
+        self.cursor.read_i32::<LittleEndian>()
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.cursor.read_u8()? != 0)
+    }
+
+    pub fn bytes(&mut self) -> io::Result<Vec<u8>> {
+        let length = self.u32()? as usize;
+        let remaining = self.cursor.get_ref().len() - self.cursor.position() as usize;
+
+        if length > remaining {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "state data is truncated"));
+        }
+
+        let mut bytes = vec![0; length];
+        self.cursor.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    // The stored region has to be the same size as the one it's loaded into.
+    pub fn atomics(&mut self, bytes: &[AtomicU8]) -> io::Result<()> {
+        let data = self.bytes()?;
+
+        if data.len() != bytes.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("region size mismatch, {} instead of {} bytes", data.len(), bytes.len())));
+        }
+
+        for (byte, value) in bytes.iter().zip(data.iter()) {
+            byte.store(*value, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::gpu::Frame;
+
+
+const SCREENSHOT_DIR: &str = "screenshots";
+
+// Screenshots taken with the hotkey go to screenshots/<rom title>_<milliseconds since the epoch>.png,
+// with the player number after the time when linked. A number is added if the file is already there.
+pub fn timestamped_path(rom_title: &str, player: Option<usize>) -> PathBuf {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis()).unwrap_or(0);
+    let mut name = format!("{}_{}", rom_title, timestamp);
+
+    if let Some(player) = player {
+        name.push_str(&format!("_p{}", player + 1));
+    }
+
+    let mut path = Path::new(SCREENSHOT_DIR).join(format!("{}.png", name));
+    let mut copy = 2;
+
+    while path.exists() {
+        path = Path::new(SCREENSHOT_DIR).join(format!("{}_{}.png", name, copy));
+        copy += 1;
+    }
+
+    path
+}
+
+// Every pixel becomes a scale x scale square, like in the window.
+pub fn save_png(frame: &Frame, scale: u32, path: &Path) -> io::Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    let scale = scale.max(1) as usize;
+    let row_bytes = frame.width as usize * 4;
+    let mut pixels = Vec::with_capacity(frame.pixels.len() * scale * scale);
+
+    for row in frame.pixels.chunks(row_bytes) {
+        let scaled_row: Vec<u8> = row.chunks(4).flat_map(|pixel| pixel.repeat(scale)).collect();
+
+        for _ in 0..scale {
+            pixels.extend_from_slice(&scaled_row);
+        }
+    }
+
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, frame.width * scale as u32, frame.height * scale as u32);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&pixels)?;
+    writer.finish()?;
+    Ok(())
+}
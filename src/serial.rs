@@ -0,0 +1,194 @@
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+use super::link::{LinkAddress, LinkCable};
+use super::printer;
+use super::printer::Printer;
+use super::savestate::{StateReader, StateWriter};
+
+
+// The internal clock runs at 8192 Hz, 8 bits take 4096 cycles. The CGB's fast clock runs at
+// 262144 Hz.
+const CYCLES_PER_BIT: u32 = 512;
+const FAST_CYCLES_PER_BIT: u32 = 16;
+
+// Whatever is plugged into the link port. Externally clocked transfers only finish if the
+// device clocks them, like on a Game Boy with nothing plugged in.
+pub trait SerialDevice: Send {
+    // An internally clocked transfer started sending this byte.
+    fn transfer_started(&mut self, _byte: u8) {}
+
+    // Called once the byte has been shifted out, returns the byte shifted in from the other side.
+    fn exchange(&mut self, byte: u8) -> u8;
+
+    // Called as cycles go by with the port contents, and whether it waits for an external clock.
+    // Returns the byte shifted in if the device clocked a transfer.
+    fn clock(&mut self, _cycles: u16, _data: u8, _waiting: bool) -> Option<u8> {
+        None
+    }
+
+    // False while the emulator has to hold on, like a linked Game Boy that hasn't caught up yet.
+    fn ready(&mut self) -> bool {
+        true
+    }
+}
+
+// Nothing plugged in, the data line stays high.
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn exchange(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+}
+
+// Prints every byte sent, which is how test ROMs like Blargg's report their results.
+pub struct StdoutDevice;
+
+impl SerialDevice for StdoutDevice {
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut stdout = io::stdout();
+        stdout.write_all(&[byte]).and_then(|_| stdout.flush()).ok();
+        0xFF
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub enum SerialConfig {
+    Disconnected,
+    Stdout,
+    // Link cable to another instance, see link::LinkCable.
+    Link(LinkAddress),
+    // Game Boy Printer, saving printouts to the directory.
+    Printer(PathBuf),
+}
+
+impl SerialConfig {
+    pub fn parse(value: &str) -> Result<SerialConfig, String> {
+        match value {
+            "none" => Ok(SerialConfig::Disconnected),
+            "stdout" => Ok(SerialConfig::Stdout),
+            "printer" => Ok(SerialConfig::Printer(PathBuf::from(printer::DEFAULT_PRINTER_DIR))),
+            _ => match (value.strip_prefix("printer:"), LinkAddress::parse(value)) {
+                (Some(directory), _) => Ok(SerialConfig::Printer(PathBuf::from(directory))),
+                (None, Some(address)) => Ok(SerialConfig::Link(address)),
+                (None, None) => Err(format!("Unknown serial device {}, expected none, stdout, printer, printer:DIR, tcp-listen:ADDRESS, tcp:ADDRESS, unix-listen:PATH or unix:PATH", value)),
+            }
+        }
+    }
+
+    // Linking waits until the other side is there.
+    pub fn device(&self) -> Result<Box<dyn SerialDevice>, String> {
+        match self {
+            SerialConfig::Disconnected => Ok(Box::new(Disconnected)),
+            SerialConfig::Stdout => Ok(Box::new(StdoutDevice)),
+            SerialConfig::Link(address) => Ok(Box::new(LinkCable::connect(address)?)),
+            SerialConfig::Printer(directory) => Ok(Box::new(Printer::new(directory))),
+        }
+    }
+}
+
+// SB (0xFF01) and SC (0xFF02).
+pub struct Serial {
+    data: u8,
+    control: u8,
+    // Left until the running transfer ends, 0 if there's none.
+    transfer_cycles: u32,
+
+    device: Box<dyn SerialDevice>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            control: 0,
+            transfer_cycles: 0,
+
+            device: Box::new(Disconnected),
+        }
+    }
+
+    // The device isn't part of the machine, it stays plugged in.
+    pub fn reset(&mut self) {
+        self.data = 0;
+        self.control = 0;
+        self.transfer_cycles = 0;
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u8(self.data);
+        writer.u8(self.control);
+        writer.u32(self.transfer_cycles);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.data = reader.u8()?;
+        self.control = reader.u8()? & 0x83;
+        self.transfer_cycles = reader.u32()?;
+        Ok(())
+    }
+
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    // Leaves this port with nothing plugged in.
+    pub fn take_device(&mut self) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, Box::new(Disconnected))
+    }
+
+    pub fn read(&self, address: u16, cgb: bool) -> u8 {
+        match address {
+            0xFF01 => self.data,
+            // Unused bits read as 1, bit 1 is only there on a CGB.
+            _ => self.control | if cgb {0x7C} else {0x7E},
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8, cgb: bool) {
+        match address {
+            0xFF01 => self.data = value,
+            _ => {
+                self.control = value & if cgb {0x83} else {0x81};
+
+                // Bit 7 starts a transfer, bit 0 picks the internal clock and bit 1 its speed.
+                let internal = self.control & 0x81 == 0x81;
+                let cycles_per_bit = if self.control & 0x02 != 0 {FAST_CYCLES_PER_BIT} else {CYCLES_PER_BIT};
+                self.transfer_cycles = if internal {8 * cycles_per_bit} else {0};
+
+                if internal {
+                    self.device.transfer_started(self.data);
+                }
+            }
+        }
+    }
+
+    pub fn ready(&mut self) -> bool {
+        self.device.ready()
+    }
+
+    // Returns true when a transfer just ended, which requests the serial interrupt.
+    pub fn serial_cycle(&mut self, cycles: u16) -> bool {
+        if let Some(byte) = self.device.clock(cycles, self.data, self.control & 0x81 == 0x80) {
+            self.data = byte;
+            self.control &= 0x7F;
+            return true;
+        }
+
+        if self.transfer_cycles == 0 {
+            return false;
+        }
+
+        if (cycles as u32) < self.transfer_cycles {
+            self.transfer_cycles -= cycles as u32;
+            return false;
+        }
+
+        self.transfer_cycles = 0;
+        self.data = self.device.exchange(self.data);
+        self.control &= 0x7F;
+        true
+    }
+}
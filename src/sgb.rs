@@ -0,0 +1,563 @@
+use std::io;
+
+use log::{info, warn};
+
+use super::gpu::{SCREEN_WIDTH, SCREEN_HEIGHT};
+use super::savestate::{StateReader, StateWriter};
+
+
+// The SNES shows the Game Boy screen in the middle of a 256x224 picture, the border goes around it.
+pub const BORDER_WIDTH: u32 = 256;
+pub const BORDER_HEIGHT: u32 = 224;
+const SCREEN_X: usize = 48;
+const SCREEN_Y: usize = 40;
+
+// Attributes pick a palette for every 8x8 cell of the screen.
+const CELLS_X: usize = 20;
+const CELLS_Y: usize = 18;
+const ATTRIBUTE_FILES: usize = 45;
+const ATTRIBUTE_FILE_SIZE: usize = CELLS_X * CELLS_Y / 4;
+
+const SYSTEM_PALETTES: usize = 512;
+const BORDER_TILES: usize = 256;
+
+// What the SNES copies from VRAM after a *_TRN command.
+pub const TRANSFER_SIZE: usize = 4096;
+
+// Palette 0 until the game sends its own.
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+const COMMAND_PAL01: u8 = 0x00;
+const COMMAND_PAL23: u8 = 0x01;
+const COMMAND_PAL03: u8 = 0x02;
+const COMMAND_PAL12: u8 = 0x03;
+const COMMAND_ATTR_BLK: u8 = 0x04;
+const COMMAND_ATTR_LIN: u8 = 0x05;
+const COMMAND_ATTR_DIV: u8 = 0x06;
+const COMMAND_ATTR_CHR: u8 = 0x07;
+const COMMAND_PAL_SET: u8 = 0x0A;
+const COMMAND_PAL_TRN: u8 = 0x0B;
+const COMMAND_MLT_REQ: u8 = 0x11;
+const COMMAND_CHR_TRN: u8 = 0x13;
+const COMMAND_PCT_TRN: u8 = 0x14;
+const COMMAND_ATTR_TRN: u8 = 0x15;
+const COMMAND_ATTR_SET: u8 = 0x16;
+const COMMAND_MASK_EN: u8 = 0x17;
+
+const MASK_NONE: u8 = 0;
+const MASK_FREEZE: u8 = 1;
+const MASK_BLACK: u8 = 2;
+const MASK_COLOR_0: u8 = 3;
+
+#[derive(Clone, Copy)]
+pub struct SgbSettings {
+    // SGB games run on a Super Game Boy instead of a DMG.
+    pub enabled: bool,
+    pub border: bool,
+}
+
+// Data the game has put on screen for the SNES to copy, see Memory::sgb_transfer_data.
+#[derive(Clone, Copy)]
+pub enum Transfer {
+    Palettes,
+    Attributes,
+    // Border tiles 0x00-0x7F or 0x80-0xFF.
+    Tiles(usize),
+    Border,
+}
+
+// Super Game Boy. Games talk to the SNES by pulsing the P1 select lines: a reset pulse with both
+// low, then 128 bits with P14 low for 0 and P15 low for 1, and a stop bit. The first byte of a
+// command is its number and how many 16 byte packets it takes.
+//
+// The SNES colors the Game Boy's 4 shades with one of 4 palettes for every 8x8 cell of the screen,
+// and draws the border around it.
+pub struct Sgb {
+    receiving: bool,
+    bit: usize,
+    packet: [u8; 16],
+    command: Vec<u8>,
+    previous_p1: u8,
+
+    players: u8,
+    player: u8,
+
+    palettes: [[u16; 4]; 4],
+    system_palettes: Vec<u16>,
+    attributes: Vec<u8>,
+    attribute_files: Vec<u8>,
+    mask: u8,
+
+    // Color indexes, 64 per tile.
+    border_tiles: Vec<u8>,
+    border_map: Vec<u16>,
+    border_palettes: [[u16; 16]; 4],
+
+    // What the screen showed last, kept while it's frozen.
+    screen: Vec<u16>,
+    // Commands already reported as unsupported.
+    warned: u32,
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        Sgb {
+            receiving: false,
+            bit: 0,
+            packet: [0; 16],
+            command: Vec::new(),
+            previous_p1: 0x30,
+
+            players: 1,
+            player: 0,
+
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; SYSTEM_PALETTES * 4],
+            attributes: vec![0; CELLS_X * CELLS_Y],
+            attribute_files: vec![0; ATTRIBUTE_FILES * ATTRIBUTE_FILE_SIZE],
+            mask: MASK_NONE,
+
+            border_tiles: vec![0; BORDER_TILES * 64],
+            border_map: vec![0; 32 * 32],
+            border_palettes: [[0; 16]; 4],
+
+            screen: vec![0; (SCREEN_WIDTH * SCREEN_HEIGHT) as usize],
+            warned: 0,
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.bool(self.receiving);
+        writer.u8(self.bit as u8);
+        writer.bytes(&self.packet);
+        writer.bytes(&self.command);
+        writer.u8(self.previous_p1);
+
+        writer.u8(self.players);
+        writer.u8(self.player);
+
+        for color in self.palettes.iter().flatten().chain(self.system_palettes.iter()) {
+            writer.u16(*color);
+        }
+
+        writer.bytes(&self.attributes);
+        writer.bytes(&self.attribute_files);
+        writer.u8(self.mask);
+
+        writer.bytes(&self.border_tiles);
+
+        for entry in self.border_map.iter().chain(self.border_palettes.iter().flatten()) {
+            writer.u16(*entry);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.receiving = reader.bool()?;
+        self.bit = (reader.u8()? as usize).min(128);
+        Sgb::copy_bytes(&mut self.packet, &reader.bytes()?);
+        self.command = reader.bytes()?;
+        self.previous_p1 = reader.u8()? & 0x30;
+
+        // Commands are received in whole packets, up to 7 of them.
+        if !self.command.len().is_multiple_of(16) || self.command.len() > 7 * 16 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("SGB command of {} bytes", self.command.len())));
+        }
+
+        self.players = reader.u8()?;
+        self.player = reader.u8()?;
+
+        if ![1, 2, 4].contains(&self.players) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} SGB joypads", self.players)));
+        }
+
+        self.player %= self.players;
+
+        for color in self.palettes.iter_mut().flatten().chain(self.system_palettes.iter_mut()) {
+            *color = reader.u16()?;
+        }
+
+        // Masked like the packets that set them, they're used as indices when rendering.
+        Sgb::copy_bytes(&mut self.attributes, &reader.bytes()?);
+        self.attributes.iter_mut().for_each(|palette| *palette &= 3);
+        Sgb::copy_bytes(&mut self.attribute_files, &reader.bytes()?);
+        self.mask = reader.u8()? & 3;
+
+        Sgb::copy_bytes(&mut self.border_tiles, &reader.bytes()?);
+        self.border_tiles.iter_mut().for_each(|color| *color &= 0xF);
+
+        for entry in self.border_map.iter_mut().chain(self.border_palettes.iter_mut().flatten()) {
+            *entry = reader.u16()?;
+        }
+
+        Ok(())
+    }
+
+    fn copy_bytes(destination: &mut [u8], source: &[u8]) {
+        for (byte, value) in destination.iter_mut().zip(source.iter()) {
+            *byte = *value;
+        }
+    }
+
+    // Returns the transfer a finished command asked for, the data has to be handed to transfer().
+    pub fn write_p1(&mut self, value: u8) -> Option<Transfer> {
+        let lines = value & 0x30;
+        let previous = self.previous_p1;
+        self.previous_p1 = lines;
+
+        // With several joypads, the next one is selected every time P15 goes back up.
+        if lines == 0x30 && previous & 0x20 == 0 && self.players > 1 {
+            self.player = (self.player + 1) % self.players;
+        }
+
+        match lines {
+            0x00 => {
+                self.receiving = true;
+                self.bit = 0;
+                self.packet = [0; 16];
+                None
+            },
+            0x10 | 0x20 if self.receiving && previous == 0x30 => {
+                if self.bit < 128 {
+                    if lines == 0x10 {
+                        self.packet[self.bit / 8] |= 1 << (self.bit % 8);
+                    }
+
+                    self.bit += 1;
+                    None
+                }
+                else {
+                    self.receiving = false;
+                    self.packet_received()
+                }
+            },
+            _ => None,
+        }
+    }
+
+    fn packet_received(&mut self) -> Option<Transfer> {
+        self.command.extend_from_slice(&self.packet);
+
+        let packets = (self.command[0] & 7).max(1) as usize;
+
+        if self.command.len() < packets * 16 {
+            return None;
+        }
+
+        let command = std::mem::take(&mut self.command);
+        self.run_command(&command)
+    }
+
+    fn run_command(&mut self, data: &[u8]) -> Option<Transfer> {
+        match data[0] >> 3 {
+            COMMAND_PAL01 => self.set_palettes(0, 1, data),
+            COMMAND_PAL23 => self.set_palettes(2, 3, data),
+            COMMAND_PAL03 => self.set_palettes(0, 3, data),
+            COMMAND_PAL12 => self.set_palettes(1, 2, data),
+            COMMAND_ATTR_BLK => self.attribute_blocks(data),
+            COMMAND_ATTR_LIN => self.attribute_lines(data),
+            COMMAND_ATTR_DIV => self.attribute_division(data),
+            COMMAND_ATTR_CHR => self.attribute_cells(data),
+            COMMAND_PAL_SET => self.set_system_palettes(data),
+            COMMAND_PAL_TRN => return Some(Transfer::Palettes),
+            COMMAND_MLT_REQ => {
+                self.players = match data[1] & 3 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1,
+                };
+                self.player = 0;
+            },
+            COMMAND_CHR_TRN => return Some(Transfer::Tiles((data[1] & 1) as usize)),
+            COMMAND_PCT_TRN => return Some(Transfer::Border),
+            COMMAND_ATTR_TRN => return Some(Transfer::Attributes),
+            COMMAND_ATTR_SET => self.apply_attribute_file(data[1]),
+            COMMAND_MASK_EN => self.mask = data[1] & 3,
+            command => {
+                // Sound, SNES programs and the like. Games send them on every boot, so they're only reported once.
+                if self.warned & (1 << command) == 0 {
+                    self.warned |= 1 << command;
+                    warn!("Sgb: Unsupported command {:#04X}, ignoring it", command);
+                }
+            },
+        }
+
+        None
+    }
+
+    pub fn transfer(&mut self, transfer: Transfer, data: &[u8]) {
+        match transfer {
+            Transfer::Palettes => {
+                for (color, bytes) in self.system_palettes.iter_mut().zip(data.chunks(2)) {
+                    *color = bytes[0] as u16 | ((bytes[1] as u16) << 8);
+                }
+            },
+            Transfer::Attributes => Sgb::copy_bytes(&mut self.attribute_files, data),
+            // SNES tiles have 4 bit planes, the first two rows of bytes hold planes 0 and 1, the next two planes 2 and 3.
+            Transfer::Tiles(half) => {
+                for (tile, bytes) in data.chunks(32).enumerate() {
+                    let pixels = &mut self.border_tiles[(half * 128 + tile) * 64..(half * 128 + tile + 1) * 64];
+
+                    for (index, pixel) in pixels.iter_mut().enumerate() {
+                        let (row, bit) = (index / 8, 7 - index % 8);
+                        let planes = [bytes[row * 2], bytes[row * 2 + 1], bytes[16 + row * 2], bytes[16 + row * 2 + 1]];
+
+                        *pixel = planes.iter().enumerate().fold(0, |color, (plane, byte)| color | (((byte >> bit) & 1) << plane));
+                    }
+                }
+            },
+            // The 32x32 map, then palettes 4 to 7.
+            Transfer::Border => {
+                for (entry, bytes) in self.border_map.iter_mut().zip(data.chunks(2)) {
+                    *entry = bytes[0] as u16 | ((bytes[1] as u16) << 8);
+                }
+
+                for (color, bytes) in self.border_palettes.iter_mut().flatten().zip(data[0x800..0x880].chunks(2)) {
+                    *color = bytes[0] as u16 | ((bytes[1] as u16) << 8);
+                }
+
+                info!("Sgb: Border received");
+            },
+        }
+    }
+
+    // Joypad ID reported in the low bits of P1 with nothing selected, when more than one joypad is enabled.
+    pub fn joypad_id(&self) -> Option<u8> {
+        if self.players > 1 {Some(self.player)} else {None}
+    }
+
+    fn color(data: &[u8], index: usize) -> u16 {
+        data[1 + index * 2] as u16 | ((data[2 + index * 2] as u16) << 8)
+    }
+
+    // Color 0 is shared by all 4 palettes.
+    fn set_palettes(&mut self, first: usize, second: usize, data: &[u8]) {
+        for palette in self.palettes.iter_mut() {
+            palette[0] = Sgb::color(data, 0);
+        }
+
+        for index in 1..4 {
+            self.palettes[first][index] = Sgb::color(data, index);
+            self.palettes[second][index] = Sgb::color(data, index + 3);
+        }
+    }
+
+    fn set_system_palettes(&mut self, data: &[u8]) {
+        for palette in 0..4 {
+            let index = (Sgb::color(data, palette) & 0x1FF) as usize;
+            self.palettes[palette].copy_from_slice(&self.system_palettes[index * 4..index * 4 + 4]);
+        }
+
+        for palette in 1..4 {
+            self.palettes[palette][0] = self.palettes[0][0];
+        }
+
+        if data[9] & 0x80 != 0 {
+            self.apply_attribute_file(data[9]);
+        }
+        else if data[9] & 0x40 != 0 {
+            self.mask = MASK_NONE;
+        }
+    }
+
+    // The file number goes in the low 6 bits, bit 6 also cancels the screen mask.
+    fn apply_attribute_file(&mut self, value: u8) {
+        let file = (value & 0x3F) as usize;
+
+        if file < ATTRIBUTE_FILES {
+            let data = &self.attribute_files[file * ATTRIBUTE_FILE_SIZE..(file + 1) * ATTRIBUTE_FILE_SIZE];
+
+            for (index, attribute) in self.attributes.iter_mut().enumerate() {
+                *attribute = (data[index / 4] >> (6 - (index % 4) * 2)) & 3;
+            }
+        }
+
+        if value & 0x40 != 0 {
+            self.mask = MASK_NONE;
+        }
+    }
+
+    // Rectangles with a palette for the cells inside, on the edge and outside of them. Setting only
+    // the inside or the outside also sets the edge.
+    fn attribute_blocks(&mut self, data: &[u8]) {
+        let sets = (data[1] as usize).min(18);
+
+        for set in data[2..].chunks(6).take(sets) {
+            if set.len() < 6 {
+                break;
+            }
+
+            // Bits for the inside, the edge and the outside, in the same order as the palettes.
+            let mut control = set[0] & 7;
+            let mut palettes = [set[1] & 3, (set[1] >> 2) & 3, (set[1] >> 4) & 3];
+
+            if control == 1 || control == 4 {
+                palettes[1] = palettes[control as usize / 2];
+                control |= 2;
+            }
+
+            let (x1, y1, x2, y2) = (set[2] as usize, set[3] as usize, set[4] as usize, set[5] as usize);
+
+            for y in 0..CELLS_Y {
+                for x in 0..CELLS_X {
+                    let inside = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                    let edge = inside && (x == x1 || x == x2 || y == y1 || y == y2);
+
+                    let region = match (inside, edge) {
+                        (true, true) => 1,
+                        (true, false) => 0,
+                        _ => 2,
+                    };
+
+                    if control & (1 << region) != 0 {
+                        self.attributes[y * CELLS_X + x] = palettes[region];
+                    }
+                }
+            }
+        }
+    }
+
+    // Whole rows or columns of cells.
+    fn attribute_lines(&mut self, data: &[u8]) {
+        let lines = data[1] as usize;
+
+        for line in data[2..].iter().take(lines) {
+            let (index, palette) = ((line & 0x1F) as usize, (line >> 5) & 3);
+
+            if line & 0x80 != 0 && index < CELLS_Y {
+                self.attributes[index * CELLS_X..(index + 1) * CELLS_X].fill(palette);
+            }
+            else if line & 0x80 == 0 && index < CELLS_X {
+                for y in 0..CELLS_Y {
+                    self.attributes[y * CELLS_X + index] = palette;
+                }
+            }
+        }
+    }
+
+    // Splits the screen in two at a row or a column, which gets a palette of its own.
+    fn attribute_division(&mut self, data: &[u8]) {
+        let (after, before, on_line) = (data[1] & 3, (data[1] >> 2) & 3, (data[1] >> 4) & 3);
+        let horizontal = data[1] & 0x40 != 0;
+        let split = data[2] as usize;
+
+        for y in 0..CELLS_Y {
+            for x in 0..CELLS_X {
+                let position = if horizontal {y} else {x};
+
+                self.attributes[y * CELLS_X + x] = match position {
+                    position if position < split => before,
+                    position if position == split => on_line,
+                    _ => after,
+                };
+            }
+        }
+    }
+
+    // One palette per cell, 4 to a byte, going right or down from a starting cell.
+    fn attribute_cells(&mut self, data: &[u8]) {
+        let (mut x, mut y) = ((data[1] as usize).min(CELLS_X - 1), (data[2] as usize).min(CELLS_Y - 1));
+        let count = (data[3] as usize | ((data[4] as usize) << 8)).min(CELLS_X * CELLS_Y);
+        let vertical = data[5] == 1;
+
+        for index in 0..count {
+            let byte = match data.get(6 + index / 4) {
+                Some(byte) => *byte,
+                None => break,
+            };
+
+            self.attributes[y * CELLS_X + x] = (byte >> (6 - (index % 4) * 2)) & 3;
+
+            if vertical {
+                y += 1;
+
+                if y == CELLS_Y {
+                    y = 0;
+                    x = (x + 1) % CELLS_X;
+                }
+            }
+            else {
+                x += 1;
+
+                if x == CELLS_X {
+                    x = 0;
+                    y = (y + 1) % CELLS_Y;
+                }
+            }
+        }
+    }
+
+    // Colors a finished frame. Shades come from the GPU, one per pixel. Returns RGBA32 pixels
+    // and their size, which includes the border if it's shown.
+    pub fn render(&mut self, shades: &[u8], border: bool) -> (Vec<u8>, u32, u32) {
+        match self.mask {
+            MASK_FREEZE => (),
+            MASK_BLACK => self.screen.fill(0),
+            MASK_COLOR_0 => self.screen.fill(self.palettes[0][0]),
+            _ => {
+                for (index, (color, shade)) in self.screen.iter_mut().zip(shades.iter()).enumerate() {
+                    let (x, y) = (index % SCREEN_WIDTH as usize, index / SCREEN_WIDTH as usize);
+                    let palette = self.attributes[(y / 8) * CELLS_X + x / 8] as usize;
+                    *color = self.palettes[palette][(shade & 3) as usize];
+                }
+            },
+        }
+
+        if !border {
+            let pixels = self.screen.iter().flat_map(|color| rgba(*color)).collect();
+            return (pixels, SCREEN_WIDTH, SCREEN_HEIGHT);
+        }
+
+        let mut pixels = Vec::with_capacity((BORDER_WIDTH * BORDER_HEIGHT * 4) as usize);
+
+        for y in 0..BORDER_HEIGHT as usize {
+            for x in 0..BORDER_WIDTH as usize {
+                let on_screen = (SCREEN_X..SCREEN_X + SCREEN_WIDTH as usize).contains(&x) && (SCREEN_Y..SCREEN_Y + SCREEN_HEIGHT as usize).contains(&y);
+
+                let color = if on_screen {
+                    self.screen[(y - SCREEN_Y) * SCREEN_WIDTH as usize + x - SCREEN_X]
+                }
+                else {
+                    self.border_color(x, y)
+                };
+
+                pixels.extend_from_slice(&rgba(color));
+            }
+        }
+
+        (pixels, BORDER_WIDTH, BORDER_HEIGHT)
+    }
+
+    // Map entries are the tile number, the palette (4 to 7) in bits 10-12, and X and Y flips in bits 14 and 15.
+    // Color 0 is transparent, showing the backdrop: color 0 of the game's palettes.
+    fn border_color(&self, x: usize, y: usize) -> u16 {
+        let entry = self.border_map[(y / 8) * 32 + x / 8];
+        let (mut row, mut column) = (y % 8, x % 8);
+
+        if entry & 0x4000 != 0 {
+            column = 7 - column;
+        }
+
+        if entry & 0x8000 != 0 {
+            row = 7 - row;
+        }
+
+        let color = self.border_tiles[(entry & 0xFF) as usize * 64 + row * 8 + column];
+
+        if color == 0 {
+            self.palettes[0][0]
+        }
+        else {
+            self.border_palettes[((entry >> 10) & 3) as usize][color as usize]
+        }
+    }
+}
+
+// RGB555 to RGBA32, the top bits are repeated so white stays white.
+fn rgba(color: u16) -> [u8; 4] {
+    let channel = |shift: u16| {
+        let value = ((color >> shift) & 0x1F) as u8;
+        (value << 3) | (value >> 2)
+    };
+
+    [channel(0), channel(5), channel(10), 255]
+}
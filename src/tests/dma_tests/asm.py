@@ -0,0 +1,150 @@
+# Tiny assembler for the DMA test ROMs in this folder, which report over serial. Running one of the
+# scripts with python3 rebuilds its ROMs next to it, then they run headless with the stdout device:
+#   rusty_boi --headless --frames 120 hdma.gb
+import struct
+
+
+class Rom:
+    def __init__(self, cgb=False):
+        self.code = bytearray()
+        self.labels = {}
+        self.fixups = []
+        self.base = 0x150
+        self.cgb = cgb
+        self.counter = 0
+
+    def pc(self):
+        return self.base + len(self.code)
+
+    def label(self, name):
+        self.labels[name] = self.pc()
+
+    def fresh(self, prefix="l"):
+        self.counter += 1
+        return "%s_%d" % (prefix, self.counter)
+
+    def emit(self, *values):
+        self.code.extend(values)
+
+    def emit16(self, value):
+        if isinstance(value, str):
+            self.fixups.append(("abs", len(self.code), value))
+            self.code.extend(b"\0\0")
+        else:
+            self.code.extend(struct.pack("<H", value))
+
+    def jr(self, cond, target):
+        ops = {None: 0x18, "nz": 0x20, "z": 0x28, "nc": 0x30, "c": 0x38}
+        self.emit(ops[cond])
+        self.fixups.append(("rel", len(self.code), target))
+        self.emit(0)
+
+    def jp(self, cond, target):
+        ops = {None: 0xC3, "nz": 0xC2, "z": 0xCA, "nc": 0xD2, "c": 0xDA}
+        self.emit(ops[cond])
+        self.emit16(target)
+
+    def call(self, target):
+        self.emit(0xCD)
+        self.emit16(target)
+
+    def ret(self):
+        self.emit(0xC9)
+
+    # Loads and ALU, by name.
+    def ld_a(self, n): self.emit(0x3E, n & 0xFF)
+    def ld_b(self, n): self.emit(0x06, n & 0xFF)
+    def ld_c(self, n): self.emit(0x0E, n & 0xFF)
+    def ld_e(self, n): self.emit(0x1E, n & 0xFF)
+    def ld_hl(self, n): self.emit(0x21); self.emit16(n)
+    def ld_de(self, n): self.emit(0x11); self.emit16(n)
+    def ld_sp(self, n): self.emit(0x31); self.emit16(n)
+    def ldh_w(self, reg): self.emit(0xE0, reg & 0xFF)    # ldh (reg), a
+    def ldh_r(self, reg): self.emit(0xF0, reg & 0xFF)    # ldh a, (reg)
+    def ld_mem_a(self, addr): self.emit(0xEA); self.emit16(addr)
+    def ld_a_mem(self, addr): self.emit(0xFA); self.emit16(addr)
+    def cp(self, n): self.emit(0xFE, n & 0xFF)
+    def and_(self, n): self.emit(0xE6, n & 0xFF)
+    def add(self, n): self.emit(0xC6, n & 0xFF)
+
+    def build(self, path, title=b"TEST"):
+        for kind, offset, target in self.fixups:
+            address = self.labels[target] if isinstance(target, str) else target
+            if kind == "abs":
+                self.code[offset:offset + 2] = struct.pack("<H", address)
+            else:
+                delta = address - (self.base + offset + 1)
+                assert -128 <= delta <= 127, target
+                self.code[offset] = delta & 0xFF
+
+        rom = bytearray(0x8000)
+        rom[0x100:0x104] = b"\x00\xC3\x50\x01"
+        rom[0x104:0x134] = bytes.fromhex(
+            "CEED6666CC0D000B03730083000C000D0008111F8889000EDCCC6EE6DDDDD999BBBB67636E0EECCCDDDC999FBBB9333E")
+        rom[0x134:0x134 + len(title)] = title
+        if self.cgb:
+            rom[0x143] = 0x80
+        rom[0x150:0x150 + len(self.code)] = self.code
+        rom[0x14D] = (-sum(rom[0x134:0x14D]) - 25) & 0xFF
+        open(path, "wb").write(rom)
+
+    # Sends A over serial with the internal clock and waits for the transfer.
+    def serial_a(self):
+        self.ldh_w(0x01)
+        self.ld_a(0x81)
+        self.ldh_w(0x02)
+        loop = self.fresh()
+        self.label(loop)
+        self.ldh_r(0x02)
+        self.and_(0x80)
+        self.jr("nz", loop)
+
+    def print_subroutine(self):
+        # Prints the zero terminated string at HL.
+        self.label("print")
+        self.emit(0x2A)            # ld a, (hl+)
+        self.emit(0xB7)            # or a
+        self.emit(0xC8)            # ret z
+        self.emit(0xE5)            # push hl
+        self.serial_a()
+        self.emit(0xE1)            # pop hl
+        self.jr(None, "print")
+
+        # Prints A as 2 hex digits.
+        self.label("print_hex")
+        self.emit(0xF5)            # push af
+        self.emit(0xCB, 0x37)      # swap a
+        self.call("print_digit")
+        self.emit(0xF1)            # pop af
+        self.label("print_digit")
+        self.and_(0x0F)
+        self.cp(10)
+        skip = self.fresh()
+        self.jr("c", skip)
+        self.add(7)
+        self.label(skip)
+        self.add(0x30)
+        self.serial_a()
+        self.ret()
+
+    def string(self, name, text):
+        self.label(name)
+        self.code.extend(text.encode() + b"\0")
+
+    def print(self, name):
+        self.ld_hl(name)
+        self.call("print")
+
+    def halt_forever(self):
+        loop = self.fresh()
+        self.label(loop)
+        self.jr(None, loop)
+
+    def lcd_off(self):
+        loop = self.fresh()
+        self.label(loop)
+        self.ldh_r(0x44)
+        self.cp(144)
+        self.jr("nz", loop)
+        self.ld_a(0)
+        self.ldh_w(0x40)
@@ -0,0 +1,251 @@
+# CGB VRAM DMA checks, after the behavior Pan Docs describes for HDMA1-HDMA5. Each check prints
+# its name and PASS or FAIL over serial, with the values it read.
+import os
+
+from asm import Rom
+
+rom = Rom(cgb=True)
+
+
+def fill(address, length, seed):
+    rom.ld_hl(address)
+    rom.ld_b(length)
+    rom.ld_a(seed)
+    loop = rom.fresh()
+    rom.label(loop)
+    rom.emit(0x22)      # ld (hl+), a
+    rom.add(13)
+    rom.emit(0x05)      # dec b
+    rom.jr("nz", loop)
+
+
+# Compares length bytes at HL and DE, jumps to fail if any differ.
+def compare(source, destination, length, fail):
+    rom.ld_hl(source)
+    rom.ld_de(destination)
+    rom.ld_b(length)
+    loop = rom.fresh()
+    rom.label(loop)
+    rom.emit(0x1A)      # ld a, (de)
+    rom.emit(0xBE)      # cp (hl)
+    rom.jp("nz", fail)
+    rom.emit(0x23)      # inc hl
+    rom.emit(0x13)      # inc de
+    rom.emit(0x05)      # dec b
+    rom.jr("nz", loop)
+
+
+def set_hdma(source, destination):
+    rom.ld_a(source >> 8); rom.ldh_w(0x51)
+    rom.ld_a(source & 0xFF); rom.ldh_w(0x52)
+    rom.ld_a(destination >> 8); rom.ldh_w(0x53)
+    rom.ld_a(destination & 0xFF); rom.ldh_w(0x54)
+
+
+def wait_lines(count):
+    rom.ld_c(count)
+    outer = rom.fresh()
+    inner = rom.fresh()
+    rom.label(outer)
+    rom.ldh_r(0x44)
+    rom.emit(0x47)      # ld b, a
+    rom.label(inner)
+    rom.ldh_r(0x44)
+    rom.emit(0xB8)      # cp b
+    rom.jr("z", inner)
+    rom.emit(0x0D)      # dec c
+    rom.jr("nz", outer)
+
+
+# Waits for the line after this one to start. Line 0 is avoided, LY reads 1 there too.
+def sync_line(line):
+    loop = rom.fresh()
+    rom.label(loop)
+    rom.ldh_r(0x44)
+    rom.cp(line)
+    rom.jr("nz", loop)
+    wait_lines(1)
+
+
+def report(name):
+    # Prints PASS, or FAIL when the check jumped to <name>_fail.
+    done = rom.fresh()
+    rom.print(name + "_pass")
+    rom.jp(None, done)
+    rom.label(name + "_fail")
+    rom.print(name + "_fail_text")
+    rom.label(done)
+    strings.append((name + "_pass", "hdma/%s: PASS" % name))
+    strings.append((name + "_fail_text", "hdma/%s: FAIL" % name))
+
+
+def print_saved(count):
+    # Prints the values saved at 0xC800 and a newline.
+    for index in range(count):
+        rom.ld_a(0x20)
+        rom.serial_a()
+        rom.ld_a_mem(0xC800 + index)
+        rom.call("print_hex")
+    rom.ld_a(0x0A)
+    rom.serial_a()
+
+
+def save(index):
+    rom.ld_mem_a(0xC800 + index)
+
+
+strings = []
+
+rom.ld_sp(0xFFFE)
+rom.lcd_off()
+
+# General purpose DMA copies everything at once, and HDMA5 reads 0xFF after.
+fill(0xC000, 0x80, 0x05)
+set_hdma(0xC000, 0x8000)
+rom.ld_a(0x07)
+rom.ldh_w(0x55)
+rom.ldh_r(0x55)
+save(0)
+rom.cp(0xFF)
+rom.jp("nz", "gdma_copy_fail")
+compare(0xC000, 0x8000, 0x80, "gdma_copy_fail")
+report("gdma_copy")
+print_saved(1)
+
+# The low 4 bits of the addresses are ignored, so are the top 3 of the destination.
+# The address registers read 0xFF.
+fill(0xC120, 0x10, 0x31)
+set_hdma(0xC12F, 0xE10F)
+rom.ld_a(0x00)
+rom.ldh_w(0x55)
+for index, register in enumerate([0x51, 0x52, 0x53, 0x54]):
+    rom.ldh_r(register)
+    save(index)
+compare(0xC120, 0x8100, 0x10, "gdma_masks_fail")
+for index in range(4):
+    rom.ld_a_mem(0xC800 + index)
+    rom.cp(0xFF)
+    rom.jp("nz", "gdma_masks_fail")
+report("gdma_masks")
+print_saved(4)
+
+
+def gdma_timing(name, expected):
+    # Counts polling loop iterations until LY changes, after a dummy write and after starting
+    # 8 blocks. An iteration is 32 CPU cycles, as long as a block, so 8 fewer are expected, 16 in
+    # double speed where blocks take twice as many CPU cycles.
+    rom.ld_a(0x91)
+    rom.ldh_w(0x40)
+    set_hdma(0xC000, 0x8800)
+    for with_dma, index in [(False, 0), (True, 1)]:
+        sync_line(20)
+        rom.ldh_r(0x44)
+        rom.emit(0x47)          # ld b, a
+        rom.ld_e(0)
+        rom.ld_a(0x07)
+        rom.ldh_w(0x55 if with_dma else 0x80)
+        loop = rom.fresh()
+        rom.label(loop)
+        rom.emit(0x1C)          # inc e
+        rom.ldh_r(0x44)
+        rom.emit(0xB8)          # cp b
+        rom.jr("z", loop)
+        rom.emit(0x7B)          # ld a, e
+        save(index)
+    rom.lcd_off()
+    rom.ld_a_mem(0xC801)
+    rom.emit(0x47)              # ld b, a
+    rom.ld_a_mem(0xC800)
+    rom.emit(0x90)              # sub b
+    save(2)
+    rom.cp(expected)
+    rom.jp("nz", name + "_fail")
+    report(name)
+    print_saved(3)
+
+
+gdma_timing("gdma_timing", 8)
+
+# HBlank DMA copies a block in every line's HBlank, HDMA5 counts down to 0xFF.
+rom.ld_a(0x91)
+rom.ldh_w(0x40)
+wait_lines(2)
+fill(0xC200, 0x40, 0x47)
+set_hdma(0xC200, 0x8200)
+sync_line(20)
+rom.ld_a(0x83)
+rom.ldh_w(0x55)
+rom.ldh_r(0x55)
+save(0)
+wait_lines(1)
+rom.ldh_r(0x55)
+save(1)
+wait_lines(3)
+rom.ldh_r(0x55)
+save(2)
+rom.lcd_off()
+for index, expected in enumerate([0x03, 0x02, 0xFF]):
+    rom.ld_a_mem(0xC800 + index)
+    rom.cp(expected)
+    rom.jp("nz", "hblank_dma_fail")
+compare(0xC200, 0x8200, 0x40, "hblank_dma_fail")
+report("hblank_dma")
+print_saved(3)
+
+# Writing HDMA5 with bit 7 clear stops an HBlank DMA, HDMA5 then reads the blocks left minus one
+# with bit 7 set, and nothing else is copied.
+rom.ld_a(0x91)
+rom.ldh_w(0x40)
+wait_lines(2)
+fill(0xC300, 0x40, 0x59)
+set_hdma(0xC300, 0x8300)
+sync_line(20)
+rom.ld_a(0x8F)
+rom.ldh_w(0x55)
+wait_lines(2)
+rom.ld_a(0x00)
+rom.ldh_w(0x55)
+rom.ldh_r(0x55)
+save(0)
+wait_lines(3)
+rom.ldh_r(0x55)
+save(1)
+rom.lcd_off()
+rom.ld_a_mem(0x8320)
+save(2)
+for index, expected in enumerate([0x8D, 0x8D, 0x00]):
+    rom.ld_a_mem(0xC800 + index)
+    rom.cp(expected)
+    rom.jp("nz", "hblank_cancel_fail")
+compare(0xC300, 0x8300, 0x20, "hblank_cancel_fail")
+report("hblank_cancel")
+print_saved(3)
+
+# With the LCD off, starting an HBlank DMA copies the first block right away.
+fill(0xC400, 0x20, 0x6B)
+set_hdma(0xC400, 0x8400)
+rom.ld_a(0x81)
+rom.ldh_w(0x55)
+rom.ldh_r(0x55)
+save(0)
+rom.cp(0x00)
+rom.jp("nz", "lcd_off_hblank_fail")
+compare(0xC400, 0x8400, 0x10, "lcd_off_hblank_fail")
+rom.ld_a(0x00)
+rom.ldh_w(0x55)
+report("lcd_off_hblank")
+print_saved(1)
+
+# Double speed.
+rom.ld_a(0x01)
+rom.ldh_w(0x4D)
+rom.emit(0x10, 0x00)        # stop
+rom.ldh_r(0x4D)
+save(0)
+gdma_timing("gdma_timing_double_speed", 16)
+
+rom.halt_forever()
+rom.print_subroutine()
+for name, text in strings:
+    rom.string(name, text)
+rom.build(os.path.join(os.path.dirname(os.path.abspath(__file__)), "hdma.gb"))
@@ -0,0 +1,154 @@
+# OAM DMA checks modeled on Mooneye's acceptance/oam_dma tests: basic, reg_read and sources-GS.
+# Each prints its name and PASS or FAIL with details over serial.
+import os
+
+from asm import Rom
+
+HERE = os.path.dirname(os.path.abspath(__file__))
+
+# The routine games copy to HRAM: start the DMA and wait 160 M-cycles for it.
+DMA_ROUTINE = bytes([0xE0, 0x46, 0x3E, 0x28, 0x3D, 0x20, 0xFD, 0xC9])
+# Same, but reads DMA right after writing it into B.
+DMA_READ_ROUTINE = bytes([0xE0, 0x46, 0xF0, 0x46, 0x47, 0x3E, 0x28, 0x3D, 0x20, 0xFD, 0xC9])
+
+
+def setup(rom, routine):
+    rom.ld_sp(0xFFFE)
+    rom.lcd_off()
+    # Copy the routine to HRAM.
+    rom.ld_hl(0xFF80)
+    for byte in routine:
+        rom.emit(0x36, byte)   # ld (hl), n
+        rom.emit(0x23)         # inc hl
+
+
+# Fills 160 bytes with a pattern that starts at seed, so every source looks different.
+def fill(rom, address, seed):
+    rom.ld_hl(address)
+    rom.ld_b(160)
+    rom.ld_a(seed)
+    loop = rom.fresh()
+    rom.label(loop)
+    rom.emit(0x22)      # ld (hl+), a
+    rom.add(7)
+    rom.emit(0x05)      # dec b
+    rom.jr("nz", loop)
+
+
+# Compares 160 bytes against OAM, jumps to <fail>_pre with the mismatch's OAM address low byte in E.
+def compare_oam(rom, address, fail):
+    rom.ld_hl(address)
+    rom.ld_de(0xFE00)
+    rom.ld_b(160)
+    loop = rom.fresh()
+    rom.label(loop)
+    rom.emit(0x1A)      # ld a, (de)
+    rom.emit(0xBE)      # cp (hl)
+    rom.jr("nz", fail + "_pre")
+    rom.emit(0x23)      # inc hl
+    rom.emit(0x13)      # inc de
+    rom.emit(0x05)      # dec b
+    rom.jr("nz", loop)
+
+
+def basic():
+    rom = Rom()
+    setup(rom, DMA_ROUTINE)
+    fill(rom, 0xC100, 0x11)
+    rom.ld_a(0xC1)
+    rom.call(0xFF80)
+    compare_oam(rom, 0xC100, "fail")
+    rom.print("pass")
+    rom.halt_forever()
+
+    rom.label("fail_pre")
+    rom.emit(0x7B)      # ld a, e (offset into OAM)
+    rom.emit(0xF5)
+    rom.print("fail")
+    rom.emit(0xF1)
+    rom.call("print_hex")
+    rom.halt_forever()
+
+    rom.print_subroutine()
+    rom.string("pass", "oam_dma/basic: PASS\n")
+    rom.string("fail", "oam_dma/basic: FAIL at OAM offset ")
+    rom.build(os.path.join(HERE, "oam_dma_basic.gb"))
+
+
+def reg_read():
+    rom = Rom()
+    setup(rom, DMA_READ_ROUTINE)
+    # Every value written to DMA reads back once the transfer is over. Sources stay in ROM and WRAM.
+    for value in [0x00, 0x12, 0x3F, 0x80, 0xC0, 0xC5, 0xDF]:
+        rom.ld_a(value)
+        rom.call(0xFF80)
+        rom.ldh_r(0x46)
+        rom.cp(value)
+        rom.jp("nz", "fail")
+    rom.print("pass")
+    rom.emit(0x78)      # ld a, b: what DMA read right after the write
+    rom.call("print_hex")
+    rom.ld_a(0x0A)
+    rom.serial_a()
+    rom.halt_forever()
+
+    rom.label("fail")
+    rom.emit(0xF5)
+    rom.print("fail_text")
+    rom.emit(0xF1)
+    rom.call("print_hex")
+    rom.halt_forever()
+
+    rom.print_subroutine()
+    rom.string("pass", "oam_dma/reg_read: PASS, read during the transfer: ")
+    rom.string("fail_text", "oam_dma/reg_read: FAIL, read back ")
+    rom.build(os.path.join(HERE, "oam_dma_reg_read.gb"))
+
+
+def sources():
+    # Like sources-GS on a DMG: 0x0000-0xDF00 read what's there, 0xE000-0xFF00 read WRAM 0x2000 lower.
+    rom = Rom()
+    setup(rom, DMA_ROUTINE)
+    checks = [
+        (0x01, 0x0100, None),    # ROM
+        (0x80, 0x8000, 0x21),    # VRAM
+        (0xC0, 0xC000, 0x33),    # WRAM bank 0
+        (0xD0, 0xD000, 0x45),    # WRAM bank 1
+        (0xDF, 0xDF00, 0x57),
+        (0xE0, 0xC000, 0x69),    # Echo RAM
+        (0xF1, 0xD100, 0x7B),
+        (0xFE, 0xDE00, 0x8D),    # OAM itself isn't a source, WRAM is
+        (0xFF, 0xDF00, 0x9F),    # neither are I/O and HRAM
+    ]
+    for source, address, seed in checks:
+        if seed is not None:
+            fill(rom, address, seed)
+        rom.ld_a(source)
+        rom.call(0xFF80)
+        fail = "fail_%02X" % source
+        compare_oam(rom, address, fail)
+        ok = rom.fresh()
+        rom.jr(None, ok)
+        rom.label(fail + "_pre")
+        rom.ld_a(source)
+        rom.jp(None, "fail")
+        rom.label(ok)
+    rom.print("pass")
+    rom.halt_forever()
+
+    rom.label("fail")
+    rom.emit(0xF5)
+    rom.print("fail_text")
+    rom.emit(0xF1)
+    rom.call("print_hex")
+    rom.halt_forever()
+
+    rom.print_subroutine()
+    rom.string("pass", "oam_dma/sources-GS: PASS\n")
+    rom.string("fail_text", "oam_dma/sources-GS: FAIL for source page ")
+    rom.build(os.path.join(HERE, "oam_dma_sources.gb"))
+
+
+basic()
+reg_read()
+sources()
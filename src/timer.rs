@@ -1,7 +1,9 @@
+use std::io;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU16, Ordering};
 
 use super::memory::Memory;
+use super::savestate::{StateReader, StateWriter};
 
 
 pub struct TimerModule {
@@ -25,6 +27,19 @@ impl TimerModule {
         }
     }
 
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.u16(self.div_cycles);
+        writer.u16(self.timer_cycles);
+        writer.u16(self.cycles_needed);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> io::Result<()> {
+        self.div_cycles = reader.u16()?;
+        self.timer_cycles = reader.u16()?;
+        self.cycles_needed = reader.u16()?;
+        Ok(())
+    }
+
     pub fn timer_cycle(&mut self) {
         let tac = self.shared_memory.read(0xFF07);
         let timer_enabled = ((tac >> 2) & 1) == 1;
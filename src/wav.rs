@@ -0,0 +1,58 @@
+use std::io;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::fs::File;
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+
+// 16-bit PCM WAV writer. Sizes in the header are patched in when the file is finished.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32, channels: u16) -> io::Result<WavWriter> {
+        let mut file = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+
+        file.write_all(b"RIFF")?;
+        file.write_u32::<LittleEndian>(36)?;
+        file.write_all(b"WAVE")?;
+
+        file.write_all(b"fmt ")?;
+        file.write_u32::<LittleEndian>(16)?;
+        file.write_u16::<LittleEndian>(1)?;
+        file.write_u16::<LittleEndian>(channels)?;
+        file.write_u32::<LittleEndian>(sample_rate)?;
+        file.write_u32::<LittleEndian>(sample_rate * block_align as u32)?;
+        file.write_u16::<LittleEndian>(block_align)?;
+        file.write_u16::<LittleEndian>(16)?;
+
+        file.write_all(b"data")?;
+        file.write_u32::<LittleEndian>(0)?;
+
+        Ok(WavWriter {
+            file,
+            data_size: 0,
+        })
+    }
+
+    pub fn write_sample(&mut self, sample: f32) -> io::Result<()> {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+
+        self.file.write_i16::<LittleEndian>(value)?;
+        self.data_size += 2;
+
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_u32::<LittleEndian>(36 + self.data_size)?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_u32::<LittleEndian>(self.data_size)?;
+        self.file.flush()
+    }
+}